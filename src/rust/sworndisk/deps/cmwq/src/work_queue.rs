@@ -24,6 +24,13 @@ impl WorkQueue {
         // - `struct work_struct*` is non-null and valid.
         unsafe { bindings::queue_work(self.0, work.raw()) };
     }
+
+    /// Wait until all the works in the queue are finished, including the ones queued
+    /// by the running works
+    pub fn drain(&self) {
+        // SAFETY: `self.0` is allocated in `WorkQueue::new` and is valid.
+        unsafe { bindings::drain_workqueue(self.0) };
+    }
 }
 
 impl Drop for WorkQueue {
@@ -33,9 +40,13 @@ impl Drop for WorkQueue {
     }
 }
 
-/// Rust wrapper for `struct work_struct`
+/// Rust wrapper for `struct work_struct`, which carries a data pointer for the work
 #[repr(C)]
-pub struct WorkStruct(bindings::work_struct);
+pub struct WorkStruct {
+    /// must be the first field, so `struct work_struct*` is also a pointer to `WorkStruct`
+    inner: bindings::work_struct,
+    data: *mut c_void,
+}
 
 impl Debug for WorkStruct {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -46,18 +57,36 @@ impl Debug for WorkStruct {
 impl WorkStruct {
     /// Create a new `struct work_struct`
     pub fn new() -> Self {
-        Self(bindings::work_struct::default())
+        Self {
+            inner: bindings::work_struct::default(),
+            data: core::ptr::null_mut(),
+        }
     }
 
     /// Initialize
     pub fn init<T: WorkFuncTrait>(&mut self) {
         let work_func = unsafe { WorkFuncVTable::<T>::build().work };
-        unsafe { bindings::init_work(&mut self.0, work_func) };
+        unsafe { bindings::init_work(&mut self.inner, work_func) };
+    }
+
+    /// Set the data pointer carried by the work
+    pub fn set_data<T>(&mut self, data: *mut T) {
+        self.data = data as *mut c_void;
+    }
+
+    /// Get the data pointer carried by the work of `work_struct`, see `set_data`
+    ///
+    /// # Safety
+    ///
+    /// `work_struct` must be embedded in a `WorkStruct`, e.g. the one passed to
+    /// `WorkFuncTrait::work`.
+    pub unsafe fn data_of<T>(work_struct: *mut bindings::work_struct) -> *mut T {
+        unsafe { (*(work_struct as *mut WorkStruct)).data as *mut T }
     }
 
     /// Get the raw reference of self.inner
     pub unsafe fn raw(&mut self) -> *mut bindings::work_struct {
-        &mut self.0
+        &mut self.inner
     }
 }
 
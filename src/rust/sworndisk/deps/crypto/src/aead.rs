@@ -131,11 +131,31 @@ impl Aead {
         nonce: &mut Vec<u8>,
         plain: &mut [u8],
         len: usize,
+    ) -> Result<Vec<u8>> {
+        unsafe { self.encrypt_in_place_with_ad(key, nonce, &mut [], plain, len) }
+    }
+
+    /// Encrypt the data with key, nonce, plain-text, and authenticate the associated data
+    /// `assoc` along with it.
+    ///
+    /// This method will encrypt the data in its place (i.e. plain -> cipher) and returns MAC.
+    /// `assoc` is not encrypted, but the same associated data must be given to decrypt.
+    /// Like the other buffers in a scatterlist, `assoc` should not be on stack.
+    ///
+    /// This method is marked as unsafe because it violates the mutability rules of Rust.
+    pub unsafe fn encrypt_in_place_with_ad(
+        self: Pin<&Self>,
+        key: &Vec<u8>,
+        nonce: &mut Vec<u8>,
+        assoc: &mut [u8],
+        plain: &mut [u8],
+        len: usize,
     ) -> Result<Vec<u8>> {
         // SAFETY: Safe, `self.inner` is still pinned.
         let inner_lock = unsafe { self.as_ref().map_unchecked(|t| &t.inner) };
         let inner = inner_lock.lock();
         let plain_len = len;
+        let assoc_len = assoc.len();
 
         // set key
         Self::set_key(*inner, key)?;
@@ -146,11 +166,16 @@ impl Aead {
 
         let mut req = unsafe { AeadRequest::new(*inner)? };
         let mut sg = ScatterList::<{ AES_GCM_SCATTER_LIST_LEN }>::new()?;
-        sg.set_buf_slice(0, plain, plain_len)?;
-        sg.set_buf(1, &mut mac, AES_GCM_TAG_LEN)?;
+        let mut index = 0;
+        if assoc_len > 0 {
+            sg.set_buf_slice(index, assoc, assoc_len)?;
+            index += 1;
+        }
+        sg.set_buf_slice(index, plain, plain_len)?;
+        sg.set_buf(index + 1, &mut mac, AES_GCM_TAG_LEN)?;
 
         let sg_ptr = sg.raw();
-        req.set_assoc_data_len(0)?;
+        req.set_assoc_data_len(assoc_len)?;
         unsafe {
             bindings::aead_request_set_crypt(
                 req.raw(),
@@ -221,6 +246,24 @@ impl Aead {
         nonce: &mut Vec<u8>,
         cipher: &mut [u8],
         len: usize,
+    ) -> Result {
+        unsafe { self.decrypt_in_place_with_ad(key, mac, nonce, &mut [], cipher, len) }
+    }
+
+    /// Decrypt the data with key, nonce, mac and cipher-text, and verify the associated
+    /// data `assoc` given in encryption.
+    ///
+    /// This method will decrypt the data in its place (i.e. cipher -> plain).
+    ///
+    /// This method is marked as unsafe because it violates the mutability rules of Rust.
+    pub unsafe fn decrypt_in_place_with_ad(
+        self: Pin<&Self>,
+        key: &Vec<u8>,
+        mac: &mut Vec<u8>,
+        nonce: &mut Vec<u8>,
+        assoc: &mut [u8],
+        cipher: &mut [u8],
+        len: usize,
     ) -> Result {
         // SAFETY: Safe, `self.inner` is still pinned.
         let inner_lock = unsafe { self.as_ref().map_unchecked(|t| &t.inner) };
@@ -228,17 +271,22 @@ impl Aead {
 
         let cipher_len = len;
         let mac_len = mac.len();
+        let assoc_len = assoc.len();
 
         // set key
         Self::set_key(*inner, key)?;
 
         let mut req = unsafe { AeadRequest::new(*inner)? };
         let mut sg = ScatterList::<{ AES_GCM_SCATTER_LIST_LEN }>::new()?;
-        sg.set_buf_slice(0, cipher, cipher_len)?;
-        sg.set_buf(1, mac, AES_GCM_TAG_LEN)?;
+        let mut index = 0;
+        if assoc_len > 0 {
+            sg.set_buf_slice(index, assoc, assoc_len)?;
+            index += 1;
+        }
+        sg.set_buf_slice(index, cipher, cipher_len)?;
+        sg.set_buf(index + 1, mac, AES_GCM_TAG_LEN)?;
 
         let sg_ptr = sg.raw();
-        req.set_assoc_data_len(0)?;
         unsafe {
             bindings::aead_request_set_crypt(
                 req.raw(),
@@ -249,7 +297,100 @@ impl Aead {
             );
         };
 
-        req.set_assoc_data_len(0)?;
+        req.set_assoc_data_len(assoc_len)?;
+        req.decrypt()?;
+
+        Ok(())
+    }
+
+    /// Compute the MAC of `assoc` and `data` with key and nonce, without encryption
+    /// (e.g. GMAC for `gcm(aes)`), returns MAC.
+    ///
+    /// Both buffers are authenticated as associated data and left as they are. Like the other
+    /// buffers in a scatterlist, they should not be on stack.
+    ///
+    /// This method is marked as unsafe because it violates the mutability rules of Rust.
+    pub unsafe fn authenticate_with_ad(
+        self: Pin<&Self>,
+        key: &Vec<u8>,
+        nonce: &mut Vec<u8>,
+        assoc: &mut [u8],
+        data: &mut [u8],
+    ) -> Result<Vec<u8>> {
+        // SAFETY: Safe, `self.inner` is still pinned.
+        let inner_lock = unsafe { self.as_ref().map_unchecked(|t| &t.inner) };
+        let inner = inner_lock.lock();
+        let assoc_len = assoc.len();
+        let data_len = data.len();
+
+        // set key
+        Self::set_key(*inner, key)?;
+
+        // allocate buffer for store the MAC
+        let mut mac = Vec::try_with_capacity(AES_GCM_TAG_LEN)?;
+        mac.try_resize(AES_GCM_TAG_LEN, 0)?;
+
+        // | assoc-data | data | mac |, all but the MAC are associated data
+        let mut req = unsafe { AeadRequest::new(*inner)? };
+        let mut sg = ScatterList::<{ AES_GCM_SCATTER_LIST_LEN }>::new()?;
+        sg.set_buf_slice(0, assoc, assoc_len)?;
+        sg.set_buf_slice(1, data, data_len)?;
+        sg.set_buf(2, &mut mac, AES_GCM_TAG_LEN)?;
+
+        let sg_ptr = sg.raw();
+        req.set_assoc_data_len(assoc_len + data_len)?;
+        unsafe {
+            bindings::aead_request_set_crypt(
+                req.raw(),
+                core::mem::transmute(sg_ptr),
+                core::mem::transmute(sg_ptr),
+                0,
+                nonce.as_mut_ptr(),
+            );
+        };
+        req.encrypt()?;
+
+        Ok(mac)
+    }
+
+    /// Verify the MAC of `assoc` and `data` computed by `authenticate_with_ad`.
+    ///
+    /// This method is marked as unsafe because it violates the mutability rules of Rust.
+    pub unsafe fn verify_with_ad(
+        self: Pin<&Self>,
+        key: &Vec<u8>,
+        mac: &mut Vec<u8>,
+        nonce: &mut Vec<u8>,
+        assoc: &mut [u8],
+        data: &mut [u8],
+    ) -> Result {
+        // SAFETY: Safe, `self.inner` is still pinned.
+        let inner_lock = unsafe { self.as_ref().map_unchecked(|t| &t.inner) };
+        let inner = inner_lock.lock();
+        let assoc_len = assoc.len();
+        let data_len = data.len();
+
+        // set key
+        Self::set_key(*inner, key)?;
+
+        let mut req = unsafe { AeadRequest::new(*inner)? };
+        let mut sg = ScatterList::<{ AES_GCM_SCATTER_LIST_LEN }>::new()?;
+        sg.set_buf_slice(0, assoc, assoc_len)?;
+        sg.set_buf_slice(1, data, data_len)?;
+        sg.set_buf(2, mac, AES_GCM_TAG_LEN)?;
+
+        let sg_ptr = sg.raw();
+        unsafe {
+            bindings::aead_request_set_crypt(
+                req.raw(),
+                core::mem::transmute(sg_ptr),
+                core::mem::transmute(sg_ptr),
+                AES_GCM_TAG_LEN as u32,
+                nonce.as_mut_ptr(),
+            );
+        };
+
+        req.set_assoc_data_len(assoc_len + data_len)?;
         req.decrypt()?;
 
         Ok(())
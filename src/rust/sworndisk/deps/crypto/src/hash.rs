@@ -0,0 +1,76 @@
+//! Synchronous Message Digest API
+
+use core::{fmt, fmt::Debug};
+
+use crate::prelude::*;
+
+/// Synchronous message digest (hash) handle
+pub struct Shash {
+    inner: *mut bindings::crypto_shash,
+}
+
+impl Debug for Shash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shash").finish()
+    }
+}
+
+impl Shash {
+    /// Create a new message digest handle of `algorithm`, e.g. `sha256`
+    pub fn new(algorithm: &'static CStr, _type: u32, mask: u32) -> Result<Self> {
+        // SAFETY: Calling FFI function
+        let inner = unsafe {
+            let inner = bindings::crypto_alloc_shash(algorithm.as_char_ptr(), _type, mask);
+
+            if bindings::IS_ERR(inner as *const c_void) {
+                // Since the `inner` is error, the unwrap_err() will not failed
+                Err(to_result(|| bindings::PTR_ERR(inner as *const c_void) as i32).unwrap_err())
+            } else {
+                Ok(inner)
+            }
+        }?;
+
+        Ok(Self { inner })
+    }
+
+    /// Get the digest size of the algorithm
+    pub fn digest_size(&self) -> usize {
+        // SAFETY: Safe. `self.inner` is a valid handle.
+        unsafe { bindings::crypto_shash_digestsize(self.inner) as usize }
+    }
+
+    /// Set the key of a keyed algorithm, e.g. `hmac(sha256)`. The handle must not be used
+    /// by others while its key is changed.
+    pub fn set_key(&mut self, key: &[u8]) -> Result {
+        // SAFETY: Calling FFI function, `self.inner` is a valid handle.
+        to_result(|| unsafe {
+            bindings::crypto_shash_setkey(self.inner, key.as_ptr(), key.len() as u32)
+        })
+    }
+
+    /// Calculate the digest of `data`
+    pub fn digest(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        out.try_resize(self.digest_size(), 0u8)?;
+
+        // SAFETY: Calling FFI function, `out` has `digest_size()` bytes.
+        to_result(|| unsafe {
+            bindings::crypto_shash_tfm_digest(
+                self.inner,
+                data.as_ptr(),
+                data.len() as u32,
+                out.as_mut_ptr(),
+            )
+        })?;
+
+        Ok(out)
+    }
+}
+
+impl Drop for Shash {
+    fn drop(&mut self) {
+        // The key of the handle is zeroed when it is freed.
+        // SAFETY: Safe. `self.inner` is allocated by `crypto_alloc_shash`.
+        unsafe { bindings::crypto_free_shash(self.inner) };
+    }
+}
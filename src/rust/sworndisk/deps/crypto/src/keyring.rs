@@ -0,0 +1,112 @@
+//! Kernel Key Retention Service API
+
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr,
+};
+
+use crate::prelude::*;
+
+/// Read the payload of a `logon` key described by `description` from the kernel keyring.
+///
+/// The payload must be exactly `nbytes` long, otherwise `EINVAL` is returned.
+pub fn request_logon_key(description: &CStr, nbytes: usize) -> Result<Secret> {
+    // SAFETY: Calling FFI function, `key_type_logon` is a static key type.
+    let key = unsafe {
+        let key = bindings::request_key(
+            &mut bindings::key_type_logon,
+            description.as_char_ptr(),
+            ptr::null(),
+        );
+
+        if bindings::IS_ERR(key as *const c_void) {
+            // Since the `key` is error, the unwrap_err() will not failed
+            Err(to_result(|| bindings::PTR_ERR(key as *const c_void) as i32).unwrap_err())
+        } else {
+            Ok(key)
+        }
+    }?;
+
+    // SAFETY: Safe. `key` is valid until `key_put` below, and the payload is only
+    // accessed with the key semaphore held.
+    let payload = unsafe {
+        bindings::key_read_lock(key);
+        let payload = bindings::user_key_payload_locked(key);
+
+        // the payload is null if the key has been revoked
+        let result = if payload.is_null() || (*payload).datalen as usize != nbytes {
+            Err(EINVAL)
+        } else {
+            let mut buf: Vec<u8> = Vec::new();
+            buf.try_resize(nbytes, 0)
+                .map(|_| {
+                    ptr::copy(
+                        (*payload).data.as_ptr() as *const u8,
+                        buf.as_mut_ptr(),
+                        nbytes,
+                    );
+                    buf
+                })
+                .map_err(Error::from)
+        };
+
+        bindings::key_read_unlock(key);
+        bindings::key_put(key);
+
+        result
+    }?;
+
+    Ok(Secret::new(payload))
+}
+
+/// Wipe the secret in `buf`, which is guaranteed not to be optimized out by the compiler.
+pub fn wipe(buf: &mut [u8]) {
+    // SAFETY: Safe. `buf` is a valid buffer of `buf.len()` bytes.
+    unsafe { bindings::memzero_explicit(buf.as_mut_ptr() as *mut c_void, buf.len() as _) };
+}
+
+/// A secret key held in a heap buffer, which is wiped when it is dropped, including
+/// on the error paths.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    /// Take the ownership of `key`
+    pub fn new(key: Vec<u8>) -> Self {
+        Self(key)
+    }
+
+    /// Copy the secret in `key`
+    pub fn from_slice(key: &[u8]) -> Result<Self> {
+        let mut vec = Vec::try_with_capacity(key.len())?;
+        vec.try_extend_from_slice(key)?;
+        Ok(Self(vec))
+    }
+}
+
+impl Deref for Secret {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for Secret {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    // the secret is never printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({} bytes)", self.0.len())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
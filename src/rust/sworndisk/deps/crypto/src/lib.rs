@@ -9,12 +9,15 @@ mod prelude;
 
 pub mod aead;
 pub mod aead_request;
+pub mod hash;
+pub mod keyring;
 pub mod scatter_list;
 
 use prelude::*;
 
 pub use aead::*;
 pub use aead_request::*;
+pub use hash::*;
 pub use scatter_list::*;
 
 /// Generate N random bytes
@@ -5,7 +5,10 @@ use kernel::{
     error::{code::*, Result},
 };
 
-use super::{consts::Direction, prelude::*};
+use super::{
+    consts::{Direction, REQ_FUA, REQ_PREFLUSH},
+    prelude::*,
+};
 
 use crate::impl_getset;
 
@@ -173,6 +176,16 @@ impl Bio {
         unsafe { (*(self.inner)).bi_opf & bindings::REQ_OP_MASK }
     }
 
+    /// Check if the volatile write cache should be flushed before the bio (`REQ_PREFLUSH`)
+    pub fn is_preflush(&self) -> bool {
+        self.op_flags() & REQ_PREFLUSH != 0
+    }
+
+    /// Check if the bio should be durable when it completes (`REQ_FUA`)
+    pub fn is_fua(&self) -> bool {
+        self.op_flags() & REQ_FUA != 0
+    }
+
     /// Check if the bio carries data
     pub fn has_data(&self) -> bool {
         // SAFETY: From the type invariant, we can guarantee that `self.inner` is valid and non-null.
@@ -225,6 +238,16 @@ impl Bio {
         unsafe { bindings::bio_endio(self.inner) };
     }
 
+    /// End a bio with `status` (`BLK_STS_*`), so the error is reported to the submitter
+    ///
+    /// # Safety
+    ///
+    /// Calls this method to end a bio request must own this bio.
+    pub unsafe fn end_with_status(&mut self, status: u8) {
+        self.set_status(status);
+        unsafe { self.end() };
+    }
+
     /// Read data from sector. Returns (buffer, read-bytes)
     pub fn data(&self, max_len: usize) -> Result<(Vec<u8>, usize)> {
         // if `max_len` is specified, read up to `max_len` bytes
@@ -3,7 +3,7 @@ use super::prelude::*;
 use super::{
     block::{Bio, BlockDevice},
     utils::args_to_vec,
-    wrappers::{DmDev, DmReportZonesArgs, DmTarget},
+    wrappers::{DmDev, DmReportZonesArgs, DmStatusResult, DmTarget, QueueLimits},
 };
 
 /// Represents which fields of device mapper callbacks should be populated
@@ -23,6 +23,16 @@ pub struct ToUse {
     pub iterate_devices: bool,
     /// use dm_report_zones fn
     pub report_zones: bool,
+    /// use dm_message_fn
+    pub message: bool,
+    /// use dm_presuspend_fn
+    pub presuspend: bool,
+    /// use dm_postsuspend_fn
+    pub postsuspend: bool,
+    /// use dm_resume_fn
+    pub resume: bool,
+    /// use dm_io_hints_fn
+    pub io_hints: bool,
 }
 
 /// Default value for `ToUse`
@@ -34,6 +44,11 @@ pub const USE_NONE: ToUse = ToUse {
     prepare_ioctl: false,
     iterate_devices: false,
     report_zones: false,
+    message: false,
+    presuspend: false,
+    postsuspend: false,
+    resume: false,
+    io_hints: false,
 };
 
 /// Callbacks trait for Device Mapper interface (ctr, dtr, map...)
@@ -57,8 +72,8 @@ pub trait DmCallbacks {
         todo!()
     }
 
-    /// status callback function
-    fn status(target: DmTarget, _type: u32, flags: u32) -> Result {
+    /// status callback function, the status should be written to `result`
+    fn status(target: DmTarget, _type: u32, flags: u32, result: &mut DmStatusResult) -> Result {
         todo!()
     }
 
@@ -76,6 +91,36 @@ pub trait DmCallbacks {
     fn report_zones(target: DmTarget, args: DmReportZonesArgs, nr_zones: u32) -> Result<i32> {
         todo!()
     }
+
+    /// message callback function of `dmsetup message`, the reply should be written to
+    /// `result`, return 1 if there is a reply or 0 otherwise
+    fn message(
+        target: DmTarget,
+        args: Vec<&'static CStr>,
+        result: &mut DmStatusResult,
+    ) -> Result<i32> {
+        todo!()
+    }
+
+    /// presuspend callback function, called before the I/O to the target is stopped
+    fn presuspend(target: DmTarget) -> Result {
+        todo!()
+    }
+
+    /// postsuspend callback function, called after all the in-flight I/O is completed
+    fn postsuspend(target: DmTarget) -> Result {
+        todo!()
+    }
+
+    /// resume callback function, called before the I/O to the target is restarted
+    fn resume(target: DmTarget) -> Result {
+        todo!()
+    }
+
+    /// io_hints callback function, the I/O limits of the target should be set in `limits`
+    fn io_hints(target: DmTarget, limits: &mut QueueLimits) -> Result {
+        todo!()
+    }
 }
 
 /// Callbacks vtable for `struct target_type`
@@ -94,6 +139,16 @@ pub struct TargetTypeCallbacks {
     pub iterate_devices: bindings::dm_iterate_devices_fn,
     /// C type of report_zones function
     pub report_zones: bindings::dm_report_zones_fn,
+    /// C type of message function
+    pub message: bindings::dm_message_fn,
+    /// C type of presuspend function
+    pub presuspend: bindings::dm_presuspend_fn,
+    /// C type of postsuspend function
+    pub postsuspend: bindings::dm_postsuspend_fn,
+    /// C type of resume function
+    pub resume: bindings::dm_resume_fn,
+    /// C type of io_hints function
+    pub io_hints: bindings::dm_io_hints_fn,
 }
 
 /// FFI functions table
@@ -146,7 +201,8 @@ impl<T: DmCallbacks> DmCallbacksVTable<T> {
         maxlen: c_types::c_uint,
     ) {
         let target = DmTarget::from(target);
-        let res = T::status(target, _type, status_flags);
+        let mut result = DmStatusResult::new(result, maxlen as usize);
+        let res = T::status(target, _type, status_flags, &mut result);
 
         match res {
             Ok(()) => {}
@@ -203,6 +259,60 @@ impl<T: DmCallbacks> DmCallbacksVTable<T> {
         }
     }
 
+    unsafe extern "C" fn message(
+        target: *mut bindings::dm_target,
+        argc: c_types::c_uint,
+        argv: *mut *mut c_types::c_char,
+        result: *mut c_types::c_char,
+        maxlen: c_types::c_uint,
+    ) -> c_types::c_int {
+        let target = DmTarget::from(target);
+        let args = args_to_vec(argc, argv).unwrap();
+        let mut result = DmStatusResult::new(result, maxlen as usize);
+
+        let res = T::message(target, args, &mut result);
+        match res {
+            Ok(ret) => ret,
+            Err(e) => e.to_kernel_errno(),
+        }
+    }
+
+    unsafe extern "C" fn presuspend(target: *mut bindings::dm_target) {
+        let res = T::presuspend(DmTarget::from(target));
+        match res {
+            Ok(()) => {}
+            Err(e) => pr_warn!("error @ presuspend: {:?}", e),
+        };
+    }
+
+    unsafe extern "C" fn postsuspend(target: *mut bindings::dm_target) {
+        let res = T::postsuspend(DmTarget::from(target));
+        match res {
+            Ok(()) => {}
+            Err(e) => pr_warn!("error @ postsuspend: {:?}", e),
+        };
+    }
+
+    unsafe extern "C" fn resume(target: *mut bindings::dm_target) {
+        let res = T::resume(DmTarget::from(target));
+        match res {
+            Ok(()) => {}
+            Err(e) => pr_warn!("error @ resume: {:?}", e),
+        };
+    }
+
+    unsafe extern "C" fn io_hints(
+        target: *mut bindings::dm_target,
+        limits: *mut bindings::queue_limits,
+    ) {
+        let mut limits = QueueLimits::from(limits);
+        let res = T::io_hints(DmTarget::from(target), &mut limits);
+        match res {
+            Ok(()) => {}
+            Err(e) => pr_warn!("error @ io_hints: {:?}", e),
+        };
+    }
+
     const VTABLE: TargetTypeCallbacks = TargetTypeCallbacks {
         ctr: match T::TO_USE.ctr {
             true => Some(Self::ctr),
@@ -232,6 +342,26 @@ impl<T: DmCallbacks> DmCallbacksVTable<T> {
             true => Some(Self::report_zones),
             false => None,
         },
+        message: match T::TO_USE.message {
+            true => Some(Self::message),
+            false => None,
+        },
+        presuspend: match T::TO_USE.presuspend {
+            true => Some(Self::presuspend),
+            false => None,
+        },
+        postsuspend: match T::TO_USE.postsuspend {
+            true => Some(Self::postsuspend),
+            false => None,
+        },
+        resume: match T::TO_USE.resume {
+            true => Some(Self::resume),
+            false => None,
+        },
+        io_hints: match T::TO_USE.io_hints {
+            true => Some(Self::io_hints),
+            false => None,
+        },
     };
 
     /// Build a vtable of device mapper callbacks
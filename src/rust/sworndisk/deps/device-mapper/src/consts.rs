@@ -43,6 +43,38 @@ pub const DM_ENDIO_REQUEUE: u32 = 2;
 /// delay_requeue
 pub const DM_ENDIO_DELAY_REQUEUE: u32 = 3;
 
+/* Block I/O status (blk_status_t) */
+/// ok
+pub const BLK_STS_OK: u8 = 0;
+/// operation not supported
+pub const BLK_STS_NOTSUPP: u8 = 1;
+/// timeout
+pub const BLK_STS_TIMEOUT: u8 = 2;
+/// critical space allocation
+pub const BLK_STS_NOSPC: u8 = 3;
+/// recoverable transport
+pub const BLK_STS_TRANSPORT: u8 = 4;
+/// critical target
+pub const BLK_STS_TARGET: u8 = 5;
+/// critical nexus
+pub const BLK_STS_NEXUS: u8 = 6;
+/// critical medium
+pub const BLK_STS_MEDIUM: u8 = 7;
+/// protection (integrity verification failure)
+pub const BLK_STS_PROTECTION: u8 = 8;
+/// kernel resource
+pub const BLK_STS_RESOURCE: u8 = 9;
+/// I/O error
+pub const BLK_STS_IOERR: u8 = 10;
+
+/* Request flags (enum req_flag_bits), above the REQ_OP_BITS of operation */
+/// synchronous I/O
+pub const REQ_SYNC: u32 = 1 << 11;
+/// forced unit access, the data is durable when the request completes
+pub const REQ_FUA: u32 = 1 << 17;
+/// flush the volatile write cache before the request
+pub const REQ_PREFLUSH: u32 = 1 << 18;
+
 #[repr(u32)]
 /// status_type
 pub enum StatusType {
@@ -1,6 +1,6 @@
 //! Device Mapper low-level I/O
 
-use crate::{block::BlockDevice, prelude::*};
+use crate::{block::BlockDevice, consts::*, prelude::*};
 
 /// Rust wrapper for `struct dm_io_region`
 pub struct DmIoRegion(bindings::dm_io_region);
@@ -79,17 +79,36 @@ impl DmIoRequest {
         Self(request)
     }
 
-    /// Submit a DM I/O request of 1 region. Returns `sync_error_bits`.
-    pub fn submit(&mut self, region: &mut DmIoRegion) -> u64 {
+    /// Create a DM I/O request that flushes the volatile write cache of a device.
+    /// It carries no data, so it should be submitted with a region of 0 sector.
+    pub fn with_preflush<'a>(client: &'a DmIoClient) -> Self {
+        Self::with_kernel_memory(
+            BioOperationType::WRITE as i32,
+            (REQ_PREFLUSH | REQ_SYNC) as i32,
+            core::ptr::null_mut(),
+            0,
+            client,
+        )
+    }
+
+    /// Submit a DM I/O request of 1 region synchronously.
+    ///
+    /// Returns `EIO` if the region failed, i.e. `sync_error_bits` is set.
+    pub fn submit(&mut self, region: &mut DmIoRegion) -> Result {
         let mut sync_error_bits: u64 = 0;
-        unsafe {
+        // SAFETY: Calling FFI function, the request and region are valid.
+        kernel::to_result(|| unsafe {
             bindings::dm_io(
                 &mut self.0 as *mut bindings::dm_io_request,
                 1,
                 region.raw(),
                 &mut sync_error_bits,
             )
-        };
-        sync_error_bits
+        })?;
+
+        match sync_error_bits {
+            0 => Ok(()),
+            _ => Err(EIO),
+        }
     }
 }
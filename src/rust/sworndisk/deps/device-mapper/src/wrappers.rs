@@ -73,6 +73,11 @@ impl TargetType {
         inner_mut.prepare_ioctl = vtable.prepare_ioctl;
         inner_mut.iterate_devices = vtable.iterate_devices;
         inner_mut.report_zones = vtable.report_zones;
+        inner_mut.message = vtable.message;
+        inner_mut.presuspend = vtable.presuspend;
+        inner_mut.postsuspend = vtable.postsuspend;
+        inner_mut.resume = vtable.resume;
+        inner_mut.io_hints = vtable.io_hints;
 
         // SAFETY: users must guarantee that you will never move the data
         // out of the mutable reference.
@@ -183,6 +188,8 @@ impl DmTarget {
     impl_getset!(num_write_same_bios, set_num_write_same_bios, u32);
     impl_getset!(num_write_zeroes_bios, set_num_write_zeroes_bios, u32);
     impl_getset!(per_io_data_size, set_per_io_data_size, u32);
+    impl_getset!(flush_supported, set_flush_supported, bool);
+    impl_getset!(discards_supported, set_discards_supported, bool);
 
     /// Get raw pointer reference of `dm_target`
     pub fn raw(&self) -> Option<*mut bindings::dm_target> {
@@ -253,6 +260,7 @@ impl DmTarget {
         let private = unsafe { (*(self.inner)).private };
         if !private.is_null() {
             let _ = unsafe { Box::from_raw(private as *mut PrivateField<T>) };
+            unsafe { (*(self.inner)).private = core::ptr::null_mut() };
         }
     }
 
@@ -312,6 +320,30 @@ impl DmTarget {
         Ok(res)
     }
 
+    /// Get the mutable reference of the private field without acquiring its lock.
+    /// Returns None if the private field is not set or its type is not `T`.
+    ///
+    /// # Safety
+    ///
+    /// The reference aliases with the ones got by the other callbacks of the target, users
+    /// should synchronize the accesses by themselves, and should not use it after the private
+    /// field is dropped.
+    pub unsafe fn get_private_mut<'a, T: Any + Debug + 'static>(&self) -> Option<&'a mut T> {
+        let inner = self.inner;
+        if inner.is_null() {
+            return None;
+        }
+
+        // SAFETY: Safe, `inner` is non-null.
+        let ptr = unsafe { (*inner).private } as *mut PrivateField<T>;
+        if ptr.is_null() {
+            return None;
+        }
+
+        // SAFETY: Safe, the private field is set by `init_lock_and_private`.
+        unsafe { (*ptr).get_private_mut::<T>() }
+    }
+
     /// Lookup device and assign to dm_target.
     pub fn get_device(&mut self, path: &'static CStr, mode: u32, dev: &mut DmDev) -> i32 {
         let ti = self.raw().unwrap();
@@ -341,6 +373,28 @@ impl From<*mut bindings::dm_target> for DmTarget {
     }
 }
 
+/// Rust wrapper for `struct queue_limits`, the I/O limits of the request queue
+pub struct QueueLimits {
+    inner: *mut bindings::queue_limits,
+}
+
+impl From<*mut bindings::queue_limits> for QueueLimits {
+    fn from(inner: *mut bindings::queue_limits) -> Self {
+        Self { inner }
+    }
+}
+
+impl QueueLimits {
+    impl_getset!(logical_block_size, set_logical_block_size, u32);
+    impl_getset!(physical_block_size, set_physical_block_size, u32);
+    impl_getset!(io_min, set_io_min, u32);
+    impl_getset!(io_opt, set_io_opt, u32);
+    impl_getset!(discard_granularity, set_discard_granularity, u32);
+    impl_getset!(max_discard_sectors, set_max_discard_sectors, u32);
+    impl_getset!(max_hw_discard_sectors, set_max_hw_discard_sectors, u32);
+    impl_getset!(max_write_zeroes_sectors, set_max_write_zeroes_sectors, u32);
+}
+
 /// Rust wrapper for `struct dm_report_zones_args`
 pub struct DmReportZonesArgs {
     inner: *mut bindings::dm_report_zones_args,
@@ -375,3 +429,58 @@ impl DmReportZonesArgs {
         }
     }
 }
+
+/// Result buffer of `dm_status_fn` and `dm_message_fn`, which is written like `DMEMIT` in C
+///
+/// The content is always null-terminated, and the writing fails once the buffer is full.
+pub struct DmStatusResult {
+    buf: *mut c_types::c_char,
+    maxlen: usize,
+    len: usize,
+}
+
+impl DmStatusResult {
+    /// Create a status result buffer from `result` of `maxlen` bytes
+    ///
+    /// # Safety
+    ///
+    /// `result` should be a valid buffer of `maxlen` bytes.
+    pub unsafe fn new(result: *mut c_types::c_char, maxlen: usize) -> Self {
+        if !result.is_null() && maxlen > 0 {
+            *result = 0;
+        }
+
+        Self {
+            buf: result,
+            maxlen,
+            len: 0,
+        }
+    }
+
+    /// Get the length of the content written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl core::fmt::Write for DmStatusResult {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // keep a byte for the terminating null
+        if self.buf.is_null() || self.len + s.len() + 1 > self.maxlen {
+            return Err(core::fmt::Error);
+        }
+
+        // SAFETY: Safe. The buffer has `maxlen` bytes, which is checked above.
+        unsafe {
+            core::ptr::copy(
+                s.as_ptr() as *const c_types::c_char,
+                self.buf.add(self.len),
+                s.len(),
+            );
+            self.len += s.len();
+            *self.buf.add(self.len) = 0;
+        }
+
+        Ok(())
+    }
+}
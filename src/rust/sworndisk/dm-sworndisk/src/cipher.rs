@@ -0,0 +1,176 @@
+//! AEAD cipher suites of SwornDisk
+//!
+//! The cipher suite is chosen when a SwornDisk is formatted and recorded in the superblock,
+//! every block, journal and checkpoint of the SwornDisk is sealed with it. All the suites
+//! take a 96-bit nonce and a 128-bit MAC, they only differ in the key length.
+//!
+//! The data blocks are encrypted by default. With `DataMode::Integrity`, they are stored in
+//! plaintext and only authenticated, the MAC is kept in the `Record` as usual. The index
+//! blocks, journal and checkpoint are always encrypted.
+
+use crate::{prelude::*, types::KeyType};
+
+use crypto::get_random_bytes;
+
+/// AEAD cipher suite, see `CipherSuite::algorithm` for the transform of each one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CipherSuite {
+    /// AES-128-GCM
+    Aes128Gcm,
+    /// AES-256-GCM
+    Aes256Gcm,
+    /// ChaCha20-Poly1305 (RFC 7539), which is faster on CPUs without AES instructions
+    ChaCha20Poly1305,
+}
+
+impl Default for CipherSuite {
+    fn default() -> Self {
+        CipherSuite::Aes128Gcm
+    }
+}
+
+impl CipherSuite {
+    /// Parse the suite recorded in the superblock
+    pub fn from_u64(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(CipherSuite::Aes128Gcm),
+            1 => Ok(CipherSuite::Aes256Gcm),
+            2 => Ok(CipherSuite::ChaCha20Poly1305),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// The value recorded in the superblock
+    pub fn as_u64(&self) -> u64 {
+        match self {
+            CipherSuite::Aes128Gcm => 0,
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    /// Parse the name of a suite given in the table line
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "aes-128-gcm" => Ok(CipherSuite::Aes128Gcm),
+            "aes-256-gcm" => Ok(CipherSuite::Aes256Gcm),
+            "chacha20-poly1305" => Ok(CipherSuite::ChaCha20Poly1305),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Name of the kernel crypto transform
+    pub fn algorithm(&self) -> &'static CStr {
+        match self {
+            CipherSuite::Aes128Gcm | CipherSuite::Aes256Gcm => c_str!("gcm(aes)"),
+            CipherSuite::ChaCha20Poly1305 => c_str!("rfc7539(chacha20,poly1305)"),
+        }
+    }
+
+    /// Key length in bytes, a `KeyType` holds the key in its leading bytes
+    pub fn key_len(&self) -> usize {
+        match self {
+            CipherSuite::Aes128Gcm => 16,
+            CipherSuite::Aes256Gcm | CipherSuite::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// Create a crypto handle of the transform
+    pub fn aead(&self) -> Result<Pin<Box<Aead>>> {
+        Aead::new(self.algorithm(), 0, 0)
+    }
+
+    /// Create a random key, the bytes after `key_len()` are zeros
+    pub fn random_key(&self) -> Result<KeyType> {
+        let mut key = [0; SWORNDISK_KEY_LENGTH];
+        key[..self.key_len()].copy_from_slice(&get_random_bytes(self.key_len())?);
+        Ok(key)
+    }
+
+    /// The bytes of `key` passed to the transform
+    pub fn key(&self, key: &KeyType) -> Result<Vec<u8>> {
+        let mut vec = Vec::new();
+        vec.try_extend_from_slice(&key[..self.key_len()])?;
+        Ok(vec)
+    }
+}
+
+/// How the data blocks are sealed, chosen at format time and recorded in the superblock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataMode {
+    /// The data blocks are encrypted and authenticated
+    Encrypted,
+    /// The data blocks are stored in plaintext and authenticated (GMAC for AES-GCM), for
+    /// the public data which needs tamper and rollback protection but no confidentiality
+    Integrity,
+}
+
+impl Default for DataMode {
+    fn default() -> Self {
+        DataMode::Encrypted
+    }
+}
+
+impl DataMode {
+    /// Parse the mode recorded in the superblock
+    pub fn from_u64(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(DataMode::Encrypted),
+            1 => Ok(DataMode::Integrity),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// The value recorded in the superblock
+    pub fn as_u64(&self) -> u64 {
+        match self {
+            DataMode::Encrypted => 0,
+            DataMode::Integrity => 1,
+        }
+    }
+
+    /// Seal the data `block` in place with `key` and `nonce`, `assoc` is authenticated along
+    /// with it. Returns the MAC.
+    pub fn seal(
+        &self,
+        aead: &Pin<Box<Aead>>,
+        key: &Vec<u8>,
+        nonce: &mut Vec<u8>,
+        assoc: &mut [u8],
+        block: &mut [u8],
+    ) -> Result<Vec<u8>> {
+        let len = block.len();
+        // SAFETY: Safe. The buffers are borrowed mutably, there is no concurrent access.
+        unsafe {
+            match self {
+                DataMode::Encrypted => aead
+                    .as_ref()
+                    .encrypt_in_place_with_ad(key, nonce, assoc, block, len),
+                DataMode::Integrity => aead.as_ref().authenticate_with_ad(key, nonce, assoc, block),
+            }
+        }
+    }
+
+    /// Open the data `block` sealed by `seal` in place, fails with `EBADMSG` if the block or
+    /// `assoc` does not match `mac`
+    pub fn open(
+        &self,
+        aead: &Pin<Box<Aead>>,
+        key: &Vec<u8>,
+        mac: &mut Vec<u8>,
+        nonce: &mut Vec<u8>,
+        assoc: &mut [u8],
+        block: &mut [u8],
+    ) -> Result {
+        let len = block.len();
+        // SAFETY: Safe. The buffers are borrowed mutably, there is no concurrent access.
+        unsafe {
+            match self {
+                DataMode::Encrypted => aead
+                    .as_ref()
+                    .decrypt_in_place_with_ad(key, mac, nonce, assoc, block, len),
+                DataMode::Integrity => aead.as_ref().verify_with_ad(key, mac, nonce, assoc, block),
+            }
+        }
+    }
+}
@@ -28,6 +28,11 @@ pub const MAX_CONCURRENT_LOCKS: u32 = 5;
 /// Magic number of SwornDisk superblock
 pub const SWORNDISK_MAGIC_NUMBER: u64 = 0x03070612;
 
+/// Version of the on-disk format recorded in the superblock. Version 2 keeps a key of
+/// `SWORNDISK_KEY_LENGTH` bytes in the records, journal and checkpoint, along with the
+/// record versions. The disks of other versions are rejected rather than misread.
+pub const SWORNDISK_FORMAT_VERSION: u64 = 2;
+
 /// Checksum XOR number of SuerBlock
 pub const SWORNDISK_SUPERBLOCK_XOR: u32 = 998244353;
 
@@ -36,15 +41,18 @@ pub const SWORNDISK_FIRST_SUPERBLOCK_LOCATION: u64 = 0;
 pub const SWORNDISK_SECOND_SUPERBLOCK_LOCATION: u64 = 1;
 
 /* Encrypt parameters */
-/// AES-128-GCM key length
-pub const SWORNDISK_KEY_LENGTH: usize = 16;
+/// Max key length of the cipher suites, see `CipherSuite::key_len`
+pub const SWORNDISK_KEY_LENGTH: usize = 32;
 
-/// AES-128-GCM nonce (iv) length
+/// AEAD nonce (iv) length, which is the same for all the cipher suites
 pub const SWORNDISK_NONCE_LENGTH: usize = 12;
 
-/// AES-128-GCM MAC (tag) length
+/// AEAD MAC (tag) length, which is the same for all the cipher suites
 pub const SWORNDISK_MAC_LENGTH: usize = 16;
 
+/// SHA-256 digest length
+pub const SWORNDISK_HASH_LENGTH: usize = 32;
+
 /* I/O operation types */
 /// READ (b00)
 pub const READ: u32 = 0;
@@ -52,17 +60,34 @@ pub const READ: u32 = 0;
 pub const WRITE: u32 = 1;
 /// FLUSH (b10)
 pub const FLUSH: u32 = 2;
+/// DISCARD (b11)
+pub const DISCARD: u32 = 3;
+/// WRITE_ZEROES (b1001)
+pub const WRITE_ZEROES: u32 = 9;
 
 /* BIT parameters */
 /// Max levels of a BIT
 pub const BIT_MAX_LEVEL: usize = 5;
 
+/// Max blocks of an extent. The extents never cross a boundary aligned to this size,
+/// so a sequential write is indexed by an extent per 512 KiB.
+pub const EXTENT_MAX_BLOCKS: u64 = 128;
+
 /// Max levels of dsLSM-tree
 pub const LSM_TREE_MAX_LEVEL: usize = 3;
 
-/// Max record number of MemTable
+/// Default max extent number of MemTable, can be changed by `set memtable_threshold`
 pub const MEMTABLE_THRESHOLD: usize = 65536;
 
+/// Bits of the Bloom filter of a BIT per LBA it indexes
+pub const BLOOM_FILTER_BITS_PER_KEY: usize = 10;
+
+/// Max blocks of a Bloom filter, a larger BIT gets a filter with more false positives
+pub const BLOOM_FILTER_MAX_BLOCKS: usize = 128;
+
+/// Max hash functions of a Bloom filter
+pub const BLOOM_FILTER_MAX_HASHES: usize = 8;
+
 /// Max size of IndirectBlock or LeafBlock LRU Cache
 pub const LRU_CACHE_MAX_SIZE: usize = 4096;
 
@@ -71,3 +96,10 @@ pub const MAX_COMPACTION_NUMBER: usize = 6;
 
 /// Max workers number
 pub const MAX_WORKERS: usize = 6;
+
+/* Segment cleaning parameters */
+/// Trigger segment cleaning when the free data segments are fewer than this number
+pub const GC_LOW_WATERMARK: usize = 8;
+
+/// Max victim segments reclaimed by a segment cleaning job
+pub const MAX_GC_SEGMENTS: usize = 4;
@@ -1,20 +1,27 @@
 // SPDX-License-Identifier: GPL-2.0
 
-//! SwornDisk global context strucure
+//! SwornDisk context strucure, one per device mapper target
 
 use crate::{
+    cipher::DataMode,
+    counter::TrustedCounter,
+    kdf::KeyDeriver,
+    options::ErrorPolicy,
     prelude::*,
     regions::{
-        Checkpoint, DataSegment, IndexSegment, IndirectBlock, LeafBlock, MemTable, SuperBlock, BIT,
+        Checkpoint, DataSegment, IndexSegment, IndirectBlock, Journal, JournalRecord, LeafBlock,
+        MemTable, SuperBlock, BIT,
     },
+    stats::Statistics,
+    types::HashType,
     utils::{DebugIgnore, LruCache},
-    workers::{CompactionWorker, IoWorker},
+    workers::{CompactionWorker, IoWorker, Scrubber, SegmentCleaner},
 };
 
-use kernel::sync::{Mutex, RwSemaphore};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-/// global SwornDisk context
-pub static mut CONTEXT: Option<&mut SwornDiskContext> = None;
+use crypto::keyring::Secret;
+use kernel::sync::{Mutex, RwSemaphore};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -29,12 +36,20 @@ pub struct SwornDiskContext {
     pub checkpoint: Checkpoint,
     /// Device mapper I/O clinet
     pub dm_io_client: DmIoClient,
+    /// Policy on I/O errors
+    pub error_policy: ErrorPolicy,
     /// data segment buffer
     pub data_seg_buffer: DataSegment,
     /// Real block device for storing data segment
     pub data_dev: DmDev,
+    /// How the data blocks are sealed, see `DataMode`
+    pub data_mode: DataMode,
     /// Index segment
     pub index_seg: IndexSegment,
+    /// Security journal
+    pub journal: Journal,
+    /// Key deriver of the data and index blocks
+    pub kdf: KeyDeriver,
     /// IndirectBlock LRU cache (HBA -> IndirectBlock)
     pub indirect_block_cache: LruCache<u64, IndirectBlock>,
     /// LeafBlock LRU cache (HBA -> LeafBlock)
@@ -45,26 +60,93 @@ pub struct SwornDiskContext {
     pub meta_dev: DmDev,
     /// Level 0 (in-memory) block index tree
     pub memtable: MemTable,
+    /// MemTable is written back as a BIT once it holds this number of records
+    pub memtable_threshold: usize,
+    /// Write requests are rejected if set, see `ErrorPolicy::ReadOnly`
+    pub read_only: AtomicBool,
+    /// Root key to encrypt the checkpoint, which is never written to disk and wiped when
+    /// the context is dropped. Its length is the key length of the cipher suite.
+    pub root_key: Secret,
+    /// Scrubbing keeps running while it is set, see `Scrubber`
+    pub scrubbing: AtomicBool,
+    /// Scrubbing is paused by suspending and restarted on resume
+    pub scrub_on_resume: AtomicBool,
     /// start sector
     pub start: u64,
+    /// Runtime statistics reported by `dmsetup status`
+    pub stats: Statistics,
     /// SwornDisk superblock
     pub superblock: SuperBlock,
+    /// Set between postsuspend and resume, the disk should not be written meanwhile
+    pub suspended: AtomicBool,
+    /// Value of `write_seq` made durable by the last sync
+    pub synced_seq: u64,
+    /// Table line reported by `dmsetup table`, see `DmSwornDiskHandler::table_line`
+    pub table: Vec<u8>,
+    /// Trusted counter advanced to the journal head whenever the journal is durable, `None`
+    /// if rollback protection is disabled
+    pub trusted_counter: Option<Box<dyn TrustedCounter>>,
     /// Async work queue
     pub work_queue: Box<WorkQueue>,
+    /// Number of write requests acknowledged, a flush covers the writes counted before it
+    pub write_seq: AtomicU64,
 
     /// Worker for handle bio requests
     pub rw_worker: [WorkStruct; MAX_WORKERS],
     /// Worker for handle compaction
     pub compaction_worker: WorkStruct,
+    /// Worker for handle segment cleaning
+    pub gc_worker: WorkStruct,
+    /// Worker for handle scrubbing
+    pub scrub_worker: WorkStruct,
 }
 
 impl SwornDiskContext {
-    /// Initialize async workers that will make use of any member in SwornDiskContext
-    pub fn init_workers(&mut self) {
+    /// Get the context stored in the private field of `target`
+    ///
+    /// # Safety
+    ///
+    /// The context is shared by all the callbacks and workers of `target`, the members
+    /// should only be accessed with `lock` held.
+    pub unsafe fn of<'a>(target: &DmTarget) -> Result<&'a mut Self> {
+        unsafe { target.get_private_mut::<Box<Self>>() }
+            .map(|ctx| ctx.as_mut())
+            .ok_or(EINVAL)
+    }
+
+    /// Get the context of the target that `work_struct` belongs to, see `init_workers`
+    ///
+    /// # Safety
+    ///
+    /// `work_struct` must be one of the workers of a context, and the same rules as `of` apply.
+    pub unsafe fn of_work<'a>(work_struct: *mut bindings::work_struct) -> Result<&'a mut Self> {
+        let target = DmTarget::from(unsafe { WorkStruct::data_of(work_struct) });
+        unsafe { Self::of(&target) }
+    }
+
+    /// Get `lock` without borrowing the context, so the members can be mutated with it held
+    pub fn rw_lock<'a>(&self) -> &'a RwSemaphore<()> {
+        // SAFETY: Safe. The lock is pinned on heap, and lives until the target is destroyed
+        // after all the requests and workers are finished.
+        unsafe { &*(&**self.lock as *const RwSemaphore<()>) }
+    }
+
+    /// Initialize async workers that will make use of any member in SwornDiskContext,
+    /// each worker finds the context through `target`
+    pub fn init_workers(&mut self, target: &DmTarget) -> Result {
+        let target = target.raw().ok_or(EINVAL)?;
         for worker in &mut self.rw_worker {
             worker.init::<IoWorker>();
+            worker.set_data(target);
         }
         self.compaction_worker.init::<CompactionWorker>();
+        self.compaction_worker.set_data(target);
+        self.gc_worker.init::<SegmentCleaner>();
+        self.gc_worker.set_data(target);
+        self.scrub_worker.init::<Scrubber>();
+        self.scrub_worker.set_data(target);
+
+        Ok(())
     }
 
     /// Flush SwornDisk
@@ -72,31 +154,113 @@ impl SwornDiskContext {
         // flush data segment
         self.data_seg_buffer.flush(
             &self.aead,
+            &self.kdf,
             &mut self.checkpoint,
             &mut self.dm_io_client,
             &mut self.memtable,
+            &mut self.journal,
             &self.data_dev.block_device()?,
+            &self.meta_dev.block_device()?,
         )?;
 
-        // generate MemTable from BIT and write to index segment
-        let bit = BIT::from_memtable(
-            &mut self.memtable,
+        // generate BIT from MemTable and write to index segment
+        if self.memtable.size() > 0 {
+            let bit = BIT::from_memtable(
+                &mut self.memtable,
+                &self.aead,
+                &self.kdf,
+                &mut self.dm_io_client,
+                &mut self.checkpoint,
+                &self.meta_dev.block_device()?,
+                &mut self.index_seg,
+            )?;
+            self.checkpoint.bit_category.add_bit(bit, 0)?;
+            self.memtable.clear();
+
+            let record =
+                JournalRecord::bit_creation(&self.checkpoint.bit_category, 0, &self.index_seg)?;
+            self.journal.append(
+                &record,
+                &self.aead,
+                &self.meta_dev.block_device()?,
+                &self.dm_io_client,
+            )?;
+        }
+
+        // log the checkpoint event, replaying will start from the journal head after it
+        self.checkpoint.journal_head = self.journal.checkpoint(
             &self.aead,
-            &mut self.dm_io_client,
-            &mut self.checkpoint,
             &self.meta_dev.block_device()?,
-            &mut self.index_seg,
+            &self.dm_io_client,
         )?;
-        self.checkpoint.bit_category.add_bit(bit, 0)?;
 
         // write checkpoint
-        let checkpoint_hba = self.superblock.checkpoint_region / SECTOR_SIZE;
+        self.checkpoint.index_hba = self.index_seg.hba;
         self.checkpoint.write_to_disk(
+            &self.aead,
+            &self.kdf,
+            &self.root_key,
             &self.meta_dev.block_device()?,
             &mut self.dm_io_client,
-            checkpoint_hba,
+            self.superblock.checkpoint_hba(),
+            self.superblock.checkpoint_slot_sectors(),
         )?;
 
+        // the checkpoint is committed, the older ones are stale since then
+        if let Some(counter) = self.trusted_counter.as_mut() {
+            let mut region = DmIoRegion::new(&self.meta_dev.block_device()?, 0, 0)?;
+            DmIoRequest::with_preflush(&self.dm_io_client).submit(&mut region)?;
+            counter.advance(self.journal.head().seq)?;
+        }
+
+        Ok(())
+    }
+
+    /// Root hash of the current disk state, see `Checkpoint::root_hash`
+    pub fn root_hash(&self) -> Result<HashType> {
+        self.checkpoint.root_hash(&self.kdf, &self.journal.head())
+    }
+
+    /// Make all the completed writes durable without a checkpoint (`REQ_PREFLUSH` / `REQ_FUA`).
+    ///
+    /// The buffered data segment is written back and data_dev is flushed before its data
+    /// logs are appended (see `DataSegment::flush`). Then the journal is committed and
+    /// meta_dev is flushed, so the MemTable can be rebuilt on recovery. Nothing is written
+    /// if no write is acknowledged since the last sync.
+    pub fn sync(&mut self) -> Result {
+        let seq = self.write_seq.load(Ordering::Acquire);
+        if seq == self.synced_seq {
+            return Ok(());
+        }
+
+        let data_bdev = self.data_dev.block_device()?;
+        let meta_bdev = self.meta_dev.block_device()?;
+
+        if self.data_seg_buffer.used > 0 {
+            self.data_seg_buffer.flush(
+                &self.aead,
+                &self.kdf,
+                &mut self.checkpoint,
+                &self.dm_io_client,
+                &mut self.memtable,
+                &mut self.journal,
+                &data_bdev,
+                &meta_bdev,
+            )?;
+        }
+        self.journal
+            .commit(&self.aead, &meta_bdev, &self.dm_io_client)?;
+
+        // the committed journal blocks are durable since then
+        let mut region = DmIoRegion::new(&meta_bdev, 0, 0)?;
+        DmIoRequest::with_preflush(&self.dm_io_client).submit(&mut region)?;
+        self.synced_seq = seq;
+
+        // the journal can not be truncated before the committed blocks since then
+        if let Some(counter) = self.trusted_counter.as_mut() {
+            counter.advance(self.journal.head().seq)?;
+        }
+
         Ok(())
     }
 }
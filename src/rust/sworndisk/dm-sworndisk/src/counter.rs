@@ -0,0 +1,247 @@
+//! Trusted monotonic counter for rollback protection
+//!
+//! Everything on the meta / data devices can be replaced by an attacker with an older copy,
+//! which still decrypts and verifies. A trusted counter is kept outside the untrusted disk and
+//! advanced to the sequence number of the journal head whenever the journal is durable, i.e.
+//! on every checkpoint and sync. So an image whose journal ends before the counter is known
+//! to be rolled back or truncated.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::prelude::*;
+
+/// A monotonic counter kept in a place that the attacker can not roll back
+pub trait TrustedCounter: Debug {
+    /// Read the current value of the counter
+    fn read(&mut self) -> Result<u64>;
+
+    /// Advance the counter to `value`, a value less than the current one is rejected
+    fn advance(&mut self, value: u64) -> Result;
+
+    /// Set the counter to `value` whatever it holds, which is only done when a SwornDisk
+    /// is formatted and the counter can not be read
+    fn init(&mut self, value: u64) -> Result;
+}
+
+/// The max length of the decimal value in a counter file
+const COUNTER_FILE_MAX_LEN: usize = 32;
+
+/// Width of the zero-padded decimal value written to a counter file, which fits a u64
+const COUNTER_FILE_WIDTH: usize = 20;
+
+/// Counter backed by a file (or a sysfs attribute) that the TEE owner controls.
+///
+/// The file holds the decimal value of the counter, zero-padded to `COUNTER_FILE_WIDTH`
+/// digits. The value is overwritten in place and never truncated, so a crash in the middle
+/// of an update never leaves the file empty. A missing, empty or invalid file is an error.
+#[derive(Debug)]
+pub struct FileCounter {
+    /// null-terminated file path
+    path: Vec<u8>,
+}
+
+impl FileCounter {
+    /// Create a counter backed by the file at `path`
+    pub fn new(path: &str) -> Result<Self> {
+        let mut vec = Vec::new();
+        vec.try_extend_from_slice(path.as_bytes())?;
+        vec.try_push(0)?;
+
+        Ok(Self { path: vec })
+    }
+
+    /// Write `value` to the counter file opened with the extra `flags` and sync it
+    fn write(&self, value: u64, flags: u32) -> Result {
+        // format the zero-padded decimal value, followed by a newline
+        let mut buf = [b'0'; COUNTER_FILE_WIDTH + 1];
+        let mut rest = value;
+        for i in (0..COUNTER_FILE_WIDTH).rev() {
+            buf[i] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+        }
+        buf[COUNTER_FILE_WIDTH] = b'\n';
+
+        let file = self.open(bindings::O_WRONLY | bindings::O_CREAT | flags)?;
+        let mut pos = 0;
+        // SAFETY: Safe. `file` is opened above and `buf` has `buf.len()` valid bytes.
+        let (written, synced) = unsafe {
+            let written = bindings::kernel_write(
+                file,
+                buf.as_ptr() as *const c_void,
+                buf.len() as _,
+                &mut pos,
+            );
+            let synced = bindings::vfs_fsync(file, 0);
+            bindings::filp_close(file, ptr::null_mut());
+            (written, synced)
+        };
+        if written < 0 {
+            return Err(Error::from_kernel_errno(written as i32));
+        }
+        if written as usize != buf.len() {
+            return Err(EIO);
+        }
+
+        kernel::to_result(|| synced)
+    }
+
+    /// Open the counter file with `flags`
+    fn open(&self, flags: u32) -> Result<*mut bindings::file> {
+        // SAFETY: Calling FFI function, `self.path` is null-terminated.
+        unsafe {
+            let file =
+                bindings::filp_open(self.path.as_ptr() as *const c_char, flags as i32, 0o600);
+            if bindings::IS_ERR(file as *const c_void) {
+                // Since the `file` is error, the unwrap_err() will not failed
+                Err(
+                    kernel::to_result(|| bindings::PTR_ERR(file as *const c_void) as i32)
+                        .unwrap_err(),
+                )
+            } else {
+                Ok(file)
+            }
+        }
+    }
+}
+
+impl TrustedCounter for FileCounter {
+    fn read(&mut self) -> Result<u64> {
+        let file = self.open(bindings::O_RDONLY)?;
+
+        let mut buf = [0u8; COUNTER_FILE_MAX_LEN];
+        let mut pos = 0;
+        // SAFETY: Safe. `file` is opened above and `buf` has `COUNTER_FILE_MAX_LEN` bytes.
+        let len = unsafe {
+            let len = bindings::kernel_read(
+                file,
+                buf.as_mut_ptr() as *mut c_void,
+                COUNTER_FILE_MAX_LEN as _,
+                &mut pos,
+            );
+            bindings::filp_close(file, ptr::null_mut());
+            len
+        };
+        if len < 0 {
+            return Err(Error::from_kernel_errno(len as i32));
+        }
+
+        let value = str::from_utf8(&buf[..len as usize])
+            .map(|value| value.trim())
+            .unwrap_or_default();
+        value.parse::<u64>().map_err(|_| {
+            pr_err!("The trusted counter file holds no valid value");
+            EINVAL
+        })
+    }
+
+    fn advance(&mut self, value: u64) -> Result {
+        if value < self.read()? {
+            return Err(EINVAL);
+        }
+
+        // overwrite the value in place
+        self.write(value, 0)
+    }
+
+    fn init(&mut self, value: u64) -> Result {
+        // the invalid content is dropped, a crash in between leaves an empty file, which
+        // fails the next construction rather than passing it
+        self.write(value, bindings::O_TRUNC)
+    }
+}
+
+/// Max number of devices that have an in-memory counter in the module
+const MEMORY_COUNTER_SLOTS: usize = 16;
+
+/// Bit set in the key of a claimed slot above the `dev_t` of its device, so a claimed key
+/// is never 0
+const CLAIMED_SLOT: u64 = 1 << 32;
+
+/// An in-memory counter, claimed by the device whose `dev_t` is in `key`. A slot is free if
+/// its key is 0, and a claimed slot is never released, so the counter survives a remount.
+pub struct CounterSlot {
+    key: AtomicU64,
+    value: AtomicU64,
+}
+
+impl CounterSlot {
+    /// Create a free slot
+    pub const fn new() -> Self {
+        Self {
+            key: AtomicU64::new(0),
+            value: AtomicU64::new(0),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_SLOT: CounterSlot = CounterSlot::new();
+
+/// The in-memory counters of the module, one for each device
+static MODULE_COUNTERS: [CounterSlot; MEMORY_COUNTER_SLOTS] = [FREE_SLOT; MEMORY_COUNTER_SLOTS];
+
+/// In-memory counter, used as a test double of a real trusted counter.
+///
+/// The value lives as long as the counter cell, e.g. the counter of a device in the module
+/// survives a remount of the target but not a reload of the module.
+#[derive(Debug)]
+pub struct MemoryCounter {
+    cell: &'static AtomicU64,
+}
+
+impl MemoryCounter {
+    /// Create a counter backed by `cell`
+    pub fn new(cell: &'static AtomicU64) -> Self {
+        Self { cell }
+    }
+
+    /// Create a counter backed by the in-memory counter of the device `dev` (its `dev_t`)
+    /// in the module, so the targets on different devices never share a counter. Returns
+    /// `ENOSPC` if all the counters are taken by other devices.
+    pub fn of_device(dev: u32) -> Result<Self> {
+        Self::of_device_in(&MODULE_COUNTERS, dev)
+    }
+
+    /// Create a counter backed by the slot of the device `dev` in `slots`, which is claimed
+    /// if the device has none.
+    ///
+    /// The slots are claimed in order and never released, so the first slot that is either
+    /// free or claimed by `dev` is the only one `dev` can have. A single compare-exchange on
+    /// it both finds and claims the slot, the concurrent claims of a device get the same one.
+    pub fn of_device_in(slots: &'static [CounterSlot], dev: u32) -> Result<Self> {
+        let key = CLAIMED_SLOT | dev as u64;
+        for slot in slots.iter() {
+            match slot
+                .key
+                .compare_exchange(0, key, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Ok(Self::new(&slot.value)),
+                Err(claimed) if claimed == key => return Ok(Self::new(&slot.value)),
+                Err(_) => continue,
+            }
+        }
+
+        pr_err!("No in-memory counter is left for device {:#x}", dev);
+        Err(ENOSPC)
+    }
+}
+
+impl TrustedCounter for MemoryCounter {
+    fn read(&mut self) -> Result<u64> {
+        Ok(self.cell.load(Ordering::SeqCst))
+    }
+
+    fn advance(&mut self, value: u64) -> Result {
+        self.cell
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                (value >= current).then(|| value)
+            })
+            .map(|_| ())
+            .map_err(|_| EINVAL)
+    }
+
+    fn init(&mut self, value: u64) -> Result {
+        self.cell.store(value, Ordering::SeqCst);
+        Ok(())
+    }
+}
@@ -1,47 +1,112 @@
 //! Device Mapper target event handlers of SwornDisk
 
 use crate::{
-    context::{SwornDiskContext, CONTEXT as context},
+    cipher::{CipherSuite, DataMode},
+    context::SwornDiskContext,
+    counter::TrustedCounter,
+    kdf::{KeyDeriver, KeyScheme},
+    options::TableOptions,
     prelude::*,
-    regions::{Checkpoint, DataSegment, IndexSegment, MemTable, SuperBlock},
-    utils::{current_timestamp, DebugIgnore, LruCache},
+    recovery::replay_journal,
+    regions::{Checkpoint, DataSegment, IndexSegment, Journal, MemTable, SuperBlock},
+    stats::Statistics,
+    utils::{current_timestamp, hex_to_slice, DebugIgnore, Hex, LruCache},
+    workers::{CompactionWorker, SegmentCleaner},
 };
 
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crypto::keyring::{self, Secret};
+
 use kernel::sync::{Mutex, RwSemaphore};
 
 pub(crate) struct DmSwornDiskHandler;
 
 impl DmCallbacks for DmSwornDiskHandler {
     // declares that this device mapper handler defined `dm_ctr_fn`, `dm_dtr_fn` and `dm_map_fn`
-    declare_device_mapper_callbacks!(ctr, dtr, map);
+    declare_device_mapper_callbacks!(
+        ctr,
+        dtr,
+        map,
+        status,
+        message,
+        postsuspend,
+        resume,
+        io_hints
+    );
 
     /// Constructor of SwornDisk device mapper target
     ///
     /// # Usage
     ///
-    /// `dmsetup create <target_name> <start> <nr_sector> sworndisk <data_dev_path> <meta_dev_path> <start_sector> <should_format>`
+    /// `dmsetup create <target_name> <start> <nr_sector> sworndisk <data_dev_path> <meta_dev_path> <start_sector> <should_format> <root_key> [<#opt_params> <opt_params>]`
+    ///
+    /// `root_key` is the key which encrypts the checkpoint, of the key length of the cipher
+    /// suite (128-bit for AES-128-GCM, 256-bit otherwise). It is either a hex string, or
+    /// `:<key_size>:logon:<key_description>` to read the key from the kernel keyring.
+    ///
+    /// See `TableOptions` for the optional parameters.
     ///
-    /// # Exaple
+    /// # Example
     ///
-    /// `dmsetup create test-sworndisk 0 58593750 sworndisk /dev/loop0 0` will create a
-    /// SwornDisk device mapper at `/dev/mapper/test-sworndisk` of size 30GiB (58593750 sectors).
+    /// `dmsetup create test-sworndisk 0 58593744 sworndisk /dev/loop0 /dev/loop1 0 force :16:logon:sworndisk:key`
+    /// will format and create a SwornDisk device mapper at `/dev/mapper/test-sworndisk` of
+    /// size 30GiB (58593744 sectors), whose 128-bit root key is the `logon` key
+    /// `sworndisk:key` in the kernel keyring. The size should be a multiple of `BLOCK_SECTORS`.
     fn ctr(mut target: DmTarget, args: Vec<&'static CStr>) -> Result<i32> {
-        // Check argument length should contain [dev_path, start_sector, force_format]
+        // Check argument length should contain [dev_path, start_sector, force_format, root_key]
         let argc = args.len();
-        if argc != 4 {
+        if argc < 5 {
             pr_warn!("Invalid arguments to construct a SwornDisk.");
-            pr_warn!("Accept paramteters: <data_dev> <meta_dev> <start_sector> <should_format>");
+            pr_warn!(
+                "Accept parameters: <data_dev> <meta_dev> <start_sector> <should_format> <root_key> [<#opt_params> <opt_params>]"
+            );
             return Err(EINVAL);
         }
 
-        // Set device mapper device
-        let mut data_dev = DmDev::new()?;
-        let path = args[0];
-        target.get_device(path, 0, &mut data_dev);
+        // The logical block size is `BLOCK_SIZE`, see `io_hints`
+        if target.len() % BLOCK_SECTORS != 0 {
+            pr_warn!(
+                "The target length {} is not a multiple of {} sectors.",
+                target.len(),
+                BLOCK_SECTORS
+            );
+            return Err(EINVAL);
+        }
+
+        // Parse the optional parameters
+        let options = TableOptions::parse(&args[5..])?;
+
+        // Parse the root key
+        let root_key = match Self::parse_root_key(str::from_utf8(args[4].as_bytes())?) {
+            Ok(key) => key,
+            Err(err) => {
+                pr_warn!(
+                    "Invalid root key, should be a hex string of 16 or {} bytes or `:<key_size>:logon:<key_description>`.",
+                    SWORNDISK_KEY_LENGTH
+                );
+                return Err(err);
+            }
+        };
 
-        let mut meta_dev = DmDev::new()?;
-        let path = args[1];
-        target.get_device(path, 0, &mut meta_dev);
+        // Set device mapper device. The devices are put back on any error since then.
+        let data_dev = Self::get_device(&mut target, args[0])?;
+        let data_guard = DeviceGuard::new(&target, &data_dev);
+
+        let meta_dev = Self::get_device(&mut target, args[1])?;
+        let meta_guard = DeviceGuard::new(&target, &meta_dev);
+
+        // The trusted counter belongs to the meta device, whatever path it is given by
+        let mut trusted_counter = match &options.trusted_counter {
+            Some(backend) => Some(backend.open(meta_dev.block_device()?.dev())?),
+            None => {
+                pr_warn!("No trusted counter is given, rollback protection is disabled.");
+                None
+            }
+        };
 
         // Create device mapper block manager
         let block_manager = DmBlockManager::new(
@@ -58,6 +123,10 @@ impl DmCallbacks for DmSwornDiskHandler {
         let index_nbytes = meta_nbytes / 2;
         let journal_nbytes = meta_nbytes / 4;
 
+        let key_scheme = options.key_scheme.unwrap_or_default();
+        let cipher = options.cipher.unwrap_or_default();
+        let data_mode = options.data_mode.unwrap_or_default();
+
         let (superblock, should_init) = match format_type {
             // if `format_type` is "force", then we are going to create an empty SwornDisk.
             "force" => {
@@ -67,6 +136,11 @@ impl DmCallbacks for DmSwornDiskHandler {
                         data_nbytes,
                         index_nbytes,
                         journal_nbytes,
+                        meta_nbytes,
+                        &root_key,
+                        key_scheme,
+                        cipher,
+                        data_mode,
                         &block_manager,
                     )?,
                     true,
@@ -76,6 +150,11 @@ impl DmCallbacks for DmSwornDiskHandler {
                 data_nbytes,
                 index_nbytes,
                 journal_nbytes,
+                meta_nbytes,
+                &root_key,
+                key_scheme,
+                cipher,
+                data_mode,
                 &block_manager,
                 format_type,
             )?,
@@ -83,41 +162,189 @@ impl DmCallbacks for DmSwornDiskHandler {
 
         pr_info!("SuperBlock: {:?}", superblock);
 
+        // The cipher suite is chosen at format time, an existing SwornDisk keeps its own
+        let cipher = superblock.cipher()?;
+        if let Some(given) = options.cipher {
+            if given != cipher {
+                pr_warn!(
+                    "Cipher suite {:?} is given, but the SwornDisk is formatted with {:?}.",
+                    given,
+                    cipher
+                );
+                return Err(EINVAL);
+            }
+        }
+
+        // So is the data mode
+        let data_mode = superblock.data_mode()?;
+        if let Some(given) = options.data_mode {
+            if given != data_mode {
+                pr_warn!(
+                    "Data mode {:?} is given, but the SwornDisk is formatted with {:?}.",
+                    given,
+                    data_mode
+                );
+                return Err(EINVAL);
+            }
+        }
+        Self::check_root_key(&root_key, cipher)?;
+
+        // Create an AEAD crypto handle of the cipher suite
+        let aead = cipher.aead()?;
+
+        // A wrong root key must not go any further
+        if !superblock.verify_key(&aead, &root_key)? {
+            pr_warn!("The root key does not match the key-check value in superblock.");
+            return Err(EINVAL);
+        }
+
+        // The key scheme is chosen at format time, an existing SwornDisk keeps its own
+        let kdf = KeyDeriver::new(superblock.key_scheme()?, cipher, &root_key)?;
+        if let Some(key_scheme) = options.key_scheme {
+            if key_scheme != kdf.scheme() {
+                pr_warn!(
+                    "Key scheme {:?} is given, but the SwornDisk is formatted with {:?}.",
+                    key_scheme,
+                    kdf.scheme()
+                );
+                return Err(EINVAL);
+            }
+        }
+
         // Create a device mapper I/O client
-        let mut dm_io_client = DmIoClient::new();
+        let dm_io_client = DmIoClient::new();
 
         // Read or create checkpoint
+        let meta_bdev = meta_dev.block_device()?;
+        let checkpoint_hba = superblock.checkpoint_hba();
+        let slot_sectors = superblock.checkpoint_slot_sectors();
         let mut checkpoint = match should_init {
-            true => Checkpoint::new(
-                superblock.data_segments_number(),
-                superblock.index_segments_number(),
-            )?,
+            true => {
+                Checkpoint::erase_on_disk(&meta_bdev, &dm_io_client, checkpoint_hba, slot_sectors)?;
+                Checkpoint::new(
+                    superblock.data_segments_number(),
+                    superblock.index_segments_number(),
+                )?
+            }
             false => {
                 pr_info!("Reading existed Checkpoint.");
-
-                let checkpoint_hba = superblock.checkpoint_region / SECTOR_SIZE;
-                let checkpoint_ondisk = Checkpoint::read_from_disk(
-                    &meta_dev.block_device()?,
-                    &mut dm_io_client,
+                Checkpoint::read_from_disk(
+                    &aead,
+                    &kdf,
+                    &root_key,
+                    &meta_bdev,
+                    &dm_io_client,
                     checkpoint_hba,
-                )?;
-                checkpoint_ondisk
+                    slot_sectors,
+                )?
             }
         };
 
-        // Create a data segment buffer
-        // TODO: Multi logging head
-        // TODO: Handle the situation if there is no segment left
-        let data_seg_buffer = {
-            let data_seg_index = checkpoint.data_svt.alloc()?;
-            let hba = (data_seg_index as u64) * SEGMENT_SECTORS;
-            DataSegment::new(hba)?
+        // Rollback protection: the journal must reach the block recorded by the trusted
+        // counter, a new SwornDisk continues the counter
+        let tail_seq = match trusted_counter.as_mut() {
+            Some(counter) => match counter.read() {
+                Ok(value) => value,
+                // only a new SwornDisk may start a counter that is missing or invalid
+                Err(err) if should_init => {
+                    pr_warn!(
+                        "The trusted counter is not readable ({:?}), reset to 0.",
+                        err
+                    );
+                    counter.init(0)?;
+                    0
+                }
+                Err(err) => {
+                    pr_err!("The trusted counter is not readable: {:?}", err);
+                    return Err(err);
+                }
+            },
+            None => 0,
         };
+        if should_init {
+            checkpoint.journal_head.seq = tail_seq;
+        }
 
-        let index_seg = IndexSegment::new(SEGMENT_SECTORS);
+        // Open the security journal from the head recorded in checkpoint
+        let mut journal = Journal::new(
+            checkpoint.journal_head,
+            superblock.journal_region / SECTOR_SIZE,
+            superblock.journal_size,
+            cipher,
+        )?;
 
         // Create an in-memory index tree
-        let memtable = MemTable::new();
+        let mut memtable = MemTable::new();
+
+        // Recovery: roll forward the journal records written after the checkpoint,
+        // the journal appending continues after them.
+        if !should_init {
+            let records = journal.read_records(&aead, &meta_bdev, &dm_io_client, tail_seq)?;
+            if let Some(counter) = trusted_counter.as_mut() {
+                counter.advance(journal.head().seq)?;
+            }
+            let report = replay_journal(&records, &mut checkpoint, &mut memtable)?;
+            pr_info!(
+                "Recovered from checkpoint {} and {} journal records: {:?}",
+                checkpoint.seq,
+                records.len(),
+                report
+            );
+        }
+
+        // The recovered state must be the one that the owner expects, which covers the
+        // newest checkpoint and the journal replayed after it
+        if let (false, Some(expected)) = (should_init, &options.root_hash) {
+            let root_hash = checkpoint.root_hash(&kdf, &journal.head())?;
+            if root_hash != *expected {
+                pr_err!(
+                    "Root hash of checkpoint {} and its journal is {}, but {} is expected.",
+                    checkpoint.seq,
+                    Hex(&root_hash),
+                    Hex(expected)
+                );
+                return Err(EINVAL);
+            }
+        }
+
+        // Bloom filters of the BITs are kept in memory, the reads consult them before
+        // descending any BIT
+        checkpoint
+            .bit_category
+            .load_filters(&aead, &kdf, &meta_bdev, &dm_io_client)?;
+
+        // Create a data segment buffer. The logging head recorded in checkpoint is reused
+        // if it is not exhausted, new blocks are appended after the written ones.
+        // TODO: Multi logging head
+        let data_seg_buffer = {
+            let current = checkpoint.current_data_segment;
+            if !checkpoint.data_svt.is_allocated(current)? || checkpoint.dst[current].is_exhausted()
+            {
+                checkpoint.current_data_segment = checkpoint.data_svt.alloc()?;
+            }
+            let current = checkpoint.current_data_segment;
+            let hba = (current as u64) * SEGMENT_SECTORS;
+            DataSegment::new(hba, checkpoint.dst[current].tail(), data_mode)?
+        };
+
+        let index_seg = IndexSegment::new(checkpoint.index_hba);
+
+        // Persist the initial state, so that the journal key is recorded
+        if should_init {
+            checkpoint.journal_head = journal.head();
+            checkpoint.write_to_disk(
+                &aead,
+                &kdf,
+                &root_key,
+                &meta_bdev,
+                &dm_io_client,
+                checkpoint_hba,
+                slot_sectors,
+            )?;
+            if let Some(counter) = trusted_counter.as_mut() {
+                counter.advance(journal.head().seq)?;
+            }
+        }
 
         // Create a work queue to handle async works
         let work_queue = WorkQueue::new(
@@ -138,22 +365,37 @@ impl DmCallbacks for DmSwornDiskHandler {
         let indirect_block_cache = LruCache::new(LRU_CACHE_MAX_SIZE)?;
         let leaf_block_cache = LruCache::new(LRU_CACHE_MAX_SIZE)?;
 
-        // Create a global context
+        // Create the context of this target
         let sworndisk_context = SwornDiskContext {
+            aead,
             block_manager,
             checkpoint,
             data_seg_buffer,
             dm_io_client,
             data_dev,
+            data_mode,
+            error_policy: options.error_policy,
             index_seg,
             indirect_block_cache,
+            journal,
+            kdf,
             leaf_block_cache,
             meta_dev,
             memtable,
+            memtable_threshold: MEMTABLE_THRESHOLD,
+            read_only: AtomicBool::new(false),
+            root_key,
+            scrubbing: AtomicBool::new(false),
+            scrub_on_resume: AtomicBool::new(false),
+            stats: Statistics::default(),
             superblock,
+            suspended: AtomicBool::new(false),
+            synced_seq: 0,
+            table: Self::table_line(&args)?,
+            trusted_counter,
             work_queue,
+            write_seq: AtomicU64::new(0),
 
-            aead: Aead::new(c_str!("gcm(aes)"), 0, 0)?,
             bio_queue: DebugIgnore(bio_queue),
             lock: DebugIgnore(lock),
             start: str::from_utf8(args[2].as_bytes())?
@@ -161,37 +403,265 @@ impl DmCallbacks for DmSwornDiskHandler {
                 .map_err(|_| EINVAL)?,
             rw_worker: [(); 6].map(|_| WorkStruct::new()),
             compaction_worker: WorkStruct::new(),
+            gc_worker: WorkStruct::new(),
+            scrub_worker: WorkStruct::new(),
         };
 
-        // SAFETY: Safe. The `context` will not be accessed before `ctx`.
-        // and each access of context should acquire the lock inside `context`.
+        // a bio never spans data segments, which bounds the time a request holds the lock
+        target.set_max_io_len(SEGMENT_SECTORS as u32);
+        // empty flush bios are passed to `map`, so REQ_PREFLUSH and REQ_FUA are honored
+        target.set_num_flush_bios(1);
+        target.set_flush_supported(true);
+        // discarded LBAs are replaced by tombstones in the index
+        target.set_num_discard_bios(1);
+        target.set_num_write_zeroes_bios(1);
+        target.set_discards_supported(true);
+
+        // SAFETY: Safe. The private field is dropped in `dtr`, and each access of the
+        // context should acquire the lock inside it.
         unsafe {
-            context = Some(Box::leak(Box::try_new(sworndisk_context)?));
-            context.as_mut().map(|ctx| ctx.init_workers());
+            target.init_lock_and_private(Box::try_new(sworndisk_context)?)?;
+            SwornDiskContext::of(&target)?.init_workers(&target)?;
         };
 
+        // the devices are owned by the context since then, and put back in `dtr`
+        data_guard.dismiss();
+        meta_guard.dismiss();
+
         Ok(0)
     }
 
     /// Destructor of SwornDisk device mapper target
-    fn dtr(target: DmTarget) -> Result {
+    fn dtr(mut target: DmTarget) -> Result {
         // drop the context and unregister device mapper target
-        let ctx = unsafe { context.take() };
-        if ctx.is_some() {
-            let ctx = ctx.unwrap();
-            ctx.flush()?;
+        // SAFETY: Safe. No more bio is mapped to the target, and the workers are drained below.
+        let ctx = unsafe { SwornDiskContext::of(&target) };
+        let mut result = Ok(());
+        if let Ok(ctx) = ctx {
+            // the queued works may still access the context
+            ctx.scrubbing.store(false, Ordering::Release);
+            ctx.work_queue.drain();
+
+            // release the devices and the key even if the last checkpoint fails
+            result = ctx.flush();
+            match result {
+                Ok(()) => pr_info!(
+                    "Checkpoint {} committed on exit, root hash: {}",
+                    ctx.checkpoint.seq,
+                    Hex(&ctx.root_hash().unwrap_or_default())
+                ),
+                Err(err) => pr_err!("Failed to commit checkpoint on exit: {:?}", err),
+            }
 
             target.put_device(&ctx.data_dev);
             target.put_device(&ctx.meta_dev);
 
-            drop(ctx);
+            // SAFETY: Safe. The context is never accessed since then.
+            unsafe { target.drop_private_field::<Box<SwornDiskContext>>() };
+        }
+
+        result
+    }
+
+    /// Status of SwornDisk
+    ///
+    /// `dmsetup status` reports `<checkpoint_seq> <root_hash>` of the newest committed
+    /// checkpoint and the journal after it, the root hash can be passed to the
+    /// `root_hash:<hex>` optional parameter on the next construction to detect a replayed
+    /// disk state. They are followed by
+    /// `<key>=<value>` statistics:
+    ///
+    /// - `data_segments=<free>/<used>` and `index_segments=<free>/<used>`
+    /// - `memtable=<extents>`
+    /// - `bits=<level 0>,<level 1>,...`: number of BITs on each level
+    /// - `indirect_cache=<hits>/<lookups>` and `leaf_cache=<hits>/<lookups>`
+    /// - `foreground_bytes`, `gc_bytes` and `compaction_bytes`: bytes written by user
+    ///   requests, segment cleaning and major compaction
+    /// - `scrubbed_bytes`: bytes verified by scrubbing
+    /// - `integrity_failures`: number of I/O requests failing the integrity check
+    ///
+    /// `dmsetup table` reports the table line, see `table_line`.
+    fn status(target: DmTarget, _type: u32, _flags: u32, result: &mut DmStatusResult) -> Result {
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`,
+        // except `ctx.table` which is never modified.
+        let ctx = unsafe { SwornDiskContext::of(&target)? };
+
+        if _type == StatusType::TABLE as u32 {
+            return result
+                .write_str(str::from_utf8(&ctx.table)?)
+                .map_err(|_| ENOSPC);
+        }
+        if _type != StatusType::INFO as u32 {
+            return Ok(());
+        }
+
+        let _lock = ctx.lock.read();
+        Self::write_status(ctx, result).map_err(|_| ENOSPC)
+    }
+
+    /// Message of SwornDisk, sent by `dmsetup message <name> 0 <command>`
+    ///
+    /// - `checkpoint`: commit a checkpoint, replies `<checkpoint_seq> <root_hash>`
+    /// - `compact [<level>]`: compact the BITs on `level` (every level if not given) into
+    ///   the next level, replies the number of compacted levels
+    /// - `gc [<segments>]`: clean at most `segments` (`MAX_GC_SEGMENTS` by default) data
+    ///   segments, replies `<reclaimed_segments> <moved_blocks>`
+    /// - `scrub start|stop`: start or stop verifying all the live blocks in background
+    /// - `cache drop`: drop the cached index blocks
+    /// - `set memtable_threshold <n>`: write MemTable back once it holds `n` extents
+    ///
+    /// The commands writing the disk fail with `EBUSY` while the target is suspended.
+    fn message(
+        target: DmTarget,
+        args: Vec<&'static CStr>,
+        result: &mut DmStatusResult,
+    ) -> Result<i32> {
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+        let ctx = unsafe { SwornDiskContext::of(&target)? };
+
+        let mut argv = [""; 3];
+        if args.len() > argv.len() {
+            pr_warn!("Too many arguments in message.");
+            return Err(EINVAL);
+        }
+        for (i, arg) in args.iter().enumerate() {
+            argv[i] = str::from_utf8(arg.as_bytes())?;
+        }
+        let argv = &argv[..args.len()];
+
+        let read_only = ctx.read_only.load(Ordering::Acquire);
+        let suspended = ctx.suspended.load(Ordering::Acquire);
+        match argv {
+            ["checkpoint"] | ["compact", ..] | ["gc", ..] if read_only => return Err(EROFS),
+            ["checkpoint"] | ["compact", ..] | ["gc", ..] | ["scrub", "start"] if suspended => {
+                return Err(EBUSY)
+            }
+            ["checkpoint"] => {
+                let _lock = ctx.rw_lock().write();
+                ctx.flush()?;
+                write!(result, "{} {}", ctx.checkpoint.seq, Hex(&ctx.root_hash()?))
+                    .map_err(|_| ENOSPC)?;
+            }
+            ["compact"] => {
+                let compacted = CompactionWorker::compact(ctx, 0..LSM_TREE_MAX_LEVEL - 1, 1)?;
+                write!(result, "{}", compacted).map_err(|_| ENOSPC)?;
+            }
+            ["compact", level] => {
+                let level = Self::parse_number(level)?;
+                if level >= LSM_TREE_MAX_LEVEL - 1 {
+                    pr_warn!("Level {} has no next level to compact into.", level);
+                    return Err(EINVAL);
+                }
+                let compacted = CompactionWorker::compact(ctx, level..level + 1, 1)?;
+                write!(result, "{}", compacted).map_err(|_| ENOSPC)?;
+            }
+            ["gc"] | ["gc", _] => {
+                let segments = match argv.get(1) {
+                    Some(segments) => Self::parse_number(segments)?,
+                    None => MAX_GC_SEGMENTS,
+                };
+                let _lock = ctx.rw_lock().write();
+                let (reclaimed, moved) = SegmentCleaner::clean(ctx, segments)?;
+                write!(result, "{} {}", reclaimed, moved).map_err(|_| ENOSPC)?;
+            }
+            ["scrub", "start"] => {
+                if !ctx.scrubbing.swap(true, Ordering::AcqRel) {
+                    ctx.work_queue.queue_work(&mut ctx.scrub_worker);
+                }
+            }
+            ["scrub", "stop"] => ctx.scrubbing.store(false, Ordering::Release),
+            ["cache", "drop"] => {
+                let _lock = ctx.rw_lock().write();
+                ctx.indirect_block_cache.clear();
+                ctx.leaf_block_cache.clear();
+            }
+            ["set", "memtable_threshold", threshold] => {
+                let threshold = Self::parse_number(threshold)?;
+                if threshold == 0 {
+                    return Err(EINVAL);
+                }
+                let _lock = ctx.rw_lock().write();
+                ctx.memtable_threshold = threshold;
+            }
+            _ => {
+                pr_warn!("Unknown message: {:?}", argv);
+                return Err(EINVAL);
+            }
+        }
+
+        Ok(if result.len() > 0 { 1 } else { 0 })
+    }
+
+    /// Post-suspend of SwornDisk
+    ///
+    /// All the mapped bios are completed when it is called. The workers are drained and a
+    /// checkpoint is committed, so the backing devices hold a consistent SwornDisk (e.g. for
+    /// an LVM snapshot) until the target is resumed. Scrubbing is paused until then.
+    fn postsuspend(target: DmTarget) -> Result {
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+        let ctx = unsafe { SwornDiskContext::of(&target)? };
+        ctx.suspended.store(true, Ordering::Release);
+
+        let scrubbing = ctx.scrubbing.swap(false, Ordering::AcqRel);
+        ctx.scrub_on_resume.store(scrubbing, Ordering::Release);
+
+        // wait for the workers handling the last bios, and the compaction and segment
+        // cleaning they triggered
+        ctx.work_queue.drain();
+
+        let _lock = ctx.rw_lock().write();
+        if ctx.read_only.load(Ordering::Acquire) {
+            pr_warn!("SwornDisk is read-only, no checkpoint is committed on suspend.");
+            return Ok(());
+        }
+
+        ctx.flush()?;
+        pr_info!(
+            "Checkpoint {} committed on suspend, root hash: {}",
+            ctx.checkpoint.seq,
+            Hex(&ctx.root_hash()?)
+        );
+
+        Ok(())
+    }
+
+    /// Resume of SwornDisk, restart the scrubbing paused by suspending
+    fn resume(target: DmTarget) -> Result {
+        // SAFETY: Safe. Only the atomic members and the work queue are accessed.
+        let ctx = unsafe { SwornDiskContext::of(&target)? };
+        ctx.suspended.store(false, Ordering::Release);
+
+        if ctx.scrub_on_resume.swap(false, Ordering::AcqRel)
+            && !ctx.scrubbing.swap(true, Ordering::AcqRel)
+        {
+            ctx.work_queue.queue_work(&mut ctx.scrub_worker);
         }
 
         Ok(())
     }
 
-    fn map(_target: DmTarget, mut bio: Bio) -> Result<i32> {
-        let ctx = unsafe { context.as_mut().unwrap() };
+    /// I/O hints of SwornDisk
+    ///
+    /// The requests are aligned to `BLOCK_SIZE`, so no partial block is written in general,
+    /// and the writes are preferred in whole data segments. A discard holds the lock for the
+    /// whole request, so it is limited to a data segment.
+    fn io_hints(_target: DmTarget, limits: &mut QueueLimits) -> Result {
+        limits.set_logical_block_size(BLOCK_SIZE as u32);
+        limits.set_physical_block_size(BLOCK_SIZE as u32);
+        limits.set_io_min(SEGMENT_SIZE as u32);
+        limits.set_io_opt(SEGMENT_SIZE as u32);
+
+        limits.set_discard_granularity(BLOCK_SIZE as u32);
+        limits.set_max_discard_sectors(SEGMENT_SECTORS as u32);
+        limits.set_max_hw_discard_sectors(SEGMENT_SECTORS as u32);
+        limits.set_max_write_zeroes_sectors(SEGMENT_SECTORS as u32);
+
+        Ok(())
+    }
+
+    fn map(target: DmTarget, mut bio: Bio) -> Result<i32> {
+        // SAFETY: Safe. Only the queues, which have their own locks, are accessed.
+        let ctx = unsafe { SwornDiskContext::of(&target)? };
 
         {
             let bdev = ctx.data_dev.block_device()?;
@@ -204,7 +674,7 @@ impl DmCallbacks for DmSwornDiskHandler {
         // }
 
         let status = match bio.operation() {
-            READ | WRITE | FLUSH => {
+            READ | WRITE | FLUSH | DISCARD | WRITE_ZEROES => {
                 // push the bio to queue
                 let bio_queue_lock = ctx.bio_queue.as_mut();
                 let mut bio_queue = bio_queue_lock.lock();
@@ -242,9 +712,25 @@ impl DmSwornDiskHandler {
         data_nbytes: u64,
         index_nbytes: u64,
         journal_nbytes: u64,
+        meta_nbytes: u64,
+        root_key: &[u8],
+        key_scheme: KeyScheme,
+        cipher: CipherSuite,
+        data_mode: DataMode,
         block_manager: &DmBlockManager,
     ) -> Result<SuperBlock> {
-        let superblock = SuperBlock::new(data_nbytes, index_nbytes, journal_nbytes);
+        Self::check_root_key(root_key, cipher)?;
+        let key_check = SuperBlock::key_check_value(&cipher.aead()?, root_key)?;
+        let superblock = SuperBlock::new(
+            data_nbytes,
+            index_nbytes,
+            journal_nbytes,
+            meta_nbytes,
+            key_check,
+            key_scheme,
+            cipher,
+            data_mode,
+        );
         superblock.write_to_disk(&block_manager)?;
 
         Ok(superblock)
@@ -255,12 +741,18 @@ impl DmSwornDiskHandler {
         data_nbytes: u64,
         index_nbytes: u64,
         journal_nbytes: u64,
+        meta_nbytes: u64,
+        root_key: &[u8],
+        key_scheme: KeyScheme,
+        cipher: CipherSuite,
+        data_mode: DataMode,
         block_manager: &DmBlockManager,
         format_type: &str,
     ) -> Result<(SuperBlock, bool)> {
-        match SuperBlock::read_from_disk(&block_manager) {
-            Ok(superblock) => Ok((superblock, false)),
-            Err(val) => {
+        // a disk of another on-disk format fails here, it must never be formatted over
+        match SuperBlock::read_from_disk(&block_manager)? {
+            Some(superblock) => Ok((superblock, false)),
+            None => {
                 pr_warn!("SwornDisk failed to read superblock from device.");
 
                 if format_type == "true" {
@@ -269,14 +761,203 @@ impl DmSwornDiskHandler {
                             data_nbytes,
                             index_nbytes,
                             journal_nbytes,
+                            meta_nbytes,
+                            root_key,
+                            key_scheme,
+                            cipher,
+                            data_mode,
                             block_manager,
                         )?,
                         true,
                     ))
                 } else {
-                    Err(val)
+                    Err(EINVAL)
                 }
             }
         }
     }
+
+    /// Get the device at `path` for `target`, it is put back with `DmTarget::put_device`
+    fn get_device(target: &mut DmTarget, path: &'static CStr) -> Result<DmDev> {
+        let mut dev = DmDev::new()?;
+        let ret = target.get_device(path, 0, &mut dev);
+        if ret != 0 {
+            pr_warn!(
+                "Failed to get device {}: {}",
+                str::from_utf8(path.as_bytes()).unwrap_or("?"),
+                ret
+            );
+            return Err(Error::from_kernel_errno(ret));
+        }
+
+        Ok(dev)
+    }
+
+    /// Check the length of `root_key` is the key length of `cipher`
+    fn check_root_key(root_key: &[u8], cipher: CipherSuite) -> Result {
+        if root_key.len() != cipher.key_len() {
+            pr_warn!(
+                "The root key is {} bytes, but cipher suite {:?} takes a key of {} bytes.",
+                root_key.len(),
+                cipher,
+                cipher.key_len()
+            );
+            return Err(EINVAL);
+        }
+
+        Ok(())
+    }
+
+    /// Write the INFO status of `ctx`, the caller should hold the read lock
+    fn write_status(ctx: &SwornDiskContext, result: &mut DmStatusResult) -> fmt::Result {
+        let checkpoint = &ctx.checkpoint;
+        let data_free = checkpoint.data_svt.available();
+        let index_free = checkpoint.index_svt.available();
+        write!(
+            result,
+            "{} {} data_segments={}/{} index_segments={}/{} memtable={} bits=",
+            checkpoint.seq,
+            Hex(&ctx.root_hash().map_err(|_| fmt::Error)?),
+            data_free,
+            checkpoint.data_svt.len() - data_free,
+            index_free,
+            checkpoint.index_svt.len() - index_free,
+            ctx.memtable.size(),
+        )?;
+
+        for level in 0..LSM_TREE_MAX_LEVEL {
+            if level > 0 {
+                result.write_char(',')?;
+            }
+            write!(result, "{}", checkpoint.bit_category.level_size(level))?;
+        }
+
+        let indirect = &ctx.indirect_block_cache;
+        let leaf = &ctx.leaf_block_cache;
+        write!(
+            result,
+            " indirect_cache={}/{} leaf_cache={}/{}",
+            indirect.hits(),
+            indirect.hits() + indirect.misses(),
+            leaf.hits(),
+            leaf.hits() + leaf.misses(),
+        )?;
+
+        write!(
+            result,
+            " foreground_bytes={} gc_bytes={} compaction_bytes={} scrubbed_bytes={} integrity_failures={}",
+            ctx.stats.foreground_bytes(),
+            ctx.stats.gc_bytes(),
+            ctx.stats.compaction_bytes(),
+            ctx.stats.scrubbed_bytes(),
+            ctx.stats.integrity_failures(),
+        )
+    }
+
+    /// Parse a decimal number in a message
+    fn parse_number(arg: &str) -> Result<usize> {
+        arg.parse::<usize>().map_err(|_| {
+            pr_warn!("Invalid number in message: {}", arg);
+            EINVAL
+        })
+    }
+
+    /// Build the table line reported by `dmsetup table` from the constructor arguments `args`.
+    ///
+    /// `force` is replaced by `false`, so the line never formats the disk again. The secret is
+    /// never shown: a root key given in hex is masked by `-`, and a key in the kernel keyring
+    /// is shown by its description. So only the line of a `:<key_size>:logon:<key_description>`
+    /// key can be reloaded as it is, e.g. by `dmsetup table | dmsetup load`; a hex root key
+    /// has to be given again.
+    fn table_line(args: &[&'static CStr]) -> Result<Vec<u8>> {
+        let mut table = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let arg = arg.as_bytes();
+            let arg: &[u8] = match i {
+                3 if arg == b"force" => b"false",
+                4 if !arg.starts_with(b":") => b"-",
+                _ => arg,
+            };
+
+            if i > 0 {
+                table.try_push(b' ')?;
+            }
+            table.try_extend_from_slice(arg)?;
+        }
+
+        Ok(table)
+    }
+
+    /// Parse the root key argument, which is either a hex string, or `:<key_size>:logon:<key_description>`
+    /// referring to a `logon` key in the kernel keyring (like dm-crypt). The key is either
+    /// 16 or 32 bytes, see `CipherSuite::key_len`.
+    fn parse_root_key(arg: &str) -> Result<Secret> {
+        if !arg.starts_with(':') {
+            return match arg.len() / 2 {
+                16 => Self::parse_hex_key::<16>(arg),
+                _ => Self::parse_hex_key::<{ SWORNDISK_KEY_LENGTH }>(arg),
+            };
+        }
+
+        let mut parts = arg[1..].splitn(3, ':');
+        let key_size = parts
+            .next()
+            .ok_or(EINVAL)?
+            .parse::<usize>()
+            .map_err(|_| EINVAL)?;
+        let key_type = parts.next().ok_or(EINVAL)?;
+        let description = parts.next().ok_or(EINVAL)?;
+        if (key_size != 16 && key_size != SWORNDISK_KEY_LENGTH)
+            || key_type != "logon"
+            || description.is_empty()
+        {
+            return Err(EINVAL);
+        }
+
+        // the description passed to the keyring should be null-terminated
+        let mut c_description = Vec::new();
+        c_description.try_extend_from_slice(description.as_bytes())?;
+        c_description.try_push(0)?;
+        let c_description = CStr::from_bytes_with_nul(&c_description).map_err(|_| EINVAL)?;
+
+        keyring::request_logon_key(c_description, key_size)
+    }
+
+    /// Parse a hex string of `N` bytes as a key, the copy on stack is wiped
+    fn parse_hex_key<const N: usize>(arg: &str) -> Result<Secret> {
+        let mut bytes = hex_to_slice::<N>(arg)?;
+        let key = Secret::from_slice(&bytes);
+        keyring::wipe(&mut bytes);
+
+        key
+    }
+}
+
+/// Put back a device got by `ctr` when it is dropped, unless it is dismissed once the
+/// device is owned by the context
+struct DeviceGuard {
+    target: DmTarget,
+    dev: Option<DmDev>,
+}
+
+impl DeviceGuard {
+    fn new(target: &DmTarget, dev: &DmDev) -> Self {
+        Self {
+            target: target.clone(),
+            dev: Some(dev.clone()),
+        }
+    }
+
+    /// Keep the device got
+    fn dismiss(mut self) {
+        self.dev = None;
+    }
+}
+
+impl Drop for DeviceGuard {
+    fn drop(&mut self) {
+        if let Some(dev) = self.dev.take() {
+            self.target.put_device(&dev);
+        }
+    }
 }
@@ -0,0 +1,202 @@
+//! Key derivation of the data and index blocks
+//!
+//! By default, each block is encrypted with a random key, which is stored in its record.
+//! With `KeyScheme::Derived`, the key of a block is derived from the key of its segment
+//! and its HBA, so a record carries only the nonce and MAC. The records are smaller, which
+//! raises the fan-out of BIT, and the index does not hold any key.
+//!
+//! The keys are derived with HMAC-SHA256 (the `hmac(sha256)` transform):
+//!
+//! - `master = HMAC(root_key, "sworndisk block keys")`
+//! - `segment_key = HMAC(master, region || segment)`
+//! - `block_key = HMAC(segment_key, hba || version)`, truncated to the key length of the
+//!   `CipherSuite`
+//!
+//! The version (see `Record::version`) is `seq + 1` of the checkpoint when the block is
+//! written. A data segment is only reused after the segment cleaner has released it and
+//! written a checkpoint, so the blocks written before and after the reuse get different
+//! keys. The blocks written again at the same HBA within one checkpoint, e.g. the ones
+//! lost in a crash before their journal commit, share a key. Each block has a random
+//! 96-bit nonce, so the nonces under a shared key still do not repeat.
+//!
+//! The root hash of the disk state (see `Checkpoint::root_hash`) is keyed as well, with
+//! `HMAC(root_key, "sworndisk root hash")`, so it tells nothing about the plain checkpoint.
+
+use crate::{
+    cipher::CipherSuite,
+    prelude::*,
+    regions::{Record, RecordLayout},
+    types::{HashType, KeyType},
+    utils::*,
+};
+
+use crypto::{keyring::Secret, Shash};
+
+use kernel::sync::Mutex;
+
+/// Label of the master key derived from the root key
+const MASTER_KEY_LABEL: &[u8] = b"sworndisk block keys";
+
+/// Label of the key of the root hash derived from the root key
+const HASH_KEY_LABEL: &[u8] = b"sworndisk root hash";
+
+/// How the blocks get their keys, chosen at format time and recorded in the superblock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyScheme {
+    /// A random key per block, stored in its record
+    Random,
+    /// A key per block derived from its segment and HBA, not stored
+    Derived,
+}
+
+impl Default for KeyScheme {
+    fn default() -> Self {
+        KeyScheme::Random
+    }
+}
+
+impl KeyScheme {
+    /// Parse the scheme recorded in the superblock
+    pub fn from_u64(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(KeyScheme::Random),
+            1 => Ok(KeyScheme::Derived),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// The value recorded in the superblock
+    pub fn as_u64(&self) -> u64 {
+        match self {
+            KeyScheme::Random => 0,
+            KeyScheme::Derived => 1,
+        }
+    }
+}
+
+/// Region of a block, the keys of different regions never collide
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyRegion {
+    Data = 0,
+    Index = 1,
+}
+
+/// Provide the key of each block according to the `KeyScheme` and `CipherSuite`
+pub struct KeyDeriver {
+    scheme: KeyScheme,
+    cipher: CipherSuite,
+    /// HMAC handle keyed with the master key, shared by the workers
+    master: Shash,
+    /// HMAC handle keyed with the key of the root hash, shared by the workers
+    hash: Shash,
+    /// HMAC handle rekeyed with the segment key of each derivation
+    segment: Pin<Box<Mutex<Shash>>>,
+}
+
+impl Debug for KeyDeriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyDeriver")
+            .field("scheme", &self.scheme)
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
+
+impl KeyDeriver {
+    /// Create a key deriver of `scheme` for `cipher`, whose keys are derived from `root_key`
+    pub fn new(scheme: KeyScheme, cipher: CipherSuite, root_key: &[u8]) -> Result<Self> {
+        let root = Self::hmac(root_key)?;
+        let master = Self::hmac(&Secret::new(root.digest(MASTER_KEY_LABEL)?))?;
+        let hash = Self::hmac(&Secret::new(root.digest(HASH_KEY_LABEL)?))?;
+
+        // SAFETY: `kernel::mutex_init!()` is called below.
+        let mut segment = Pin::from(Box::try_new(unsafe {
+            Mutex::new(Shash::new(c_str!("hmac(sha256)"), 0, 0)?)
+        })?);
+        kernel::mutex_init!(segment.as_mut(), "KeyDeriver::segment");
+
+        Ok(Self {
+            scheme,
+            cipher,
+            master,
+            hash,
+            segment,
+        })
+    }
+
+    pub fn scheme(&self) -> KeyScheme {
+        self.scheme
+    }
+
+    pub fn cipher(&self) -> CipherSuite {
+        self.cipher
+    }
+
+    /// Layout of the records in the index blocks
+    pub fn layout(&self) -> RecordLayout {
+        RecordLayout::of(self.scheme, self.cipher)
+    }
+
+    /// The key stored in the record of a new block, which is zeros if the key is derived
+    pub fn fresh_key(&self) -> Result<KeyType> {
+        match self.scheme {
+            KeyScheme::Random => self.cipher.random_key(),
+            KeyScheme::Derived => Ok([0; SWORNDISK_KEY_LENGTH]),
+        }
+    }
+
+    /// The key passed to the AEAD transform for the block at `hba` of `version`, whose
+    /// record stores `key`
+    pub fn block_key(
+        &self,
+        region: KeyRegion,
+        key: &KeyType,
+        hba: u64,
+        version: u64,
+    ) -> Result<Vec<u8>> {
+        if self.scheme == KeyScheme::Random {
+            return self.cipher.key(key);
+        }
+
+        let mut input = Vec::try_with_capacity(16)?;
+        input.try_push(region as u8)?;
+        input.try_extend_from_slice(&(hba / SEGMENT_SECTORS).to_le_bytes())?;
+        let segment_key = Secret::new(self.master.digest(&input)?);
+
+        input.clear();
+        input.try_extend_from_slice(&hba.to_le_bytes())?;
+        input.try_extend_from_slice(&version.to_le_bytes())?;
+        let block_key = {
+            let mut shash = self.segment.lock();
+            shash.set_key(&segment_key)?;
+            Secret::new(shash.digest(&input)?)
+        };
+
+        let mut key = Vec::new();
+        key.try_extend_from_slice(&block_key[..self.cipher.key_len()])?;
+        Ok(key)
+    }
+
+    /// The key of the data block of `record`
+    pub fn data_key(&self, record: &Record) -> Result<Vec<u8>> {
+        self.block_key(KeyRegion::Data, &record.key, record.hba, record.version)
+    }
+
+    /// The key of the index block (or Bloom filter) of `record`
+    pub fn index_key(&self, record: &Record) -> Result<Vec<u8>> {
+        self.block_key(KeyRegion::Index, &record.key, record.hba, record.version)
+    }
+
+    /// Keyed digest of `data` for the root hash, see `Checkpoint::root_hash`
+    pub fn digest(&self, data: &[u8]) -> Result<HashType> {
+        vec_to_slice::<{ SWORNDISK_HASH_LENGTH }>(&self.hash.digest(data)?)
+    }
+
+    /// Create an HMAC-SHA256 handle keyed with `key`. The handles zero their keys when
+    /// they are freed.
+    fn hmac(key: &[u8]) -> Result<Shash> {
+        let mut shash = Shash::new(c_str!("hmac(sha256)"), 0, 0)?;
+        shash.set_key(key)?;
+        Ok(shash)
+    }
+}
@@ -10,11 +10,17 @@
 extern crate alloc;
 extern crate kernel;
 
+mod cipher;
 mod constant;
 mod context;
+mod counter;
 mod handler;
+mod kdf;
+mod options;
 mod prelude;
+mod recovery;
 mod regions;
+mod stats;
 mod types;
 mod unittest;
 mod utils;
@@ -0,0 +1,186 @@
+//! Optional arguments of SwornDisk target table line
+//!
+//! The optional arguments follow the required ones in device-mapper style:
+//!
+//! `<#opt_params> <opt_param> ...`
+//!
+//! Supported optional parameters:
+//!
+//! - `trusted_counter:file:<path>`: rollback protection with the counter stored in file `path`,
+//!   which must hold a valid value unless the SwornDisk is formatted, see `FileCounter`
+//! - `trusted_counter:memory`: rollback protection with an in-memory counter of the meta
+//!   device (for testing)
+//! - `root_hash:<hex>`: the expected root hash of the newest checkpoint and the journal after
+//!   it, see `Checkpoint::root_hash`
+//! - `error_policy:<fail|readonly|panic>`: what to do when an I/O fails, see `ErrorPolicy`
+//! - `key_scheme:<random|derived>`: how the blocks get their keys, see `KeyScheme`. It only
+//!   takes effect when a SwornDisk is formatted, and should match the superblock otherwise.
+//! - `cipher:<aes-128-gcm|aes-256-gcm|chacha20-poly1305>`: the AEAD cipher suite, see
+//!   `CipherSuite`. Like `key_scheme`, it is recorded in the superblock at format time.
+//! - `data_mode:<encrypted|integrity>`: whether the data blocks are encrypted, or stored in
+//!   plaintext and only authenticated, see `DataMode`. It is recorded at format time as well.
+
+use crate::{
+    cipher::{CipherSuite, DataMode},
+    counter::{FileCounter, MemoryCounter, TrustedCounter},
+    kdf::KeyScheme,
+    prelude::*,
+    types::HashType,
+    utils::hex_to_slice,
+};
+
+/// Backend of the trusted counter
+#[derive(Debug)]
+pub enum CounterBackend {
+    /// A file or sysfs attribute, holds the path
+    File(Vec<u8>),
+    /// The in-memory counter of the device in the module
+    Memory,
+}
+
+impl CounterBackend {
+    /// Open the trusted counter of this backend for the SwornDisk whose meta device is `dev`
+    /// (its `dev_t`)
+    pub fn open(&self, dev: u32) -> Result<Box<dyn TrustedCounter>> {
+        Ok(match self {
+            CounterBackend::File(path) => Box::try_new(FileCounter::new(str::from_utf8(path)?)?)?,
+            CounterBackend::Memory => Box::try_new(MemoryCounter::of_device(dev)?)?,
+        })
+    }
+}
+
+/// What SwornDisk does when an I/O request fails, e.g. a block fails the integrity check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorPolicy {
+    /// Fail the I/O request only
+    Fail,
+    /// Fail the I/O request, and reject all the write requests since then
+    ReadOnly,
+    /// Panic the kernel
+    Panic,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        ErrorPolicy::Fail
+    }
+}
+
+/// Parsed optional arguments of SwornDisk
+#[derive(Debug, Default)]
+pub struct TableOptions {
+    /// trusted counter for rollback protection, disabled if `None`
+    pub trusted_counter: Option<CounterBackend>,
+    /// expected root hash of the newest checkpoint, not checked if `None`
+    pub root_hash: Option<HashType>,
+    /// policy on I/O errors
+    pub error_policy: ErrorPolicy,
+    /// key scheme of a new SwornDisk, `KeyScheme::Random` if `None`
+    pub key_scheme: Option<KeyScheme>,
+    /// cipher suite of a new SwornDisk, `CipherSuite::Aes128Gcm` if `None`
+    pub cipher: Option<CipherSuite>,
+    /// data mode of a new SwornDisk, `DataMode::Encrypted` if `None`
+    pub data_mode: Option<DataMode>,
+}
+
+impl TableOptions {
+    /// Parse the optional arguments `args`, which starts with the number of parameters
+    pub fn parse(args: &[&'static CStr]) -> Result<Self> {
+        let mut options = Self::default();
+        if args.is_empty() {
+            return Ok(options);
+        }
+
+        let count = str::from_utf8(args[0].as_bytes())?
+            .parse::<usize>()
+            .map_err(|_| EINVAL)?;
+        if count != args.len() - 1 {
+            pr_warn!(
+                "Expect {} optional parameters, but got {}.",
+                count,
+                args.len() - 1
+            );
+            return Err(EINVAL);
+        }
+
+        for arg in args[1..].iter() {
+            let arg = str::from_utf8(arg.as_bytes())?;
+            let (key, value) = match arg.split_once(':') {
+                Some((key, value)) => (key, value),
+                None => (arg, ""),
+            };
+
+            match key {
+                "trusted_counter" => {
+                    options.trusted_counter = Some(match value.split_once(':') {
+                        Some(("file", path)) if !path.is_empty() => {
+                            let mut vec = Vec::new();
+                            vec.try_extend_from_slice(path.as_bytes())?;
+                            CounterBackend::File(vec)
+                        }
+                        None if value == "memory" => CounterBackend::Memory,
+                        _ => {
+                            pr_warn!("Invalid trusted counter: {}", value);
+                            return Err(EINVAL);
+                        }
+                    })
+                }
+                "root_hash" => {
+                    options.root_hash = Some(
+                        hex_to_slice::<{ SWORNDISK_HASH_LENGTH }>(value).map_err(|err| {
+                            pr_warn!(
+                                "Invalid root hash, should be a hex string of {} bytes.",
+                                SWORNDISK_HASH_LENGTH
+                            );
+                            err
+                        })?,
+                    )
+                }
+                "error_policy" => {
+                    options.error_policy = match value {
+                        "fail" => ErrorPolicy::Fail,
+                        "readonly" => ErrorPolicy::ReadOnly,
+                        "panic" => ErrorPolicy::Panic,
+                        _ => {
+                            pr_warn!("Invalid error policy: {}", value);
+                            return Err(EINVAL);
+                        }
+                    }
+                }
+                "key_scheme" => {
+                    options.key_scheme = Some(match value {
+                        "random" => KeyScheme::Random,
+                        "derived" => KeyScheme::Derived,
+                        _ => {
+                            pr_warn!("Invalid key scheme: {}", value);
+                            return Err(EINVAL);
+                        }
+                    })
+                }
+                "cipher" => match CipherSuite::from_name(value) {
+                    Ok(cipher) => options.cipher = Some(cipher),
+                    Err(err) => {
+                        pr_warn!("Invalid cipher suite: {}", value);
+                        return Err(err);
+                    }
+                },
+                "data_mode" => {
+                    options.data_mode = Some(match value {
+                        "encrypted" => DataMode::Encrypted,
+                        "integrity" => DataMode::Integrity,
+                        _ => {
+                            pr_warn!("Invalid data mode: {}", value);
+                            return Err(EINVAL);
+                        }
+                    })
+                }
+                _ => {
+                    pr_warn!("Unknown optional parameter: {}", arg);
+                    return Err(EINVAL);
+                }
+            }
+        }
+
+        Ok(options)
+    }
+}
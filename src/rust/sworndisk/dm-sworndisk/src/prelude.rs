@@ -12,7 +12,8 @@ pub use crypto::Aead;
 
 pub use device_mapper::{
     declare_device_mapper_callbacks, Bio, BlockDevice, DmBlock, DmBlockManager, DmCallbacks, DmDev,
-    DmIoClient, DmIoRegion, DmIoRequest, DmTarget, TargetType,
+    DmIoClient, DmIoRegion, DmIoRequest, DmStatusResult, DmTarget, QueueLimits, StatusType,
+    TargetType, BLK_STS_IOERR, BLK_STS_NOSPC, BLK_STS_PROTECTION,
 };
 
 pub use super::constant::*;
@@ -0,0 +1,125 @@
+//! SwornDisk crash recovery
+//!
+//! The checkpoint only persists the state at the time it is written. Everything happened
+//! after it is rolled forward from the security journal: the data segments (or the parts
+//! of them) committed after the checkpoint are re-indexed in MemTable, the discarded LBAs
+//! are replaced by tombstones, and the BITs created or compacted after the checkpoint are
+//! re-applied to BITCategory.
+
+use crate::{
+    prelude::*,
+    regions::{Checkpoint, Extent, JournalRecord, MemTable},
+    utils::TryClone,
+};
+
+/// What a journal replay recovered
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// number of committed data segments
+    pub segments: usize,
+    /// number of data blocks in the committed data segments
+    pub blocks: usize,
+    /// number of BITs created
+    pub bits: usize,
+    /// number of compactions
+    pub compactions: usize,
+    /// number of data blocks logged but never committed, which are discarded
+    pub discarded: usize,
+    /// number of LBAs replaced by tombstones
+    pub tombstones: usize,
+}
+
+/// Roll forward the journal records written after `checkpoint`, rebuild the MemTable
+/// and the DST / SVT / BITCategory in `checkpoint`.
+pub fn replay_journal(
+    records: &Vec<JournalRecord>,
+    checkpoint: &mut Checkpoint,
+    memtable: &mut MemTable,
+) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+    let mut pending: Vec<Extent> = Vec::new();
+
+    for record in records.iter() {
+        match record {
+            JournalRecord::DataLog { extent } => pending.try_push(extent.try_clone()?)?,
+            JournalRecord::DataSegmentCommit { segment } => {
+                let segment = *segment as usize;
+                if segment >= checkpoint.dst.len() {
+                    return Err(EBADMSG);
+                }
+
+                if !checkpoint.data_svt.is_allocated(segment)? {
+                    checkpoint.data_svt.alloc_at(segment)?;
+                }
+
+                for extent in pending.iter() {
+                    let last_hba = extent.hba + (extent.len - 1) * BLOCK_SECTORS;
+                    if extent.is_tombstone()
+                        || (extent.hba / SEGMENT_SECTORS) as usize != segment
+                        || (last_hba / SEGMENT_SECTORS) as usize != segment
+                    {
+                        return Err(EBADMSG);
+                    }
+
+                    let index = ((extent.hba % SEGMENT_SECTORS) / BLOCK_SECTORS) as usize;
+                    for i in 0..extent.len {
+                        checkpoint.dst[segment].set_block(
+                            index + i as usize,
+                            extent.lba + i,
+                            extent.version,
+                        )?;
+                    }
+
+                    for old_extent in memtable.insert(extent.try_clone()?)?.iter() {
+                        checkpoint.invalidate_extent(old_extent)?;
+                    }
+                    report.blocks += extent.len as usize;
+                }
+
+                report.segments += 1;
+                pending.clear();
+
+                // the logging head is the segment of the last commit. Once it is exhausted,
+                // the next commit records the segment allocated for the head, or a new one
+                // is allocated when the target is constructed.
+                checkpoint.current_data_segment = segment;
+            }
+            JournalRecord::BITCreation {
+                level,
+                root_meta,
+                index_hba,
+            } => {
+                // a level 0 BIT is always created from the whole MemTable
+                if *level == 0 {
+                    memtable.clear();
+                }
+
+                checkpoint
+                    .bit_category
+                    .restore_bit(root_meta.clone(), *level as usize)?;
+                checkpoint.index_hba = *index_hba;
+                report.bits += 1;
+            }
+            JournalRecord::Compaction { level, ids } => {
+                for id in ids.iter() {
+                    checkpoint.bit_category.release_bit(*level as usize, *id)?;
+                }
+                report.compactions += 1;
+            }
+            JournalRecord::Discard { begin, end } => {
+                for old_extent in memtable
+                    .insert(Extent::tombstone(*begin, *end - *begin))?
+                    .iter()
+                {
+                    checkpoint.invalidate_extent(old_extent)?;
+                }
+                report.tombstones += (*end - *begin) as usize;
+            }
+            JournalRecord::Checkpoint { .. } => {}
+        }
+    }
+
+    report.discarded = pending.iter().map(|extent| extent.len as usize).sum();
+
+    Ok(report)
+}
@@ -1,4 +1,5 @@
 use crate::{
+    kdf::KeyDeriver,
     prelude::*,
     regions::{IndirectBlock, IndirectRecord, BIT},
     utils::*,
@@ -8,8 +9,9 @@ use crate::regions::*;
 
 use core::iter::Rev;
 use core::slice::Iter;
+use kernel::rbtree::RBTree;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BITRootMeta {
     /// unique ID of BIT
     pub unique_id: u64,
@@ -20,11 +22,17 @@ pub struct BITRootMeta {
     /// BIT level
     pub level: usize,
 
-    /// element number of BIT
+    /// extent number of BIT
     pub size: usize,
+
+    /// data blocks indexed by the extents of BIT
+    pub blocks: usize,
+
+    /// on-disk location of the Bloom filter
+    pub bloom: BloomFilterMeta,
 }
 
-const BIT_ROOT_META_SIZE: usize = mem::size_of::<BITRootMeta>();
+pub const BIT_ROOT_META_SIZE: usize = mem::size_of::<BITRootMeta>();
 
 impl Serialize for BITRootMeta {
     fn serialize(&self) -> Result<Vec<u8>> {
@@ -32,7 +40,9 @@ impl Serialize for BITRootMeta {
         vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.unique_id) })?;
         vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.level) })?;
         vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.size) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.blocks) })?;
         vec.try_extend_from_slice(&self.record.serialize()?)?;
+        vec.try_extend_from_slice(&self.bloom.serialize()?)?;
         vec.try_resize(BIT_ROOT_META_SIZE, 0u8)?;
 
         Ok(vec)
@@ -48,14 +58,20 @@ impl Deserialize for BITRootMeta {
         let unique_id = unsafe { mem::transmute::<[u8; 8], u64>(buf[0..8].try_into().unwrap()) };
         let level = unsafe { mem::transmute::<[u8; 8], usize>(buf[8..16].try_into().unwrap()) };
         let size = unsafe { mem::transmute::<[u8; 8], usize>(buf[16..24].try_into().unwrap()) };
+        let blocks = unsafe { mem::transmute::<[u8; 8], usize>(buf[24..32].try_into().unwrap()) };
         let record =
-            IndirectRecord::deserialize(buf[24..24 + INDIRECT_RECORD_SIZE].try_into().unwrap())?;
+            IndirectRecord::deserialize(buf[32..32 + INDIRECT_RECORD_SIZE].try_into().unwrap())?;
+        let bloom_index = 32 + INDIRECT_RECORD_SIZE;
+        let bloom =
+            BloomFilterMeta::deserialize(&buf[bloom_index..bloom_index + BLOOM_FILTER_META_SIZE])?;
 
         Ok(Self {
             size,
+            blocks,
             unique_id,
             level,
             record,
+            bloom,
         })
     }
 }
@@ -71,13 +87,14 @@ impl BITRootMeta {
     pub fn read_from_disk(
         &self,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         bdev: &BlockDevice,
         client: &DmIoClient,
         cache: &mut LruCache<u64, IndirectBlock>,
     ) -> Result<BIT> {
         let record = self.record.record;
         let root = match cache.get(&record.hba) {
-            Some(root_block) => root_block.clone(),
+            Some(root_block) => root_block.try_clone()?,
             _ => {
                 let mut block = Vec::new();
                 block.try_resize(BLOCK_SIZE as usize, 0u8)?;
@@ -90,20 +107,22 @@ impl BITRootMeta {
                     0,
                     client,
                 );
-                io_req.submit(&mut region);
+                io_req.submit(&mut region)?;
 
+                let mut assoc = record.associated_data(self.record.lba_range.0)?;
                 unsafe {
-                    aead.as_ref().decrypt_in_place(
-                        &slice_to_vec::<{ SWORNDISK_KEY_LENGTH }>(&record.key)?,
+                    aead.as_ref().decrypt_in_place_with_ad(
+                        &kdf.index_key(&record)?,
                         &mut slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&record.mac)?,
                         &mut slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&record.nonce)?,
+                        &mut assoc[..],
                         &mut block,
                         BLOCK_SIZE as usize,
                     )?
                 };
 
                 let root = IndirectBlock::deserialize(&block)?;
-                cache.put(record.hba, root.clone())?;
+                cache.put(record.hba, root.try_clone()?)?;
 
                 root
             }
@@ -112,10 +131,31 @@ impl BITRootMeta {
         Ok(BIT {
             root,
             size: self.size,
+            blocks: self.blocks,
             record: self.record.clone(),
             level: self.level,
+            bloom: self.bloom,
+            filter: None,
         })
     }
+
+    /// Read the Bloom filter of BIT from disk
+    pub fn read_filter(
+        &self,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result<BloomFilter> {
+        BloomFilter::read_from_disk(
+            &self.bloom,
+            self.record.lba_range.0,
+            aead,
+            kdf,
+            bdev,
+            client,
+        )
+    }
 }
 
 /// Block Index Table Category (BITC)
@@ -126,6 +166,9 @@ pub struct BITCategory {
 
     /// Root node vector
     pub category: Vec<Vec<BITRootMeta>>,
+
+    /// Bloom filters of the BITs in memory, indexed by unique_id
+    filters: DebugIgnore<RBTree<u64, BloomFilter>>,
 }
 
 impl Serialize for BITCategory {
@@ -171,6 +214,7 @@ impl Deserialize for BITCategory {
         Ok(Self {
             bit_unique_id,
             category,
+            filters: DebugIgnore(RBTree::new()),
         })
     }
 }
@@ -186,6 +230,7 @@ impl BITCategory {
         Ok(Self {
             category,
             bit_unique_id: 0,
+            filters: DebugIgnore(RBTree::new()),
         })
     }
 
@@ -209,14 +254,32 @@ impl BITCategory {
             record: bit.record,
             level: bit.level,
             size: bit.size(),
+            blocks: bit.blocks,
+            bloom: bit.bloom,
         };
 
+        if let Some(filter) = bit.filter {
+            self.filters.try_insert(self.bit_unique_id, filter)?;
+        }
         self.bit_unique_id += 1;
         self.category[level].try_push(meta_info)?;
 
         Ok(())
     }
 
+    /// Add the root metainfo of an existing BIT into BITCategory, this is used in recovery.
+    /// The Bloom filter of the BIT is read later by `load_filters`.
+    pub fn restore_bit(&mut self, root_meta: BITRootMeta, level: usize) -> Result {
+        if level >= LSM_TREE_MAX_LEVEL {
+            return Err(EINVAL);
+        }
+
+        self.bit_unique_id = cmp::max(self.bit_unique_id, root_meta.unique_id + 1);
+        self.category[level].try_push(root_meta)?;
+
+        Ok(())
+    }
+
     /// Get the number of BIT in a certain level
     pub fn level_size(&self, level: usize) -> usize {
         self.category[level].len()
@@ -230,6 +293,11 @@ impl BITCategory {
         Some(&self.category[level][index])
     }
 
+    /// Get the root metainfo of the latest BIT in a certain level
+    pub fn last_bit(&self, level: usize) -> Option<&BITRootMeta> {
+        self.category.get(level)?.last()
+    }
+
     /// Remove a BIT through its unique_id
     pub fn release_bit(&mut self, level: usize, unique_id: u64) -> Result {
         let mut index = None;
@@ -243,12 +311,42 @@ impl BITCategory {
         match index {
             Some(idx) => {
                 self.category[level].remove(idx);
+                self.filters.remove(&unique_id);
                 Ok(())
             }
             None => Err(EINVAL),
         }
     }
 
+    /// Read the Bloom filters of the BITs restored from disk
+    pub fn load_filters(
+        &mut self,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result {
+        for per_level_category in self.category.iter() {
+            for root_meta in per_level_category.iter() {
+                if self.filters.get(&root_meta.unique_id).is_none() {
+                    let filter = root_meta.read_filter(aead, kdf, bdev, client)?;
+                    self.filters.try_insert(root_meta.unique_id, filter)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the BIT of `root_meta` may contain `lba`. It is always true
+    /// if the Bloom filter of the BIT is not loaded.
+    pub fn may_contain(&self, root_meta: &BITRootMeta, lba: u64) -> bool {
+        match self.filters.get(&root_meta.unique_id) {
+            Some(filter) => filter.may_contain(lba),
+            None => true,
+        }
+    }
+
     /// Reversely iterate the BIT of a certain level
     pub fn iter_level(&self, level: usize) -> Result<Rev<Iter<'_, BITRootMeta>>> {
         if level >= self.category.len() {
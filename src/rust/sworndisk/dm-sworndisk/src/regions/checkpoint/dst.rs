@@ -1,5 +1,14 @@
 use crate::{prelude::*, utils::*};
 
+/// Size of the reverse index table of a DST (unit: Byte)
+pub const DST_RIT_SIZE: usize = SEGMENT_BLOCK_NUMBER as usize * 8;
+
+/// Size of the version table of a DST (unit: Byte)
+pub const DST_VERSION_SIZE: usize = SEGMENT_BLOCK_NUMBER as usize * 8;
+
+/// Size of the serialized DST before the BVM: last_modify, tail and BVM length (unit: Byte)
+pub const DST_HEADER_SIZE: usize = 24;
+
 /// Data Segment Table (DST)
 #[derive(Debug)]
 pub struct DST {
@@ -7,15 +16,32 @@ pub struct DST {
     bvm: BitMap,
     /// Last modification timestamp
     last_modify: u64,
+    /// Reverse Index Table (RIT), maps the block index of the segment to its LBA.
+    /// The segment cleaner relies on it to re-index the blocks it moves.
+    rit: Vec<u64>,
+    /// Version of the record written to each block, 0 if the block is not written yet.
+    /// A stale record only invalidates the block if both its LBA and version match.
+    versions: Vec<u64>,
+    /// Number of blocks allocated from the beginning of the segment. The blocks before
+    /// the tail may have been written, so new blocks are only appended after it.
+    tail: usize,
 }
 
 impl DST {
     /// Create a new DST
     pub fn new() -> Result<Self> {
         let bvm = BitMap::new(SEGMENT_BLOCK_NUMBER as usize)?;
+        let mut rit = Vec::try_with_capacity(SEGMENT_BLOCK_NUMBER as usize)?;
+        rit.try_resize(SEGMENT_BLOCK_NUMBER as usize, u64::MAX)?;
+        let mut versions = Vec::try_with_capacity(SEGMENT_BLOCK_NUMBER as usize)?;
+        versions.try_resize(SEGMENT_BLOCK_NUMBER as usize, 0)?;
+
         Ok(Self {
             bvm,
+            rit,
+            versions,
             last_modify: current_timestamp(),
+            tail: 0,
         })
     }
 
@@ -24,24 +50,76 @@ impl DST {
         self.bvm.is_full()
     }
 
-    /// Alloc a new block and mark as used
-    pub fn alloc_block(&mut self) -> Result<usize> {
-        let index = self.bvm.get_first_zero_bit()?;
+    /// Get the number of valid blocks
+    pub fn valid_blocks(&self) -> usize {
+        self.bvm.len() - self.bvm.available()
+    }
+
+    /// Check no block can be allocated in the segment, i.e. the tail reaches its end
+    pub fn is_exhausted(&self) -> bool {
+        self.tail >= SEGMENT_BLOCK_NUMBER as usize
+    }
+
+    /// Get the number of blocks allocated from the beginning of the segment
+    pub fn tail(&self) -> usize {
+        self.tail
+    }
+
+    /// Get the last modification timestamp
+    pub fn last_modify(&self) -> u64 {
+        self.last_modify
+    }
+
+    /// Check the block at `index` is valid
+    pub fn is_valid(&self, index: usize) -> Result<bool> {
+        self.bvm.get_bit(index)
+    }
+
+    /// Get the LBA of the block at `index`
+    pub fn lba(&self, index: usize) -> u64 {
+        self.rit[index]
+    }
+
+    /// Get the version of the record written to the block at `index`
+    pub fn version(&self, index: usize) -> u64 {
+        self.versions[index]
+    }
+
+    /// Set the version of the record written to the block at `index`
+    pub fn set_version(&mut self, index: usize, version: u64) {
+        self.versions[index] = version;
+    }
+
+    /// Alloc the block at the tail for `lba` and mark as used, returns `ENOSPC` if the
+    /// segment is exhausted
+    pub fn alloc_block(&mut self, lba: u64) -> Result<usize> {
+        if self.is_exhausted() {
+            return Err(ENOSPC);
+        }
+
+        let index = self.tail;
         self.bvm.set_bit(index)?;
+        self.rit[index] = lba;
+        self.versions[index] = 0;
+        self.tail += 1;
         self.last_modify = current_timestamp();
 
         Ok(index)
     }
 
-    /// Set a block as used
-    pub fn set_block(&mut self, index: usize) -> Result {
+    /// Set a block as used by the record of `lba` with `version`, the tail is moved after
+    /// it if it is not yet
+    pub fn set_block(&mut self, index: usize, lba: u64, version: u64) -> Result {
         self.bvm.set_bit(index)?;
+        self.rit[index] = lba;
+        self.versions[index] = version;
+        self.tail = cmp::max(self.tail, index + 1);
         self.last_modify = current_timestamp();
 
         Ok(())
     }
 
-    /// Release a block
+    /// Release a block, the block is invalid since then
     pub fn clear_block(&mut self, index: usize) -> Result {
         self.bvm.clear_bit(index)?;
         self.last_modify = current_timestamp();
@@ -58,8 +136,15 @@ impl Serialize for DST {
         let bvm_len = bvm.len();
 
         vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.last_modify) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.tail) })?;
         vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(bvm_len) })?;
         vec.try_extend_from_slice(&bvm)?;
+        for lba in self.rit.iter() {
+            vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*lba) })?;
+        }
+        for version in self.versions.iter() {
+            vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*version) })?;
+        }
 
         Ok(vec)
     }
@@ -68,11 +153,39 @@ impl Serialize for DST {
 impl Deserialize for DST {
     fn deserialize(buf: &[u8]) -> Result<Self> {
         let last_modify = unsafe { mem::transmute::<[u8; 8], u64>(buf[0..8].try_into().unwrap()) };
-        let bvm_len = unsafe { mem::transmute::<[u8; 8], usize>(buf[8..16].try_into().unwrap()) };
-        if bvm_len + 16 != buf.len() {
+        let tail = unsafe { mem::transmute::<[u8; 8], usize>(buf[8..16].try_into().unwrap()) };
+        let bvm_len = unsafe { mem::transmute::<[u8; 8], usize>(buf[16..24].try_into().unwrap()) };
+        if bvm_len + DST_HEADER_SIZE + DST_RIT_SIZE + DST_VERSION_SIZE != buf.len()
+            || tail > SEGMENT_BLOCK_NUMBER as usize
+        {
             return Err(EINVAL);
         }
-        let bvm = BitMap::deserialize(&buf[16..16 + bvm_len])?;
-        Ok(Self { bvm, last_modify })
+        let bvm = BitMap::deserialize(&buf[DST_HEADER_SIZE..DST_HEADER_SIZE + bvm_len])?;
+
+        let mut rit = Vec::try_with_capacity(SEGMENT_BLOCK_NUMBER as usize)?;
+        for i in 0..SEGMENT_BLOCK_NUMBER as usize {
+            let index = DST_HEADER_SIZE + bvm_len + i * 8;
+            let lba = unsafe {
+                mem::transmute::<[u8; 8], u64>(buf[index..index + 8].try_into().unwrap())
+            };
+            rit.try_push(lba)?;
+        }
+
+        let mut versions = Vec::try_with_capacity(SEGMENT_BLOCK_NUMBER as usize)?;
+        for i in 0..SEGMENT_BLOCK_NUMBER as usize {
+            let index = DST_HEADER_SIZE + bvm_len + DST_RIT_SIZE + i * 8;
+            let version = unsafe {
+                mem::transmute::<[u8; 8], u64>(buf[index..index + 8].try_into().unwrap())
+            };
+            versions.try_push(version)?;
+        }
+
+        Ok(Self {
+            bvm,
+            last_modify,
+            rit,
+            versions,
+            tail,
+        })
     }
 }
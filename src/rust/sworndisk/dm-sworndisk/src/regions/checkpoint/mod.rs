@@ -7,12 +7,22 @@ pub use dst::*;
 pub use svt::*;
 
 use crate::{
+    kdf::KeyDeriver,
     prelude::*,
-    utils::{Deserialize, Serialize},
+    regions::{Extent, JournalHead, JOURNAL_HEAD_SIZE, TOMBSTONE_HBA},
+    types::{HashType, MacType, NonceType},
+    utils::{slice_to_vec, vec_to_slice, Deserialize, Serialize},
 };
 
+use crypto::{get_random_bytes, keyring::Secret};
+
 /// SwornDisk Checkpoint Region
+///
+/// There are two checkpoint slots in the checkpoint region, which are written alternately.
+/// So a torn checkpoint write never destroys the previous checkpoint.
 pub struct Checkpoint {
+    /// Sequence number of the checkpoint, the newer one has a greater number
+    pub seq: u64,
     /// Data Segment Validity Table
     pub data_svt: SVT,
     /// Index Segment Validity Table
@@ -23,6 +33,14 @@ pub struct Checkpoint {
     pub current_data_segment: usize,
     /// BIT Category
     pub bit_category: BITCategory,
+    /// Head of the security journal, replaying starts from here
+    pub journal_head: JournalHead,
+    /// HBA of the next free block in index segment
+    pub index_hba: u64,
+    /// Keyed digest of the plain checkpoint body (see `KeyDeriver::digest`), which covers
+    /// the sequence number, journal head, BITCategory, SVTs and DSTs. It is not written to
+    /// disk, but derived from the checkpoint when it is written or read.
+    pub digest: HashType,
 }
 
 impl Debug for Checkpoint {
@@ -35,10 +53,14 @@ impl Checkpoint {
     /// Create an empty checkpoint region. This is used in the init phase of SwornDisk.
     pub fn new(data_segs: u64, index_segs: u64) -> Result<Self> {
         let checkpoint = Checkpoint {
+            seq: 0,
+            index_hba: SEGMENT_SECTORS,
             bit_category: BITCategory::new()?,
             data_svt: SVT::new(data_segs)?,
             index_svt: SVT::new(index_segs)?,
             current_data_segment: 0,
+            journal_head: JournalHead::new()?,
+            digest: [0; SWORNDISK_HASH_LENGTH],
             dst: {
                 let mut dst_vec = Vec::try_with_capacity(data_segs as usize)?;
                 for _ in 0..data_segs {
@@ -51,24 +73,36 @@ impl Checkpoint {
         Ok(checkpoint)
     }
 
-    /// Write the checkpoint to disk.
+    /// Write the checkpoint to disk, the slot of the older checkpoint is overwritten.
+    ///
+    /// The checkpoint body is encrypted and authenticated with `root_key`, and begins
+    /// with a copy of the meta info, so the plain meta info can be verified after decryption.
     ///
-    /// The layout of checkpoint region in the disk:
+    /// The layout of a checkpoint slot in the disk:
     ///
-    /// - meta info of checkpoint region
-    /// - current segment index
+    /// - meta info of checkpoint region (seq, nonce, MAC and length of fields)
+    /// - [encrypted] copy of meta info (without nonce and MAC)
+    /// - [encrypted] current segment index
+    /// - next index block HBA
+    /// - journal head
     /// - data SVT
     /// - index SVT
-    /// - DST vector (last_modify + len + BitMap)
+    /// - DST vector (last_modify + tail + len + BitMap + RIT)
     /// - BITCategory
     pub fn write_to_disk(
-        &self,
+        &mut self,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        root_key: &[u8],
         bdev: &BlockDevice,
         client: &DmIoClient,
         checkpoint_hba: u64,
+        slot_sectors: u64,
     ) -> Result {
         let current_data_segment =
             unsafe { mem::transmute::<usize, [u8; 8]>(self.current_data_segment) };
+        let index_hba = unsafe { mem::transmute::<u64, [u8; 8]>(self.index_hba) };
+        let journal_head = self.journal_head.serialize()?;
         let data_svt = self.data_svt.serialize()?;
         let index_svt = self.index_svt.serialize()?;
         let bit_category = self.bit_category.serialize()?;
@@ -78,19 +112,34 @@ impl Checkpoint {
             dst_vec.try_extend_from_slice(&item.serialize()?)?;
         }
 
-        let mut vec = Vec::new();
-        vec.try_extend_from_slice(&current_data_segment)?;
-        vec.try_extend_from_slice(&data_svt)?;
-        vec.try_extend_from_slice(&index_svt)?;
-        vec.try_extend_from_slice(&dst_vec)?;
-        vec.try_extend_from_slice(&bit_category)?;
+        let mut fields = Vec::new();
+        fields.try_extend_from_slice(&current_data_segment)?;
+        fields.try_extend_from_slice(&index_hba)?;
+        fields.try_extend_from_slice(&journal_head)?;
+        fields.try_extend_from_slice(&data_svt)?;
+        fields.try_extend_from_slice(&index_svt)?;
+        fields.try_extend_from_slice(&dst_vec)?;
+        fields.try_extend_from_slice(&bit_category)?;
 
-        let len = vec.len();
+        let len = CHECKPOINT_HELPER_SIZE + fields.len();
         let should_extend = !(len % SECTOR_SIZE as usize == 0) as usize;
-        let sector_count = vec.len() / (SECTOR_SIZE as usize) + should_extend;
-        vec.try_resize(sector_count * SECTOR_SIZE as usize, 0)?;
+        let sector_count = len / (SECTOR_SIZE as usize) + should_extend;
+
+        if sector_count as u64 + 1 > slot_sectors {
+            pr_err!(
+                "checkpoint of {} sectors exceeds the checkpoint slot",
+                sector_count
+            );
+            return Err(ENOSPC);
+        }
 
-        let meta = CheckpointHelper {
+        let seq = self.seq + 1;
+        let checkpoint_hba = checkpoint_hba + (seq % 2) * slot_sectors;
+
+        let mut meta = CheckpointHelper {
+            seq,
+            nonce: [0; SWORNDISK_NONCE_LENGTH],
+            mac: [0; SWORNDISK_MAC_LENGTH],
             data_svt_len: data_svt.len(),
             index_svt_len: index_svt.len(),
             dst_size: self.dst.len(),
@@ -98,6 +147,23 @@ impl Checkpoint {
             bit_category_len: bit_category.len(),
             sector_number: sector_count,
         };
+
+        let mut vec = meta.serialize()?;
+        vec.try_extend_from_slice(&fields)?;
+        vec.try_resize(sector_count * SECTOR_SIZE as usize, 0)?;
+        let digest = kdf.digest(&vec)?;
+
+        // encrypt the checkpoint body with root key
+        let key = Secret::from_slice(root_key)?;
+        let mut nonce = get_random_bytes(SWORNDISK_NONCE_LENGTH)?;
+        let body_len = vec.len();
+        // SAFETY: Safe. `vec` is a local buffer and there is no concurrent access.
+        let mac = unsafe {
+            aead.as_ref()
+                .encrypt_in_place(&key, &mut nonce, &mut vec[..], body_len)?
+        };
+        meta.nonce = vec_to_slice::<{ SWORNDISK_NONCE_LENGTH }>(&nonce)?;
+        meta.mac = vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)?;
         let mut meta_vec = meta.serialize()?;
 
         pr_info!("checkpoint meta: {:?}", meta);
@@ -108,35 +174,99 @@ impl Checkpoint {
             checkpoint_hba
         );
 
-        // write checkpoint meta info in a single sector
-        let mut region = DmIoRegion::new(&bdev, checkpoint_hba, 1)?;
+        let mut region = DmIoRegion::new(&bdev, checkpoint_hba + 1, sector_count as u64)?;
         let mut io_req = DmIoRequest::with_kernel_memory(
             WRITE as i32,
             WRITE as i32,
-            meta_vec.as_mut_ptr() as *mut c_void,
+            vec.as_mut_ptr() as *mut c_void,
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
 
-        // TODO: encryption
-        let mut region = DmIoRegion::new(&bdev, checkpoint_hba + 1, sector_count as u64)?;
+        // write checkpoint meta info in a single sector after the body, so the slot
+        // is valid only when the whole checkpoint is written
+        meta_vec.try_resize(SECTOR_SIZE as usize, 0u8)?;
+        let mut region = DmIoRegion::new(&bdev, checkpoint_hba, 1)?;
         let mut io_req = DmIoRequest::with_kernel_memory(
             WRITE as i32,
             WRITE as i32,
-            vec.as_mut_ptr() as *mut c_void,
+            meta_vec.as_mut_ptr() as *mut c_void,
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
+
+        self.seq = seq;
+        self.digest = digest;
 
         Ok(())
     }
 
+    /// Read the newest valid checkpoint from the two checkpoint slots. A checkpoint
+    /// that fails the verification with `root_key` is rejected.
     pub fn read_from_disk(
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        root_key: &[u8],
         bdev: &BlockDevice,
         client: &DmIoClient,
         checkpoint_hba: u64,
+        slot_sectors: u64,
+    ) -> Result<Self> {
+        let mut newest: Option<Self> = None;
+
+        for slot in 0..2 {
+            let slot_hba = checkpoint_hba + slot * slot_sectors;
+            match Self::read_slot(aead, kdf, root_key, bdev, client, slot_hba, slot_sectors) {
+                Ok(checkpoint) => {
+                    pr_info!("checkpoint slot {}: valid, seq {}", slot, checkpoint.seq);
+                    if newest.is_none() || checkpoint.seq > newest.as_ref().unwrap().seq {
+                        newest = Some(checkpoint);
+                    }
+                }
+                Err(_) => pr_warn!("checkpoint slot {}: invalid", slot),
+            }
+        }
+
+        newest.ok_or(EINVAL)
+    }
+
+    /// Erase both checkpoint slots, so the checkpoints left by a previous format
+    /// will never be read.
+    pub fn erase_on_disk(
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+        checkpoint_hba: u64,
+        slot_sectors: u64,
+    ) -> Result {
+        let mut sector = Vec::new();
+        sector.try_resize(SECTOR_SIZE as usize, 0u8)?;
+
+        for slot in 0..2 {
+            let mut region = DmIoRegion::new(&bdev, checkpoint_hba + slot * slot_sectors, 1)?;
+            let mut io_req = DmIoRequest::with_kernel_memory(
+                WRITE as i32,
+                WRITE as i32,
+                sector.as_mut_ptr() as *mut c_void,
+                0,
+                client,
+            );
+            io_req.submit(&mut region)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the checkpoint in a slot, then decrypt and validate its integrity
+    fn read_slot(
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        root_key: &[u8],
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+        checkpoint_hba: u64,
+        slot_sectors: u64,
     ) -> Result<Self> {
         // read metainfo
         let mut meta_sector = Vec::new();
@@ -149,8 +279,12 @@ impl Checkpoint {
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
         let meta = CheckpointHelper::deserialize(&meta_sector[..CHECKPOINT_HELPER_SIZE])?;
+        if meta.seq == 0 || meta.sector_number == 0 || meta.sector_number as u64 + 1 > slot_sectors
+        {
+            return Err(EINVAL);
+        }
 
         // read checkpoint body
         let mut body = Vec::new();
@@ -163,14 +297,41 @@ impl Checkpoint {
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
+
+        // decrypt the checkpoint body and verify its MAC
+        let key = Secret::from_slice(root_key)?;
+        let mut nonce = slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&meta.nonce)?;
+        let mut mac = slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&meta.mac)?;
+        let body_len = body.len();
+        // SAFETY: Safe. `body` is a local buffer and there is no concurrent access.
+        unsafe {
+            aead.as_ref()
+                .decrypt_in_place(&key, &mut mac, &mut nonce, &mut body[..], body_len)?
+        };
+
+        // the plain meta info should be the same as the authenticated copy
+        let mut plain_meta = meta.clone();
+        plain_meta.nonce = [0; SWORNDISK_NONCE_LENGTH];
+        plain_meta.mac = [0; SWORNDISK_MAC_LENGTH];
+        if plain_meta.serialize()?[..] != body[..CHECKPOINT_HELPER_SIZE] {
+            return Err(EBADMSG);
+        }
+        let digest = kdf.digest(&body)?;
 
         // read fields
-        let mut index = 0;
+        let mut index = CHECKPOINT_HELPER_SIZE;
         let current_data_segment =
             unsafe { mem::transmute::<[u8; 8], usize>(body[index..index + 8].try_into().unwrap()) };
         index += 8;
 
+        let index_hba =
+            unsafe { mem::transmute::<[u8; 8], u64>(body[index..index + 8].try_into().unwrap()) };
+        index += 8;
+
+        let journal_head = JournalHead::deserialize(&body[index..index + JOURNAL_HEAD_SIZE])?;
+        index += JOURNAL_HEAD_SIZE;
+
         let data_svt = SVT::deserialize(&body[index..index + meta.data_svt_len])?;
         index += meta.data_svt_len;
 
@@ -181,9 +342,13 @@ impl Checkpoint {
         let mut dst = Vec::new();
         for _ in 0..meta.dst_size {
             let bvm_len = unsafe {
-                mem::transmute::<[u8; 8], usize>(body[index + 8..index + 16].try_into().unwrap())
+                mem::transmute::<[u8; 8], usize>(
+                    body[index + DST_HEADER_SIZE - 8..index + DST_HEADER_SIZE]
+                        .try_into()
+                        .unwrap(),
+                )
             };
-            let total_len = 16 + bvm_len;
+            let total_len = DST_HEADER_SIZE + bvm_len + DST_RIT_SIZE + DST_VERSION_SIZE;
             let item = DST::deserialize(&body[index..index + total_len])?;
             dst.try_push(item)?;
             index += total_len;
@@ -199,9 +364,108 @@ impl Checkpoint {
             dst,
             current_data_segment,
             bit_category,
+            journal_head,
+            index_hba,
+            digest,
+            seq: meta.seq,
         })
     }
 
+    /// Root hash of the disk state recovered from this checkpoint and the journal after it
+    /// up to `head`, which is the keyed digest of the checkpoint digest, and the sequence
+    /// number and MAC of the last journal block. So a journal tail that is replayed, or
+    /// missing, changes the root hash as well.
+    pub fn root_hash(&self, kdf: &KeyDeriver, head: &JournalHead) -> Result<HashType> {
+        let mut data = Vec::try_with_capacity(SWORNDISK_HASH_LENGTH + 8 + SWORNDISK_MAC_LENGTH)?;
+        data.try_extend_from_slice(&self.digest)?;
+        data.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(head.seq) })?;
+        data.try_extend_from_slice(&head.prev_mac)?;
+
+        kdf.digest(&data)
+    }
+
+    /// Mark the data block at `hba` as invalid, because the record of `lba` with `version`
+    /// pointing to it has been superseded by a newer one.
+    ///
+    /// The block is left untouched if it no longer belongs to that record, which happens when
+    /// its segment has been cleaned and reused since the stale record was written. The LBA
+    /// alone can not tell it, since the reused block may hold a newer write of the same LBA.
+    /// Nothing is done for a tombstone, which points to no block.
+    pub fn invalidate_block(&mut self, hba: u64, lba: u64, version: u64) -> Result {
+        if hba == TOMBSTONE_HBA {
+            return Ok(());
+        }
+
+        let segment = (hba / SEGMENT_SECTORS) as usize;
+        let index = ((hba % SEGMENT_SECTORS) / BLOCK_SECTORS) as usize;
+
+        if segment >= self.dst.len() {
+            return Err(EINVAL);
+        }
+
+        let dst = &mut self.dst[segment];
+        if dst.lba(index) != lba || dst.version(index) != version {
+            return Ok(());
+        }
+
+        dst.clear_block(index)
+    }
+
+    /// Invalidate the blocks of an extent, see `invalidate_block`
+    pub fn invalidate_extent(&mut self, extent: &Extent) -> Result {
+        if extent.is_tombstone() {
+            return Ok(());
+        }
+
+        for i in 0..extent.len {
+            self.invalidate_block(
+                extent.hba + i * BLOCK_SECTORS,
+                extent.lba + i,
+                extent.version,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Pick a victim data segment for segment cleaning.
+    ///
+    /// The allocated segment with the least valid blocks is chosen (greedy policy), and
+    /// the older one is preferred if there is a tie. The current logging head, the fully
+    /// valid segments and the segments in `excluded` are never chosen. Returns None if
+    /// no segment can be cleaned.
+    pub fn pick_victim(&self, excluded: &[usize]) -> Result<Option<usize>> {
+        let mut victim: Option<usize> = None;
+
+        for index in 0..self.dst.len() {
+            if index == self.current_data_segment
+                || excluded.contains(&index)
+                || !self.data_svt.is_allocated(index)?
+                || self.dst[index].is_full()
+            {
+                continue;
+            }
+
+            let dst = &self.dst[index];
+            victim = match victim {
+                Some(current) => {
+                    let current_dst = &self.dst[current];
+                    if dst.valid_blocks() < current_dst.valid_blocks()
+                        || (dst.valid_blocks() == current_dst.valid_blocks()
+                            && dst.last_modify() < current_dst.last_modify())
+                    {
+                        Some(index)
+                    } else {
+                        Some(current)
+                    }
+                }
+                None => Some(index),
+            };
+        }
+
+        Ok(victim)
+    }
+
     pub fn debug(&self) {
         pr_info!("current segment: {}", self.current_data_segment);
         pr_info!("data_svt len: {}", self.data_svt.len());
@@ -211,8 +475,11 @@ impl Checkpoint {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct CheckpointHelper {
+    seq: u64,
+    nonce: NonceType,
+    mac: MacType,
     data_svt_len: usize,
     index_svt_len: usize,
     dst_size: usize,
@@ -226,6 +493,9 @@ const CHECKPOINT_HELPER_SIZE: usize = mem::size_of::<CheckpointHelper>();
 impl Serialize for CheckpointHelper {
     fn serialize(&self) -> Result<Vec<u8>> {
         let mut vec = Vec::new();
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.seq) })?;
+        vec.try_extend_from_slice(&self.nonce)?;
+        vec.try_extend_from_slice(&self.mac)?;
         vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.data_svt_len) })?;
         vec.try_extend_from_slice(&unsafe {
             mem::transmute::<usize, [u8; 8]>(self.index_svt_len)
@@ -238,7 +508,6 @@ impl Serialize for CheckpointHelper {
         vec.try_extend_from_slice(&unsafe {
             mem::transmute::<usize, [u8; 8]>(self.sector_number)
         })?;
-        // todo: checksum
         vec.try_resize(CHECKPOINT_HELPER_SIZE, 0u8)?;
         Ok(vec)
     }
@@ -250,18 +519,33 @@ impl Deserialize for CheckpointHelper {
             return Err(EINVAL);
         }
 
-        let data_svt_len =
-            unsafe { mem::transmute::<[u8; 8], usize>(buf[0..8].try_into().unwrap()) };
-        let index_svt_len =
-            unsafe { mem::transmute::<[u8; 8], usize>(buf[8..16].try_into().unwrap()) };
-        let dst_size = unsafe { mem::transmute::<[u8; 8], usize>(buf[16..24].try_into().unwrap()) };
-        let dst_len = unsafe { mem::transmute::<[u8; 8], usize>(buf[24..32].try_into().unwrap()) };
-        let bit_category_len =
-            unsafe { mem::transmute::<[u8; 8], usize>(buf[32..40].try_into().unwrap()) };
-        let sector_number =
-            unsafe { mem::transmute::<[u8; 8], usize>(buf[40..48].try_into().unwrap()) };
+        let seq = unsafe { mem::transmute::<[u8; 8], u64>(buf[0..8].try_into().unwrap()) };
+        let mut index = 8;
+        let nonce = buf[index..index + SWORNDISK_NONCE_LENGTH]
+            .try_into()
+            .unwrap();
+        index += SWORNDISK_NONCE_LENGTH;
+        let mac = buf[index..index + SWORNDISK_MAC_LENGTH].try_into().unwrap();
+        index += SWORNDISK_MAC_LENGTH;
+
+        let mut read_usize = || {
+            let value = unsafe {
+                mem::transmute::<[u8; 8], usize>(buf[index..index + 8].try_into().unwrap())
+            };
+            index += 8;
+            value
+        };
+        let data_svt_len = read_usize();
+        let index_svt_len = read_usize();
+        let dst_size = read_usize();
+        let dst_len = read_usize();
+        let bit_category_len = read_usize();
+        let sector_number = read_usize();
 
         Ok(Self {
+            seq,
+            nonce,
+            mac,
             data_svt_len,
             index_svt_len,
             dst_size,
@@ -21,12 +21,23 @@ impl SVT {
         self.0.len()
     }
 
+    /// Get the number of free segments
+    pub fn available(&self) -> usize {
+        self.0.available()
+    }
+
+    /// Check the segment at `index` is allocated
+    pub fn is_allocated(&self, index: usize) -> Result<bool> {
+        self.0.get_bit(index)
+    }
+
     /// Alloc a new segment and mark its index as used in BitMap.
     /// Returns the index (HBA) of the segment.
     pub fn alloc(&mut self) -> Result<usize> {
-        // No more free segment to alloc, returns ENOSPC
+        // No more free segment to alloc, returns ENOSPC. The segment cleaner is
+        // triggered before the free segments run out, so reaching here means that
+        // the disk is really full of valid blocks.
         if self.0.is_full() {
-            // TODO: segment cleaning @kirainmoe
             return Err(ENOSPC);
         }
 
@@ -35,6 +46,11 @@ impl SVT {
         Ok(index)
     }
 
+    /// Mark the segment at `index` as used, this is used in recovery.
+    pub fn alloc_at(&mut self, index: usize) -> Result {
+        self.0.set_bit(index)
+    }
+
     /// Release a segment by index (HBA).
     pub fn release(&mut self, index: u64) -> Result {
         self.0.clear_bit(index as usize)?;
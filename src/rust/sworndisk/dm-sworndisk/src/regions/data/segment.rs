@@ -1,11 +1,11 @@
 use crate::{
+    cipher::DataMode,
+    kdf::{KeyDeriver, KeyRegion},
     prelude::*,
-    regions::{checkpoint::Checkpoint, MemTable, Record},
-    utils::vec_to_slice,
+    regions::{checkpoint::Checkpoint, Extent, Journal, JournalRecord, MemTable, Record},
+    utils::{slice_to_vec, vec_to_slice, TryClone},
 };
 
-use crypto::get_random_bytes;
-
 use kernel::rbtree::RBTree;
 
 /// SwornDisk Data Segment
@@ -17,11 +17,16 @@ pub struct DataSegment {
     /// Note that in Linux device mapper, we use "sector address" rather than
     /// "block address" to represent the `hba` field.
     pub hba: u64,
-    /// Used blocks of current buffer
+    /// Used blocks of current buffer, which are not written to disk yet
     pub used: u64,
+    /// Number of blocks of current segment that have been written to disk. The buffered
+    /// blocks are appended after them.
+    pub flushed: usize,
     /// Map the logical block address (LBA) to the buffer position. This is essential
     /// for fragment write request.
     pub lba_index_map: RBTree<u64, usize>,
+    /// How the blocks are sealed on writeback
+    pub mode: DataMode,
 }
 
 impl Debug for DataSegment {
@@ -29,14 +34,17 @@ impl Debug for DataSegment {
         f.debug_struct("DataSegment")
             .field("hba", &self.hba)
             .field("used", &self.used)
+            .field("flushed", &self.flushed)
+            .field("mode", &self.mode)
             .field("buffer", &self.buffer)
             .finish()
     }
 }
 
 impl DataSegment {
-    /// Create a new data segment
-    pub fn new(hba: u64) -> Result<Self> {
+    /// Create a new data segment, whose first `flushed` blocks have been written to disk.
+    /// The blocks are sealed in `mode`.
+    pub fn new(hba: u64, flushed: usize, mode: DataMode) -> Result<Self> {
         let mut buffer = Vec::try_with_capacity(SEGMENT_SIZE as usize)?;
         buffer.try_resize(SEGMENT_SIZE as usize, 0u8)?;
 
@@ -47,9 +55,31 @@ impl DataSegment {
             hba,
             lba_index_map,
             used: 0,
+            flushed,
+            mode,
         })
     }
 
+    /// Check the LBA is buffered in current data segment
+    pub fn contains(&self, lba: u64) -> bool {
+        self.lba_index_map.get(&lba).is_some()
+    }
+
+    /// Drop the buffered block of `lba` if any, its slot in the segment is invalid since then
+    pub fn discard(&mut self, lba: u64, checkpoint: &mut Checkpoint) -> Result<bool> {
+        let block_buf_index = match self.lba_index_map.remove(&lba) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        // the slot is still written with the other buffered blocks, as zeros
+        self.buffer[block_buf_index..block_buf_index + BLOCK_SIZE as usize].fill(0);
+        checkpoint.dst[checkpoint.current_data_segment]
+            .clear_block(block_buf_index / BLOCK_SIZE as usize)?;
+
+        Ok(true)
+    }
+
     pub fn read(&self, lba: u64, data: &mut [u8], offset: usize, len: usize) -> Option<usize> {
         if let Some(block_buf_index) = self.lba_index_map.get(&lba) {
             let buf_begin = *block_buf_index + offset;
@@ -70,10 +100,13 @@ impl DataSegment {
         offset: usize,
         len: usize,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         checkpoint: &mut Checkpoint,
         client: &DmIoClient,
         memtable: &mut MemTable,
+        journal: &mut Journal,
         bdev: &BlockDevice,
+        meta_bdev: &BlockDevice,
     ) -> Result<(usize, u64)> {
         // First, we check the requested LBA is in current data segment now. If the requested LBA
         // has already taken a block in the current segment buffer, we can update the block in-place
@@ -94,7 +127,7 @@ impl DataSegment {
             let current_data_segment = checkpoint.current_data_segment;
 
             // Check current data segment has enough space to contain a block.
-            if let Ok(block_index) = checkpoint.dst[current_data_segment].alloc_block() {
+            if let Ok(block_index) = checkpoint.dst[current_data_segment].alloc_block(lba) {
                 // We calculate the range of current allocated block, and put the data in the proper area,
                 // then update the LBA index and used counter.
                 let hba = self.hba + (block_index as u64) * BLOCK_SECTORS;
@@ -116,15 +149,20 @@ impl DataSegment {
                     &mut self.lba_index_map,
                     &mut self.hba,
                     &mut self.used,
+                    &mut self.flushed,
+                    self.mode,
                     aead,
+                    kdf,
                     checkpoint,
                     client,
                     memtable,
+                    journal,
                     bdev,
+                    meta_bdev,
                 )?;
 
                 let current_data_segment = checkpoint.current_data_segment;
-                let block_index = checkpoint.dst[current_data_segment].alloc_block()?;
+                let block_index = checkpoint.dst[current_data_segment].alloc_block(lba)?;
                 let hba = self.hba + (block_index as u64) * BLOCK_SECTORS;
                 let block_buf_index = block_index * BLOCK_SIZE as usize;
                 let buf_begin = block_buf_index + offset;
@@ -143,91 +181,168 @@ impl DataSegment {
     pub fn flush(
         &mut self,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         checkpoint: &mut Checkpoint,
         client: &DmIoClient,
         memtable: &mut MemTable,
+        journal: &mut Journal,
         bdev: &BlockDevice,
+        meta_bdev: &BlockDevice,
     ) -> Result {
         Self::do_flush(
             &mut self.buffer,
             &mut self.lba_index_map,
             &mut self.hba,
             &mut self.used,
+            &mut self.flushed,
+            self.mode,
             aead,
+            kdf,
             checkpoint,
             client,
             memtable,
+            journal,
             bdev,
+            meta_bdev,
         )
     }
 
-    /// write current data segment into disk, and allocate a new segment
+    /// Write the buffered blocks of current data segment into disk, only the blocks after
+    /// the flushed ones are written. A new segment is allocated if current one is exhausted,
+    /// otherwise the logging head keeps appending to it.
     fn do_flush(
         buffer: &mut Vec<u8>,
         lba_index_map: &mut RBTree<u64, usize>,
         hba: &mut u64,
         used: &mut u64,
+        flushed: &mut usize,
+        mode: DataMode,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         checkpoint: &mut Checkpoint,
         client: &DmIoClient,
         memtable: &mut MemTable,
+        journal: &mut Journal,
         bdev: &BlockDevice,
+        meta_bdev: &BlockDevice,
     ) -> Result {
-        // generate random (key, nonce) and encrypt the data
-        for (lba, index) in lba_index_map.iter() {
-            let buf_begin = *index;
-            let buf_end = buf_begin + BLOCK_SIZE as usize;
-
-            let key = get_random_bytes(SWORNDISK_KEY_LENGTH)?;
-            let mut nonce = get_random_bytes(SWORNDISK_NONCE_LENGTH)?;
-
-            // SAFETY: Encrypt the data in-place. There is no concurrent access
-            // of `self.buffer` so this is safe.
-            let mac = unsafe {
-                aead.as_ref().encrypt_in_place(
+        if *used > 0 {
+            let block_size = BLOCK_SIZE as usize;
+            let begin = *flushed * block_size;
+            let end = begin + *used as usize * block_size;
+
+            // seal into a copy of the buffered blocks, so the plain data is kept for
+            // a retry if the segment fails to be written
+            let mut cipher = Vec::try_with_capacity(end - begin)?;
+            cipher.try_extend_from_slice(&buffer[begin..end])?;
+
+            // The blocks whose LBAs and HBAs both follow the previous one are grouped into
+            // an extent, which has a fresh key (see `KeyDeriver`) and a random nonce. Then
+            // seal the data, which is encrypted unless in `DataMode::Integrity`.
+            let version = checkpoint.seq + 1;
+            let mut extents: Vec<Extent> = Vec::new();
+            for (lba, index) in lba_index_map.iter() {
+                let buf_begin = *index - begin;
+                let buf_end = buf_begin + block_size;
+                let block_hba = *hba + *index as u64 / BLOCK_SIZE * BLOCK_SECTORS;
+
+                let follows = match extents.last() {
+                    Some(extent) => {
+                        extent.end() == *lba
+                            && extent.hba + extent.len * BLOCK_SECTORS == block_hba
+                            && *lba % EXTENT_MAX_BLOCKS != 0
+                    }
+                    None => false,
+                };
+                if !follows {
+                    extents.try_push(Extent::new(*lba, block_hba, kdf.fresh_key()?, version)?)?;
+                }
+                let extent = extents.last_mut().unwrap();
+
+                let key = kdf.block_key(KeyRegion::Data, &extent.key, block_hba, version)?;
+                let mut nonce =
+                    slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&extent.nonce_of(extent.len))?;
+                let mut assoc = Record::build_associated_data(*lba, block_hba, version)?;
+
+                let mac = mode.seal(
+                    aead,
                     &key,
                     &mut nonce,
-                    &mut buffer[buf_begin..buf_end],
-                    BLOCK_SIZE as usize,
-                )?
-            };
-
-            let record = Record {
-                hba: *hba + *index as u64 / BLOCK_SIZE * BLOCK_SECTORS,
-                key: vec_to_slice::<{ SWORNDISK_KEY_LENGTH }>(&key)?,
-                nonce: vec_to_slice::<{ SWORNDISK_NONCE_LENGTH }>(&nonce)?,
-                mac: vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)?,
-            };
-
-            memtable.insert(*lba, record)?;
-        }
+                    &mut assoc[..],
+                    &mut cipher[buf_begin..buf_end],
+                )?;
 
-        // writeback
-        let mut region = DmIoRegion::new(&bdev, *hba, SEGMENT_SECTORS)?;
-        let mut io_req = DmIoRequest::with_kernel_memory(
-            WRITE as i32, // req_op
-            WRITE as i32, // req_op_flags
-            buffer.as_mut_ptr() as *mut c_void,
-            0, // offset
-            client,
-        );
+                extent.push_block(vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)?)?;
+            }
+
+            // writeback the buffered blocks only
+            let sector = *hba + *flushed as u64 * BLOCK_SECTORS;
+            let mut region = DmIoRegion::new(&bdev, sector, *used * BLOCK_SECTORS)?;
+            let mut io_req = DmIoRequest::with_kernel_memory(
+                WRITE as i32, // req_op
+                WRITE as i32, // req_op_flags
+                cipher.as_mut_ptr() as *mut c_void,
+                0, // offset
+                client,
+            );
+
+            // a failed segment must not be indexed, the buffered data stays as it is
+            io_req.submit(&mut region).map_err(|err| {
+                pr_warn!(
+                    "Failed to write data segment at sector {}: {:?}",
+                    sector,
+                    err
+                );
+                err
+            })?;
+
+            // the data logs must never be durable before the blocks they index, so the
+            // volatile write cache of data_dev is flushed before they are appended
+            let mut region = DmIoRegion::new(&bdev, 0, 0)?;
+            DmIoRequest::with_preflush(client).submit(&mut region)?;
 
-        io_req.submit(&mut region);
+            for extent in extents.into_iter() {
+                // the blocks are owned by the records of this version since then
+                let dst = &mut checkpoint.dst[(extent.hba / SEGMENT_SECTORS) as usize];
+                let index = ((extent.hba % SEGMENT_SECTORS) / BLOCK_SECTORS) as usize;
+                for i in 0..extent.len as usize {
+                    dst.set_version(index + i, extent.version);
+                }
 
-        // allocate a new LBA->index map
-        let mut new_map = RBTree::new();
-        core::mem::swap(lba_index_map, &mut new_map);
+                // the blocks superseded by the new extent are invalid now
+                for old_extent in memtable.insert(extent.try_clone()?)?.iter() {
+                    checkpoint.invalidate_extent(old_extent)?;
+                }
 
-        // allocate a new buffer and replace current buffer
-        let mut new_buffer = Vec::try_with_capacity(SEGMENT_SIZE as usize)?;
-        new_buffer.try_resize(SEGMENT_SIZE as usize, 0u8)?;
-        core::mem::swap(buffer, &mut new_buffer);
-        *used = 0;
+                journal.append(&JournalRecord::DataLog { extent }, aead, meta_bdev, client)?;
+            }
+
+            // the data logs are valid only after the segment is written
+            let segment = *hba / SEGMENT_SECTORS;
+            journal.append(
+                &JournalRecord::DataSegmentCommit { segment },
+                aead,
+                meta_bdev,
+                client,
+            )?;
+            journal.commit(aead, meta_bdev, client)?;
+
+            // the written blocks are read from disk since then
+            *lba_index_map = RBTree::new();
+            *flushed += *used as usize;
+            *used = 0;
+        }
+
+        if !checkpoint.dst[checkpoint.current_data_segment].is_exhausted() {
+            return Ok(());
+        }
 
         // allocate new data segment
         let current_data_segment = checkpoint.data_svt.alloc()?;
         checkpoint.current_data_segment = current_data_segment;
-        *hba = (1 + current_data_segment as u64) * SEGMENT_SECTORS;
+        *hba = current_data_segment as u64 * SEGMENT_SECTORS;
+        *flushed = 0;
+        buffer.fill(0);
 
         Ok(())
     }
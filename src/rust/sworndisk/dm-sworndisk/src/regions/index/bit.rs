@@ -1,5 +1,11 @@
-use super::{memtable::MemTable, record::Record, segment::IndexSegment};
-use crate::{prelude::*, regions::Checkpoint, utils::*};
+use super::{
+    bloom::{BloomFilter, BloomFilterMeta},
+    extent::Extent,
+    memtable::MemTable,
+    record::{Record, RecordLayout},
+    segment::IndexSegment,
+};
+use crate::{kdf::KeyDeriver, prelude::*, regions::Checkpoint, utils::*};
 use core::marker::PhantomData;
 
 /// # SwornDisk Linux Rust: BIT Implementation Design
@@ -19,24 +25,25 @@ use core::marker::PhantomData;
 ///               v
 ///            LeafBlock
 ///            /         \
-///          Record     Record
-
-#[derive(Copy, Clone, Debug)]
-/// Leaf node of BIT
-pub struct LeafRecord {
-    /// logical block address of current record
-    pub lba: u64,
-    /// hba & key & nonce & mac
-    pub record: Record,
-}
+///          Extent     Extent
+///
+/// A LeafBlock holds as many extents as its space allows, an extent with more MACs than
+/// the rest space of a LeafBlock is split into the next one.
+///
+/// The records in a block take the `RecordLayout` of the key scheme, which is kept in
+/// the header of the block along with the number of children.
 
 #[derive(Debug)]
 /// On-disk unit of leaf node
 pub struct LeafBlock {
-    /// number of LeafRecord
+    /// number of Extent
     pub count: usize,
     /// children vector
-    pub children: Vec<LeafRecord>,
+    pub children: Vec<Extent>,
+    /// bytes taken by the serialized children
+    pub used: usize,
+    /// layout of the serialized children
+    pub layout: RecordLayout,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -55,25 +62,14 @@ pub struct IndirectBlock {
     pub count: usize,
     /// children vector
     pub children: Vec<IndirectRecord>,
+    /// layout of the serialized children
+    pub layout: RecordLayout,
 }
 
-/// Size of struct LeafRecord
-pub const LEAF_RECORD_SIZE: usize = mem::size_of::<LeafRecord>();
-/// Size of struct IndirectRecord
+/// Size of struct IndirectRecord, which is the size in checkpoint
 pub const INDIRECT_RECORD_SIZE: usize = mem::size_of::<IndirectRecord>();
-/// Size of struct LeafBlock
-pub const LEAF_BLOCK_CHILDREN: usize = (BLOCK_SIZE as usize - 8) / LEAF_RECORD_SIZE; // 8: count(u64)
-/// Size of struct IndirectBlock
-pub const INDIRECT_BLOCK_CHILDREN: usize = (BLOCK_SIZE as usize - 8) / INDIRECT_RECORD_SIZE; // 8: count(u64)
-
-impl Default for LeafRecord {
-    fn default() -> Self {
-        Self {
-            lba: u64::MAX,
-            record: Record::default(),
-        }
-    }
-}
+/// Bytes of extents a LeafBlock can hold
+pub const LEAF_BLOCK_CAPACITY: usize = BLOCK_SIZE as usize - 8; // 8: count(u32) and key_len(u32)
 
 impl Default for IndirectRecord {
     fn default() -> Self {
@@ -86,75 +82,69 @@ impl Default for IndirectRecord {
 
 impl Default for LeafBlock {
     fn default() -> Self {
-        Self {
-            count: 0,
-            children: Vec::new(),
-        }
+        Self::new(RecordLayout::default())
     }
 }
 
 impl Default for IndirectBlock {
     fn default() -> Self {
-        Self {
-            count: 0,
-            children: Vec::new(),
-        }
+        Self::new(RecordLayout::default())
     }
 }
 
-impl Clone for LeafBlock {
-    fn clone(&self) -> Self {
-        let mut children = Vec::new();
-        children.try_extend_from_slice(&self.children).unwrap();
-        Self {
+impl TryClone for LeafBlock {
+    fn try_clone(&self) -> Result<Self> {
+        let mut children = Vec::try_with_capacity(self.children.len())?;
+        for child in self.children.iter() {
+            children.try_push(child.try_clone()?)?;
+        }
+        Ok(Self {
             children,
             count: self.count,
-        }
+            used: self.used,
+            layout: self.layout,
+        })
     }
 }
 
-impl Clone for IndirectBlock {
-    fn clone(&self) -> Self {
+impl TryClone for IndirectBlock {
+    fn try_clone(&self) -> Result<Self> {
         let mut children = Vec::new();
-        children.try_extend_from_slice(&self.children).unwrap();
-        Self {
+        children.try_extend_from_slice(&self.children)?;
+        Ok(Self {
             children,
             count: self.count,
-        }
+            layout: self.layout,
+        })
     }
 }
 
-impl Serialize for LeafRecord {
-    fn serialize(&self) -> Result<Vec<u8>> {
-        let mut vec = Vec::new();
-        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.lba) })?;
-        vec.try_extend_from_slice(&self.record.serialize()?)?;
+/// Serialize the header of an index block: the number of children and the key length
+/// of the layout
+fn serialize_block_header(count: usize, layout: RecordLayout) -> Result<Vec<u8>> {
+    let mut vec = Vec::try_with_capacity(BLOCK_SIZE as usize)?;
+    vec.try_extend_from_slice(&unsafe { mem::transmute::<u32, [u8; 4]>(count as u32) })?;
+    vec.try_extend_from_slice(&unsafe { mem::transmute::<u32, [u8; 4]>(layout.key_len as u32) })?;
 
-        assert_eq!(vec.len() <= LEAF_RECORD_SIZE, true);
-        vec.try_resize(LEAF_RECORD_SIZE, 0u8)?;
-        Ok(vec)
-    }
+    Ok(vec)
 }
 
-impl Deserialize for LeafRecord {
-    fn deserialize(buffer: &[u8]) -> Result<Self> {
-        if buffer.len() != LEAF_RECORD_SIZE {
-            return Err(EINVAL);
-        }
+/// Deserialize the header of an index block, see `serialize_block_header`
+fn deserialize_block_header(buffer: &[u8]) -> Result<(usize, RecordLayout)> {
+    let count = unsafe { mem::transmute::<[u8; 4], u32>(buffer[0..4].try_into().unwrap()) };
+    let key_len = unsafe { mem::transmute::<[u8; 4], u32>(buffer[4..8].try_into().unwrap()) };
 
-        let lba = unsafe { mem::transmute::<[u8; 8], u64>(buffer[0..8].try_into().unwrap()) };
-        let record = Record::deserialize(&buffer[8..8 + SWORNDISK_RECORD_SIZE])?;
-
-        Ok(LeafRecord { lba, record })
-    }
+    Ok((
+        count as usize,
+        RecordLayout::from_key_len(key_len as usize)?,
+    ))
 }
 
 impl Serialize for LeafBlock {
     fn serialize(&self) -> Result<Vec<u8>> {
-        let mut vec = Vec::new();
-        vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.count) })?;
+        let mut vec = serialize_block_header(self.count, self.layout)?;
         for item in self.children.iter() {
-            vec.try_extend_from_slice(&item.serialize()?)?;
+            vec.try_extend_from_slice(&item.serialize_with(self.layout)?)?;
         }
 
         assert_eq!(vec.len() <= BLOCK_SIZE as usize, true);
@@ -169,16 +159,45 @@ impl Deserialize for LeafBlock {
             return Err(EINVAL);
         }
 
-        let count = unsafe { mem::transmute::<[u8; 8], usize>(buffer[0..8].try_into().unwrap()) };
+        let (count, layout) = deserialize_block_header(buffer)?;
         let mut children = Vec::new();
-        for i in 0..LEAF_BLOCK_CHILDREN {
-            let index = 8 + i * LEAF_RECORD_SIZE;
-            children.try_push(LeafRecord::deserialize(
-                &buffer[index..index + LEAF_RECORD_SIZE],
-            )?)?;
+        let mut index = 8;
+        for _ in 0..count {
+            let (extent, len) = Extent::deserialize_from(&buffer[index..], layout)?;
+            children.try_push(extent)?;
+            index += len;
         }
 
-        Ok(Self { count, children })
+        Ok(Self {
+            count,
+            children,
+            used: index - 8,
+            layout,
+        })
+    }
+}
+
+impl IndirectRecord {
+    /// Serialize the IndirectRecord in `layout`
+    pub fn serialize_with(&self, layout: RecordLayout) -> Result<Vec<u8>> {
+        let mut vec = Vec::try_with_capacity(layout.indirect_record_size())?;
+        vec.try_extend_from_slice(&unsafe {
+            mem::transmute::<(u64, u64), [u8; 16]>(self.lba_range)
+        })?;
+        vec.try_extend_from_slice(&self.record.serialize_with(layout)?)?;
+
+        Ok(vec)
+    }
+
+    /// Deserialize an IndirectRecord in `layout`
+    pub fn deserialize_with(buffer: &[u8], layout: RecordLayout) -> Result<Self> {
+        if buffer.len() < layout.indirect_record_size() {
+            return Err(EINVAL);
+        }
+        let lba_range =
+            unsafe { mem::transmute::<[u8; 16], (u64, u64)>(buffer[0..16].try_into().unwrap()) };
+        let record = Record::deserialize_with(&buffer[16..], layout)?;
+        Ok(Self { lba_range, record })
     }
 }
 
@@ -210,10 +229,9 @@ impl Deserialize for IndirectRecord {
 
 impl Serialize for IndirectBlock {
     fn serialize(&self) -> Result<Vec<u8>> {
-        let mut vec = Vec::new();
-        vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(self.count) })?;
+        let mut vec = serialize_block_header(self.count, self.layout)?;
         for item in self.children.iter() {
-            vec.try_extend_from_slice(&item.serialize()?)?;
+            vec.try_extend_from_slice(&item.serialize_with(self.layout)?)?;
         }
 
         assert_eq!(vec.len() <= BLOCK_SIZE as usize, true);
@@ -227,43 +245,77 @@ impl Deserialize for IndirectBlock {
         if buffer.len() != BLOCK_SIZE as usize {
             return Err(EINVAL);
         }
-        let count = unsafe { mem::transmute::<[u8; 8], usize>(buffer[0..8].try_into().unwrap()) };
+        let (count, layout) = deserialize_block_header(buffer)?;
+        if count > layout.indirect_block_children() {
+            return Err(EINVAL);
+        }
+
+        let record_size = layout.indirect_record_size();
         let mut children = Vec::new();
-        for i in 0..INDIRECT_BLOCK_CHILDREN {
-            let index = 8 + i * INDIRECT_RECORD_SIZE;
-            children.try_push(IndirectRecord::deserialize(
-                &buffer[index..index + INDIRECT_RECORD_SIZE],
+        for i in 0..layout.indirect_block_children() {
+            let index = 8 + i * record_size;
+            children.try_push(IndirectRecord::deserialize_with(
+                &buffer[index..index + record_size],
+                layout,
             )?)?;
         }
-        Ok(Self { count, children })
+        Ok(Self {
+            count,
+            children,
+            layout,
+        })
     }
 }
 
 impl LeafBlock {
+    /// Create an empty LeafBlock whose extents take `layout`
+    pub fn new(layout: RecordLayout) -> Self {
+        Self {
+            count: 0,
+            children: Vec::new(),
+            used: 0,
+            layout,
+        }
+    }
+
     pub fn get_lba_range(&self) -> (u64, u64) {
         match self.count {
             0 => (u64::MAX, u64::MIN),
-            _ => (self.children[0].lba, self.children[self.count - 1].lba),
+            _ => (
+                self.children[0].lba,
+                self.children[self.count - 1].end() - 1,
+            ),
         }
     }
 
-    pub fn is_full(&self) -> bool {
-        self.count == LEAF_BLOCK_CHILDREN
+    /// Bytes left for more extents
+    pub fn room(&self) -> usize {
+        LEAF_BLOCK_CAPACITY - self.used
     }
 
-    pub fn push(&mut self, record: LeafRecord) {
+    pub fn push(&mut self, extent: Extent) {
         if self.count > 0 {
-            assert_eq!(self.children[self.count - 1].lba <= record.lba, true);
+            assert_eq!(self.children[self.count - 1].end() <= extent.lba, true);
         }
 
-        self.children.try_push(record).unwrap();
+        self.used += extent.disk_size(self.layout);
+        self.children.try_push(extent).unwrap();
         self.count += 1;
 
-        assert_eq!(self.count <= LEAF_BLOCK_CHILDREN, true);
+        assert_eq!(self.used <= LEAF_BLOCK_CAPACITY, true);
     }
 }
 
 impl IndirectBlock {
+    /// Create an empty IndirectBlock whose records take `layout`
+    pub fn new(layout: RecordLayout) -> Self {
+        Self {
+            count: 0,
+            children: Vec::new(),
+            layout,
+        }
+    }
+
     pub fn get_lba_range(&self) -> (u64, u64) {
         match self.count {
             0 => (u64::MAX, u64::MIN),
@@ -275,7 +327,7 @@ impl IndirectBlock {
     }
 
     pub fn is_full(&self) -> bool {
-        self.count == INDIRECT_BLOCK_CHILDREN
+        self.count == self.layout.indirect_block_children()
     }
 
     pub fn push(&mut self, record: IndirectRecord) {
@@ -290,7 +342,7 @@ impl IndirectBlock {
         self.children.try_push(record).unwrap();
         self.count += 1;
 
-        assert_eq!(self.count <= INDIRECT_BLOCK_CHILDREN, true);
+        assert_eq!(self.count <= self.layout.indirect_block_children(), true);
     }
 }
 
@@ -305,8 +357,17 @@ pub struct BIT {
     /// max level of BIT
     pub level: usize,
 
-    /// element number of BIT
+    /// extent number of BIT
     pub size: usize,
+
+    /// data blocks indexed by the extents of BIT
+    pub blocks: usize,
+
+    /// on-disk location of the Bloom filter
+    pub bloom: BloomFilterMeta,
+
+    /// Bloom filter of a new BIT, which is moved into `BITCategory` once the BIT is added
+    pub filter: Option<BloomFilter>,
 }
 
 impl BIT {
@@ -319,69 +380,81 @@ impl BIT {
         self.root.get_lba_range()
     }
 
+    /// Calculate the max level of a BIT of `size` extents and `blocks` data blocks in
+    /// `layout`, the max level should not exceeded the BIT_MAX_LEVEL
+    fn max_level(size: usize, blocks: usize, layout: RecordLayout) -> Result<usize> {
+        // Each LeafBlock but the last one is filled until it can not hold an extent header
+        // and a MAC, and an extent is split once at most for each LeafBlock. So the number
+        // of LeafBlocks is bounded by the bytes of headers and MACs.
+        let header_size = layout.extent_header_size();
+        let capacity = LEAF_BLOCK_CAPACITY - 2 * header_size - SWORNDISK_MAC_LENGTH;
+        let leaves = 2 + (size * header_size + blocks * SWORNDISK_MAC_LENGTH) / capacity;
+
+        let fanout = layout.indirect_block_children();
+        let mut max_level = 2; // minimum level is 2 (IndirectBlock + LeafBlock)
+        let mut children = fanout;
+        while children < leaves {
+            children = children.saturating_mul(fanout);
+            max_level += 1;
+        }
+        if max_level >= BIT_MAX_LEVEL {
+            return Err(ENOSPC);
+        }
+
+        Ok(max_level)
+    }
+
     /// Create BIT from a MemTable
     pub fn from_memtable(
         memtable: &MemTable,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         client: &DmIoClient,
         checkpoint: &mut Checkpoint,
         meta_bdev: &BlockDevice,
         index_seg: &mut IndexSegment,
     ) -> Result<Self> {
-        // calcualte the max level of BIT, the max level should not exceeded the BIT_MAX_LEVEL
-        let mut max_level = 2; // minimum level is 2 (IndirectBlock + LeafBlock)
-        let mut size = memtable.size() / LEAF_BLOCK_CHILDREN / INDIRECT_BLOCK_CHILDREN;
-        while size > 0 {
-            size /= INDIRECT_BLOCK_CHILDREN;
-            max_level += 1;
-        }
-        if max_level >= BIT_MAX_LEVEL {
-            return Err(ENOSPC);
-        }
+        let layout = kdf.layout();
+        let max_level = Self::max_level(memtable.size(), memtable.blocks(), layout)?;
 
         // array stores current level's LeafBlock / IndirectBlock node
         // this will take a space of BIT_MAX_LEVEL pages
-        let mut leaf = Box::try_new(LeafBlock::default())?;
-        let mut indirect = Box::try_new([(); BIT_MAX_LEVEL].map(|_| IndirectBlock::default()))?;
+        let mut leaf = Box::try_new(LeafBlock::new(layout))?;
+        let mut indirect = Box::try_new([(); BIT_MAX_LEVEL].map(|_| IndirectBlock::new(layout)))?;
 
-        // move elements from MemTable to BIT
-        let mut index = 0;
+        // move extents from MemTable to BIT
         let size = memtable.size();
+        let blocks = memtable.blocks();
+        let mut filter = BloomFilter::new(blocks + size)?;
+
+        for extent in memtable.iter() {
+            filter.insert_extent(extent);
+            Self::append_extent(
+                extent.try_clone()?,
+                &mut leaf,
+                &mut indirect,
+                max_level,
+                aead,
+                kdf,
+                client,
+                checkpoint,
+                meta_bdev,
+                index_seg,
+            )?;
+        }
 
-        for (lba, record) in memtable.iter() {
-            leaf.push(LeafRecord {
-                lba: *lba,
-                record: *record,
-            });
-
-            index += 1;
-            // when LeafBlock is full or is the last element, trigger a writeback
-            if index == size || leaf.is_full() {
-                let lba_range = leaf.get_lba_range();
-                let record = Self::writeback_block(
-                    leaf.as_ref(),
-                    aead,
-                    client,
-                    checkpoint,
-                    meta_bdev,
-                    index_seg,
-                )?;
-
-                indirect[max_level - 2].push(IndirectRecord { lba_range, record });
-
-                Self::pushup_indirect_block(
-                    &mut indirect,
-                    max_level,
-                    false, // index < size
-                    aead,
-                    client,
-                    checkpoint,
-                    meta_bdev,
-                    index_seg,
-                )?;
-
-                *leaf = LeafBlock::default();
-            }
+        if leaf.count > 0 {
+            Self::writeback_leaf(
+                &mut leaf,
+                &mut indirect,
+                max_level,
+                aead,
+                kdf,
+                client,
+                checkpoint,
+                meta_bdev,
+                index_seg,
+            )?;
         }
 
         let root_record = Self::pushup_indirect_block(
@@ -389,6 +462,7 @@ impl BIT {
             max_level,
             true,
             aead,
+            kdf,
             client,
             checkpoint,
             meta_bdev,
@@ -399,18 +473,39 @@ impl BIT {
         let mut root_block = IndirectBlock::default();
         mem::swap(&mut root_block, &mut indirect[0]);
 
+        let bloom = filter.write_to_disk(
+            root_record.lba_range.0,
+            aead,
+            kdf,
+            client,
+            checkpoint,
+            meta_bdev,
+            index_seg,
+        )?;
+
         Ok(Self {
             size,
+            blocks,
             root: root_block,
             record: root_record,
             level: max_level,
+            bloom,
+            filter: Some(filter),
         })
     }
 
-    /// Compact some BITs of level `i` into a new BIT of level `i+1`
+    /// Compact some BITs of level `i` into a new BIT of level `i+1`.
+    ///
+    /// The BITs are merged by LBA, and the newer BIT (with the larger index in `bits`) wins
+    /// where the extents overlap, the overlapped parts of the older extents are split off
+    /// and their blocks are invalid. Tombstones are dropped if `drop_tombstones` is set,
+    /// which is only safe when there is no older record (in a deeper level) the tombstones
+    /// should hide.
     pub fn from_compaction(
         bits: &Vec<BIT>,
+        drop_tombstones: bool,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         client: &DmIoClient,
         checkpoint: &mut Checkpoint,
         meta_bdev: &BlockDevice,
@@ -420,95 +515,116 @@ impl BIT {
         // then compute the max-level and max-size of BIT.
         let mut bit_iterators = Vec::new();
         let mut max_size = 0;
+        let mut max_blocks = 0;
         for bit in bits {
             max_size += bit.size();
-            bit_iterators.try_push(bit.iter(aead, meta_bdev, client)?)?;
+            max_blocks += bit.blocks;
+            bit_iterators.try_push(bit.iter(aead, kdf, meta_bdev, client)?)?;
         }
 
-        let mut max_level = 2;
-        let mut tmp = max_size / LEAF_BLOCK_CHILDREN / INDIRECT_BLOCK_CHILDREN;
-        while tmp > 0 {
-            tmp /= INDIRECT_BLOCK_CHILDREN;
-            max_level += 1;
-        }
+        // an overlapped extent is split into two parts at most by each newer one
+        let layout = kdf.layout();
+        let max_level = Self::max_level(2 * max_size, max_blocks, layout)?;
 
         // create a new block buffer to store the new BIT
-        let mut leaf = Box::try_new(LeafBlock::default())?;
-        let mut indirect = Box::try_new([(); BIT_MAX_LEVEL].map(|_| IndirectBlock::default()))?;
+        let mut leaf = Box::try_new(LeafBlock::new(layout))?;
+        let mut indirect = Box::try_new([(); BIT_MAX_LEVEL].map(|_| IndirectBlock::new(layout)))?;
 
-        // iterate all the BITs and find the latest node
+        // iterate all the BITs and find the latest extent
         // create a node array that holds current iterator nodes
         let mut nodes = Vec::new();
         for iterator in bit_iterators.iter_mut() {
             nodes.try_push(iterator.next()?)?;
         }
 
-        let total_index = bits.len();
-        let mut duplicates = Vec::new();
-        duplicates.try_resize(total_index, 0usize)?;
-
         let mut real_size = 0;
+        let mut real_blocks = 0;
+        let mut filter = BloomFilter::new(max_blocks + 2 * max_size)?;
 
-        // find the node to be compaction
+        // the LBAs before `cursor` have been merged
+        let mut cursor = 0;
         loop {
-            let mut baseline: Option<LeafRecord> = None;
-            let mut duplicate_number = 1;
-            for i in 0..total_index {
-                if nodes[i].is_some() {
-                    let node = nodes[i].as_ref().unwrap();
-                    if baseline.is_none() || node.lba < baseline.as_ref().unwrap().lba {
-                        baseline = nodes[i];
-                        duplicates[0] = i;
-                        duplicate_number = 1;
-                    } else if node.lba == baseline.as_ref().unwrap().lba {
-                        baseline = nodes[i];
-                        duplicates[duplicate_number] = i;
-                        duplicate_number += 1;
+            // the parts of extents before the cursor are overlapped by a newer extent
+            for i in 0..nodes.len() {
+                while let Some(node) = nodes[i].as_mut() {
+                    if node.end() <= cursor {
+                        checkpoint.invalidate_extent(node)?;
+                        nodes[i] = bit_iterators[i].next()?;
+                    } else {
+                        if node.lba < cursor {
+                            let rest = node.split_off(cursor - node.lba)?;
+                            checkpoint.invalidate_extent(node)?;
+                            *node = rest;
+                        }
+                        break;
                     }
                 }
             }
 
-            if baseline.is_some() {
-                real_size += 1;
-                leaf.push(baseline.unwrap());
+            // the newest extent of the lowest LBA is the baseline
+            let mut baseline: Option<usize> = None;
+            for i in 0..nodes.len() {
+                if let Some(node) = nodes[i].as_ref() {
+                    match baseline {
+                        Some(j) if nodes[j].as_ref().unwrap().lba < node.lba => {}
+                        _ => baseline = Some(i),
+                    }
+                }
             }
 
-            if leaf.is_full() || baseline.is_none() {
-                let lba_range = leaf.get_lba_range();
-
-                let record = Self::writeback_block(
-                    leaf.as_ref(),
-                    aead,
-                    client,
-                    checkpoint,
-                    meta_bdev,
-                    index_seg,
-                )?;
-
-                indirect[max_level - 2].push(IndirectRecord { lba_range, record });
-
-                Self::pushup_indirect_block(
-                    &mut indirect,
-                    max_level,
-                    false, // index < size
-                    aead,
-                    client,
-                    checkpoint,
-                    meta_bdev,
-                    index_seg,
-                )?;
+            let baseline = match baseline {
+                Some(baseline) => baseline,
+                None => break,
+            };
 
-                *leaf = LeafBlock::default();
+            // the baseline ends where an extent of a newer BIT begins
+            let mut node = nodes[baseline].take().unwrap();
+            let mut end = node.end();
+            for i in baseline + 1..nodes.len() {
+                if let Some(newer) = nodes[i].as_ref() {
+                    end = cmp::min(end, newer.lba);
+                }
             }
+            nodes[baseline] = match end < node.end() {
+                true => Some(node.split_off(end - node.lba)?),
+                false => bit_iterators[baseline].next()?,
+            };
+            cursor = node.end();
 
-            if baseline.is_none() {
-                break;
+            if drop_tombstones && node.is_tombstone() {
+                continue;
             }
 
-            for i in 0..duplicate_number {
-                let index = duplicates[i];
-                nodes[index] = bit_iterators[index].next()?;
-            }
+            real_size += 1;
+            real_blocks += node.data_blocks() as usize;
+            filter.insert_extent(&node);
+            Self::append_extent(
+                node,
+                &mut leaf,
+                &mut indirect,
+                max_level,
+                aead,
+                kdf,
+                client,
+                checkpoint,
+                meta_bdev,
+                index_seg,
+            )?;
+        }
+
+        // an empty leaf is written only if the whole BIT is empty
+        if leaf.count > 0 || real_size == 0 {
+            Self::writeback_leaf(
+                &mut leaf,
+                &mut indirect,
+                max_level,
+                aead,
+                kdf,
+                client,
+                checkpoint,
+                meta_bdev,
+                index_seg,
+            )?;
         }
 
         let root_record = Self::pushup_indirect_block(
@@ -516,6 +632,7 @@ impl BIT {
             max_level,
             true,
             aead,
+            kdf,
             client,
             checkpoint,
             meta_bdev,
@@ -526,18 +643,100 @@ impl BIT {
         let mut root_block = IndirectBlock::default();
         mem::swap(&mut root_block, &mut indirect[0]);
 
+        let bloom = filter.write_to_disk(
+            root_record.lba_range.0,
+            aead,
+            kdf,
+            client,
+            checkpoint,
+            meta_bdev,
+            index_seg,
+        )?;
+
         Ok(Self {
             size: real_size,
+            blocks: real_blocks,
             root: root_block,
             record: root_record,
             level: max_level,
+            bloom,
+            filter: Some(filter),
         })
     }
 
+    /// Append an extent to the LeafBlock being built. The LeafBlock is written back once
+    /// it can not hold the extent, and the leading blocks of the extent that fit in it are
+    /// split off into it before.
+    fn append_extent(
+        mut extent: Extent,
+        leaf: &mut LeafBlock,
+        indirect: &mut [IndirectBlock; BIT_MAX_LEVEL],
+        max_level: usize,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        client: &DmIoClient,
+        checkpoint: &mut Checkpoint,
+        meta_bdev: &BlockDevice,
+        index_seg: &mut IndexSegment,
+    ) -> Result {
+        loop {
+            let fit = extent.blocks_fit(leaf.room(), leaf.layout);
+            if fit == extent.len {
+                leaf.push(extent);
+                return Ok(());
+            }
+
+            if fit > 0 {
+                let rest = extent.split_off(fit)?;
+                leaf.push(extent);
+                extent = rest;
+            }
+
+            Self::writeback_leaf(
+                leaf, indirect, max_level, aead, kdf, client, checkpoint, meta_bdev, index_seg,
+            )?;
+        }
+    }
+
+    /// Write back the LeafBlock being built, and push up the IndirectBlocks once full
+    fn writeback_leaf(
+        leaf: &mut LeafBlock,
+        indirect: &mut [IndirectBlock; BIT_MAX_LEVEL],
+        max_level: usize,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        client: &DmIoClient,
+        checkpoint: &mut Checkpoint,
+        meta_bdev: &BlockDevice,
+        index_seg: &mut IndexSegment,
+    ) -> Result {
+        let lba_range = leaf.get_lba_range();
+        let record = Self::writeback_block(
+            leaf,
+            lba_range.0,
+            aead,
+            kdf,
+            client,
+            checkpoint,
+            meta_bdev,
+            index_seg,
+        )?;
+
+        indirect[max_level - 2].push(IndirectRecord { lba_range, record });
+
+        Self::pushup_indirect_block(
+            indirect, max_level, false, aead, kdf, client, checkpoint, meta_bdev, index_seg,
+        )?;
+
+        *leaf = LeafBlock::new(leaf.layout);
+        Ok(())
+    }
+
     pub fn find_record(
         &self,
         lba: u64,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         bdev: &BlockDevice,
         client: &DmIoClient,
         indirect_block_cache: &mut LruCache<u64, IndirectBlock>,
@@ -572,10 +771,10 @@ impl BIT {
                     drop(children);
                     drop(count);
 
-                    let record = indirect_record.record;
                     block = Some(Self::read_block(
-                        record,
+                        indirect_record,
                         aead,
+                        kdf,
                         bdev,
                         client,
                         indirect_block_cache,
@@ -604,33 +803,22 @@ impl BIT {
 
         match Self::find_indirect_record(children, count, lba) {
             Some(indirect_record) => {
-                let record = indirect_record.record;
                 let leaf: LeafBlock =
-                    Self::read_block(record, aead, bdev, client, leaf_block_cache)?;
+                    Self::read_block(indirect_record, aead, kdf, bdev, client, leaf_block_cache)?;
                 return Ok(Self::find_record_in_leafblock(&leaf, lba));
             }
             None => Ok(None),
         }
     }
 
-    /// binary search a Record with LBA in LeafBlock
+    /// binary search the Extent containing LBA in LeafBlock, and derive the Record of LBA
     fn find_record_in_leafblock(leafblock: &LeafBlock, lba: u64) -> Option<Record> {
-        let mut left = 0;
-        let mut right = leafblock.count - 1;
-        while left <= right {
-            let mid = (left + right) >> 1;
-            let mid_lba = leafblock.children[mid].lba;
-            if lba == mid_lba {
-                return Some(leafblock.children[mid].record);
-            } else if mid == 0 {
-                return None; // fix: usize subtract overflow
-            } else if lba < mid_lba {
-                right = mid - 1;
-            } else {
-                left = mid + 1;
-            }
+        let children = &leafblock.children[..leafblock.count];
+        let index = children.partition_point(|extent| extent.end() <= lba);
+        match children.get(index) {
+            Some(extent) if extent.contains(lba) => Some(extent.record(lba)),
+            _ => None,
         }
-        return None;
     }
 
     /// binary search a IndirectRecord that may contains the LBA
@@ -665,6 +853,7 @@ impl BIT {
         max_level: usize,
         write_all: bool,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         client: &DmIoClient,
         checkpoint: &mut Checkpoint,
         meta_bdev: &BlockDevice,
@@ -684,7 +873,9 @@ impl BIT {
 
                 let record = Self::writeback_block(
                     &indirect[level],
+                    lba_range.0,
                     aead,
+                    kdf,
                     client,
                     checkpoint,
                     meta_bdev,
@@ -692,7 +883,7 @@ impl BIT {
                 )?;
                 if level > 0 {
                     indirect[level - 1].push(IndirectRecord { lba_range, record });
-                    indirect[level] = IndirectBlock::default();
+                    indirect[level] = IndirectBlock::new(indirect[level].layout);
                     level -= 1;
                 } else {
                     return Ok(Some(IndirectRecord { lba_range, record }));
@@ -706,16 +897,18 @@ impl BIT {
     }
 
     /// Read a struct of size BLOCK_SIZE. The struct should be deserializable.  
-    pub fn read_block<T: Sized + Deserialize + Debug + Clone>(
-        record: Record,
+    pub fn read_block<T: Sized + Deserialize + Debug + TryClone>(
+        indirect_record: IndirectRecord,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         bdev: &BlockDevice,
         client: &DmIoClient,
         cache: &mut LruCache<u64, T>,
     ) -> Result<T> {
+        let record = indirect_record.record;
         match cache.get(&record.hba) {
             Some(block) => {
-                return Ok(block.clone());
+                return block.try_clone();
             }
             _ => {}
         };
@@ -731,13 +924,16 @@ impl BIT {
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
 
+        // the block should be at its position in BIT
+        let mut assoc = record.associated_data(indirect_record.lba_range.0)?;
         unsafe {
-            aead.as_ref().decrypt_in_place(
-                &slice_to_vec::<{ SWORNDISK_KEY_LENGTH }>(&record.key)?,
+            aead.as_ref().decrypt_in_place_with_ad(
+                &kdf.index_key(&record)?,
                 &mut slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&record.mac)?,
                 &mut slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&record.nonce)?,
+                &mut assoc[..],
                 &mut block,
                 BLOCK_SIZE as usize,
             )?
@@ -746,18 +942,20 @@ impl BIT {
         let result = T::deserialize(&block)?;
 
         // insert and update LRU cache
-        cache.put(record.hba, result.clone())?;
+        cache.put(record.hba, result.try_clone()?)?;
 
         Ok(result)
     }
 
     /// Read a block directly from meta device without accessing cache
-    pub fn read_block_directly<T: Sized + Deserialize + Debug + Clone>(
-        record: Record,
+    pub fn read_block_directly<T: Sized + Deserialize + Debug + TryClone>(
+        indirect_record: IndirectRecord,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         bdev: &BlockDevice,
         client: &DmIoClient,
     ) -> Result<T> {
+        let record = indirect_record.record;
         let mut block = Vec::new();
         block.try_resize(BLOCK_SIZE as usize, 0u8)?;
 
@@ -769,13 +967,16 @@ impl BIT {
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
 
+        // the block should be at its position in BIT
+        let mut assoc = record.associated_data(indirect_record.lba_range.0)?;
         unsafe {
-            aead.as_ref().decrypt_in_place(
-                &slice_to_vec::<{ SWORNDISK_KEY_LENGTH }>(&record.key)?,
+            aead.as_ref().decrypt_in_place_with_ad(
+                &kdf.index_key(&record)?,
                 &mut slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&record.mac)?,
                 &mut slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&record.nonce)?,
+                &mut assoc[..],
                 &mut block,
                 BLOCK_SIZE as usize,
             )?
@@ -789,16 +990,20 @@ impl BIT {
     pub fn iter<'a>(
         &'a self,
         aead: &'a Pin<Box<Aead>>,
+        kdf: &'a KeyDeriver,
         bdev: &'a BlockDevice,
         client: &'a DmIoClient,
     ) -> Result<BITIterator<'a>> {
-        Ok(BITIterator::new(self, aead, bdev, client)?)
+        Ok(BITIterator::new(self, aead, kdf, bdev, client)?)
     }
 
-    /// Write a Block which implemented Serialize trait to disk
+    /// Write a Block which implemented Serialize trait to disk, `lba` is the first LBA
+    /// of the range managed by the block
     fn writeback_block<T: Sized + Serialize>(
         block: &T,
+        lba: u64,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         client: &DmIoClient,
         checkpoint: &mut Checkpoint,
         meta_bdev: &BlockDevice,
@@ -808,7 +1013,9 @@ impl BIT {
         let record = index_seg.write(
             &buf,
             BLOCK_SIZE as usize,
+            lba,
             aead,
+            kdf,
             client,
             checkpoint,
             meta_bdev,
@@ -822,6 +1029,7 @@ pub struct BITIterator<'a> {
     _bit: PhantomData<&'a BIT>,
 
     aead: &'a Pin<Box<Aead>>,
+    kdf: &'a KeyDeriver,
     bdev: &'a BlockDevice,
     client: &'a DmIoClient,
 
@@ -838,6 +1046,7 @@ impl<'a> BITIterator<'a> {
     pub fn new(
         bit: &'a BIT,
         aead: &'a Pin<Box<Aead>>,
+        kdf: &'a KeyDeriver,
         bdev: &'a BlockDevice,
         client: &'a DmIoClient,
     ) -> Result<Self> {
@@ -853,7 +1062,7 @@ impl<'a> BITIterator<'a> {
         index[level - 1] = 0;
 
         // read the first node of each level
-        block_stack[0] = bit.root.clone();
+        block_stack[0] = bit.root.try_clone()?;
         for i in 1..level - 1 {
             if block_stack[i - 1].count <= 0 {
                 pr_warn!("Cannot create BITIterator: block is empty");
@@ -862,8 +1071,9 @@ impl<'a> BITIterator<'a> {
 
             // read the first element
             block_stack[i] = BIT::read_block_directly::<IndirectBlock>(
-                block_stack[i - 1].children[0].record,
+                block_stack[i - 1].children[0],
                 aead,
+                kdf,
                 bdev,
                 client,
             )?;
@@ -877,8 +1087,9 @@ impl<'a> BITIterator<'a> {
         }
 
         *leaf_block = BIT::read_block_directly::<LeafBlock>(
-            block_stack[level - 2].children[0].record,
+            block_stack[level - 2].children[0],
             aead,
+            kdf,
             bdev,
             client,
         )?;
@@ -890,6 +1101,7 @@ impl<'a> BITIterator<'a> {
             leaf_block,
 
             aead,
+            kdf,
             bdev,
             client,
 
@@ -909,8 +1121,9 @@ impl<'a> BITIterator<'a> {
 
             let mut level = self.level - 1;
             let next_leaf_block = BIT::read_block_directly::<LeafBlock>(
-                self.block_stack[level - 1].children[self.index[level - 1]].record,
+                self.block_stack[level - 1].children[self.index[level - 1]],
                 self.aead,
+                self.kdf,
                 self.bdev,
                 self.client,
             )?;
@@ -927,8 +1140,9 @@ impl<'a> BITIterator<'a> {
                     return Ok(());
                 }
                 let next_block = BIT::read_block_directly::<IndirectBlock>(
-                    self.block_stack[level - 1].children[self.index[level - 1]].record,
+                    self.block_stack[level - 1].children[self.index[level - 1]],
                     self.aead,
+                    self.kdf,
                     self.bdev,
                     self.client,
                 )?;
@@ -947,11 +1161,11 @@ impl<'a> BITIterator<'a> {
     }
 
     /// Iterate next element
-    pub fn next(&mut self) -> Result<Option<LeafRecord>> {
+    pub fn next(&mut self) -> Result<Option<Extent>> {
         self.update_block_stack()?;
 
         if self.has_next() {
-            let item = self.leaf_block.children[self.index[self.level - 1]];
+            let item = self.leaf_block.children[self.index[self.level - 1]].try_clone()?;
             self.index[self.level - 1] += 1;
             self.total_index += 1;
 
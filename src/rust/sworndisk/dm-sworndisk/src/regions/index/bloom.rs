@@ -0,0 +1,230 @@
+//! Bloom filter of the LBAs indexed by a BIT
+//!
+//! A BIT indexes a few LBAs scattered over its LBA range, so a read of an LBA in the range
+//! usually descends the BIT for nothing. The filter of a BIT tells the LBAs surely not in
+//! it, and the BIT is skipped without reading any index block.
+
+use super::{extent::Extent, record::Record, segment::IndexSegment};
+use crate::{kdf::KeyDeriver, prelude::*, regions::Checkpoint, utils::*};
+
+/// On-disk location of a Bloom filter
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BloomFilterMeta {
+    /// crypt info of the filter, which is encrypted as an extent of `nblocks` blocks
+    pub record: Record,
+
+    /// block number of the filter
+    pub nblocks: u64,
+
+    /// number of hash functions
+    pub nhashes: u64,
+}
+
+pub const BLOOM_FILTER_META_SIZE: usize = mem::size_of::<BloomFilterMeta>();
+
+impl Serialize for BloomFilterMeta {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut vec = Vec::new();
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.nblocks) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.nhashes) })?;
+        vec.try_extend_from_slice(&self.record.serialize()?)?;
+        vec.try_resize(BLOOM_FILTER_META_SIZE, 0u8)?;
+
+        Ok(vec)
+    }
+}
+
+impl Deserialize for BloomFilterMeta {
+    fn deserialize(buf: &[u8]) -> Result<Self> {
+        if buf.len() != BLOOM_FILTER_META_SIZE {
+            return Err(EINVAL);
+        }
+
+        let nblocks = unsafe { mem::transmute::<[u8; 8], u64>(buf[0..8].try_into().unwrap()) };
+        let nhashes = unsafe { mem::transmute::<[u8; 8], u64>(buf[8..16].try_into().unwrap()) };
+        let record = Record::deserialize(&buf[16..16 + SWORNDISK_RECORD_SIZE])?;
+
+        Ok(Self {
+            record,
+            nblocks,
+            nhashes,
+        })
+    }
+}
+
+/// Bloom filter with `nhashes` hash functions over a bitmap of whole blocks
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    nhashes: u64,
+}
+
+impl Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BloomFilter")
+            .field("nbytes", &self.bits.len())
+            .field("nhashes", &self.nhashes)
+            .finish()
+    }
+}
+
+impl BloomFilter {
+    /// Create an empty filter for at most `nkeys` keys
+    pub fn new(nkeys: usize) -> Result<Self> {
+        let nkeys = cmp::max(nkeys, 1);
+        let nbytes = nkeys * BLOOM_FILTER_BITS_PER_KEY / 8;
+        let nblocks = cmp::min(
+            (nbytes + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize,
+            BLOOM_FILTER_MAX_BLOCKS,
+        );
+        let nblocks = cmp::max(nblocks, 1);
+
+        // the false positive rate is the lowest with `ln(2) * nbits / nkeys` hash functions
+        let nbits = nblocks * BLOCK_SIZE as usize * 8;
+        let nhashes = cmp::min(
+            cmp::max(nbits * 69 / 100 / nkeys, 1),
+            BLOOM_FILTER_MAX_HASHES,
+        );
+
+        let mut bits = Vec::new();
+        bits.try_resize(nblocks * BLOCK_SIZE as usize, 0u8)?;
+
+        Ok(Self {
+            bits,
+            nhashes: nhashes as u64,
+        })
+    }
+
+    /// Add the LBAs of `extent` into the filter. A tombstone may cover many LBAs without
+    /// data, so only its chunk (of `EXTENT_MAX_BLOCKS` LBAs) is added.
+    pub fn insert_extent(&mut self, extent: &Extent) {
+        if extent.is_tombstone() {
+            self.insert(Self::chunk_key(extent.lba));
+            return;
+        }
+
+        for lba in extent.lba..extent.end() {
+            self.insert(lba);
+        }
+    }
+
+    /// Check whether `lba` may be in the filter, a `false` means it is surely not
+    pub fn may_contain(&self, lba: u64) -> bool {
+        self.contains(lba) || self.contains(Self::chunk_key(lba))
+    }
+
+    /// The key of the chunk of `lba`, which never collides with a LBA
+    fn chunk_key(lba: u64) -> u64 {
+        !(lba / EXTENT_MAX_BLOCKS)
+    }
+
+    /// Add `key` into the filter
+    fn insert(&mut self, key: u64) {
+        let (h1, h2) = Self::hash(key);
+        let nbits = self.bits.len() as u64 * 8;
+        for i in 0..self.nhashes {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % nbits;
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Check whether `key` may be in the filter
+    fn contains(&self, key: u64) -> bool {
+        let (h1, h2) = Self::hash(key);
+        let nbits = self.bits.len() as u64 * 8;
+        for i in 0..self.nhashes {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % nbits;
+            if self.bits[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Two hashes of `key`, the i-th hash function is `h1 + i * h2` (double hashing)
+    fn hash(key: u64) -> (u64, u64) {
+        let h1 = Self::mix(key);
+        let h2 = Self::mix(h1) | 1;
+        (h1, h2)
+    }
+
+    /// The finalizer of SplitMix64, which spreads the consecutive LBAs over the filter
+    fn mix(mut x: u64) -> u64 {
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    }
+
+    /// Write the filter into the index segment, `lba` is the first LBA of the BIT
+    pub fn write_to_disk(
+        &self,
+        lba: u64,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        client: &DmIoClient,
+        checkpoint: &mut Checkpoint,
+        meta_bdev: &BlockDevice,
+        index_seg: &mut IndexSegment,
+    ) -> Result<BloomFilterMeta> {
+        let mut blocks = Vec::new();
+        blocks.try_extend_from_slice(&self.bits)?;
+        let record =
+            index_seg.write_extent(blocks, lba, aead, kdf, client, checkpoint, meta_bdev)?;
+
+        Ok(BloomFilterMeta {
+            record,
+            nblocks: self.bits.len() as u64 / BLOCK_SIZE,
+            nhashes: self.nhashes,
+        })
+    }
+
+    /// Read the filter of `meta` from disk, `lba` is the first LBA of the BIT
+    pub fn read_from_disk(
+        meta: &BloomFilterMeta,
+        lba: u64,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result<Self> {
+        // a forged number of hash functions must not stall the lookups
+        if meta.nblocks == 0
+            || meta.nblocks > BLOOM_FILTER_MAX_BLOCKS as u64
+            || meta.nhashes == 0
+            || meta.nhashes > BLOOM_FILTER_MAX_HASHES as u64
+        {
+            return Err(EINVAL);
+        }
+
+        let record = meta.record;
+        let len = (meta.nblocks * BLOCK_SIZE) as usize;
+        let mut bits = Vec::new();
+        bits.try_resize(len, 0u8)?;
+
+        let mut region = DmIoRegion::new(&bdev, record.hba, meta.nblocks * BLOCK_SECTORS)?;
+        let mut io_req = DmIoRequest::with_kernel_memory(
+            READ as i32,
+            READ as i32,
+            bits.as_mut_ptr() as *mut c_void,
+            0,
+            client,
+        );
+        io_req.submit(&mut region)?;
+
+        let mut assoc = record.associated_data(lba)?;
+        unsafe {
+            aead.as_ref().decrypt_in_place_with_ad(
+                &kdf.index_key(&record)?,
+                &mut slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&record.mac)?,
+                &mut slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&record.nonce)?,
+                &mut assoc[..],
+                &mut bits,
+                len,
+            )?
+        };
+
+        Ok(Self {
+            bits,
+            nhashes: meta.nhashes,
+        })
+    }
+}
@@ -0,0 +1,259 @@
+//! Extent records of the index
+//!
+//! A sequential write logs a run of blocks whose LBAs and HBAs are both contiguous. Instead
+//! of a `Record` per block, the run is indexed by an extent, whose blocks share a key and
+//! take consecutive nonces, so only the MAC of each block is kept. The `Record` of a block
+//! is derived from its extent, and a split extent derives the same records.
+//!
+//! If the block keys are derived (see `KeyScheme`), the key of an extent is not stored and
+//! each block gets its own key from its HBA.
+
+use crate::{
+    prelude::*,
+    regions::{Record, RecordLayout, TOMBSTONE_HBA},
+    types::{KeyType, MacType, NonceType},
+    utils::*,
+};
+
+use crypto::get_random_bytes;
+
+/// An extent maps the LBAs `[lba, lba + len)` to the blocks beginning with `hba`
+#[derive(Debug)]
+pub struct Extent {
+    /// first LBA of the extent
+    pub lba: u64,
+    /// number of blocks
+    pub len: u64,
+    /// HBA of the first block, the blocks are contiguous on the data device
+    pub hba: u64,
+    /// crypto key shared by the blocks, see `KeyDeriver::block_key` for the key of a block
+    pub key: KeyType,
+    /// nonce of the first block, the nonce of block `i` is this one plus `i`
+    pub nonce: NonceType,
+    /// version of the blocks, see `Record::version`
+    pub version: u64,
+    /// MAC of each block, which is empty for a tombstone
+    pub macs: Vec<MacType>,
+}
+
+impl TryClone for Extent {
+    fn try_clone(&self) -> Result<Self> {
+        let mut macs = Vec::try_with_capacity(self.macs.len())?;
+        for mac in self.macs.iter() {
+            macs.try_push(*mac)?;
+        }
+        Ok(Self { macs, ..*self })
+    }
+}
+
+impl Extent {
+    /// Create an empty extent of blocks beginning with `hba` with `key` and a random nonce,
+    /// the blocks are appended by `push_block`
+    pub fn new(lba: u64, hba: u64, key: KeyType, version: u64) -> Result<Self> {
+        let nonce = get_random_bytes(SWORNDISK_NONCE_LENGTH)?;
+
+        Ok(Self {
+            lba,
+            len: 0,
+            hba,
+            key,
+            nonce: vec_to_slice::<{ SWORNDISK_NONCE_LENGTH }>(&nonce)?,
+            version,
+            macs: Vec::new(),
+        })
+    }
+
+    /// Create a tombstone extent, the LBAs are discarded and read as zeros
+    pub fn tombstone(lba: u64, len: u64) -> Self {
+        let record = Record::tombstone();
+        Self {
+            lba,
+            len,
+            hba: record.hba,
+            key: record.key,
+            nonce: record.nonce,
+            version: record.version,
+            macs: Vec::new(),
+        }
+    }
+
+    /// Check the extent is a tombstone
+    pub fn is_tombstone(&self) -> bool {
+        self.hba == TOMBSTONE_HBA
+    }
+
+    /// The LBA following the extent
+    pub fn end(&self) -> u64 {
+        self.lba + self.len
+    }
+
+    /// Check a LBA is in the extent
+    pub fn contains(&self, lba: u64) -> bool {
+        self.lba <= lba && lba < self.end()
+    }
+
+    /// Number of data blocks of the extent, which is 0 for a tombstone
+    pub fn data_blocks(&self) -> u64 {
+        self.macs.len() as u64
+    }
+
+    /// Nonce of the `index`-th block
+    pub fn nonce_of(&self, index: u64) -> NonceType {
+        // the last 8 bytes of the nonce are a little-endian counter
+        let at = SWORNDISK_NONCE_LENGTH - 8;
+        let mut nonce = self.nonce;
+        let counter = u64::from_le_bytes(nonce[at..].try_into().unwrap());
+        nonce[at..].copy_from_slice(&counter.wrapping_add(index).to_le_bytes());
+        nonce
+    }
+
+    /// Append the next block encrypted with `nonce_of(len)`, whose MAC is `mac`
+    pub fn push_block(&mut self, mac: MacType) -> Result {
+        self.macs.try_push(mac)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Derive the record of `lba`, which should be in the extent
+    pub fn record(&self, lba: u64) -> Record {
+        if self.is_tombstone() {
+            return Record::tombstone();
+        }
+
+        let index = lba - self.lba;
+        Record {
+            hba: self.hba + index * BLOCK_SECTORS,
+            key: self.key,
+            nonce: self.nonce_of(index),
+            mac: self.macs[index as usize],
+            version: self.version,
+        }
+    }
+
+    /// Split the extent at its `at`-th block, the extent keeps the blocks before it and
+    /// the rest are returned
+    pub fn split_off(&mut self, at: u64) -> Result<Self> {
+        if at == 0 || at >= self.len {
+            return Err(EINVAL);
+        }
+
+        let mut macs = Vec::new();
+        if !self.is_tombstone() {
+            macs.try_extend_from_slice(&self.macs[at as usize..])?;
+            self.macs.truncate(at as usize);
+        }
+
+        let rest = Self {
+            lba: self.lba + at,
+            len: self.len - at,
+            hba: match self.is_tombstone() {
+                true => TOMBSTONE_HBA,
+                false => self.hba + at * BLOCK_SECTORS,
+            },
+            key: self.key,
+            nonce: match self.is_tombstone() {
+                true => self.nonce,
+                false => self.nonce_of(at),
+            },
+            version: self.version,
+            macs,
+        };
+        self.len = at;
+
+        Ok(rest)
+    }
+
+    /// Size of the extent serialized in `layout`
+    pub fn disk_size(&self, layout: RecordLayout) -> usize {
+        layout.extent_header_size() + self.macs.len() * SWORNDISK_MAC_LENGTH
+    }
+
+    /// Number of the leading blocks that fit in `size` bytes once serialized in `layout`
+    pub fn blocks_fit(&self, size: usize, layout: RecordLayout) -> u64 {
+        let header_size = layout.extent_header_size();
+        if size < header_size {
+            return 0;
+        }
+        if self.is_tombstone() {
+            return self.len;
+        }
+
+        cmp::min(
+            ((size - header_size) / SWORNDISK_MAC_LENGTH) as u64,
+            self.len,
+        )
+    }
+
+    /// Serialize the extent in `layout`
+    pub fn serialize_with(&self, layout: RecordLayout) -> Result<Vec<u8>> {
+        let mut vec = Vec::try_with_capacity(self.disk_size(layout))?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.lba) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.len) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.hba) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.version) })?;
+        vec.try_extend_from_slice(&self.key[..layout.key_len])?;
+        vec.try_extend_from_slice(&self.nonce)?;
+        for mac in self.macs.iter() {
+            vec.try_extend_from_slice(mac)?;
+        }
+
+        Ok(vec)
+    }
+
+    /// Deserialize an extent in `layout` from the beginning of `buf`, returns the extent
+    /// and its length. The key is zeros if it is not stored.
+    pub fn deserialize_from(buf: &[u8], layout: RecordLayout) -> Result<(Self, usize)> {
+        let header_size = layout.extent_header_size();
+        if buf.len() < header_size {
+            return Err(EINVAL);
+        }
+
+        let lba = unsafe { mem::transmute::<[u8; 8], u64>(buf[0..8].try_into().unwrap()) };
+        let len = unsafe { mem::transmute::<[u8; 8], u64>(buf[8..16].try_into().unwrap()) };
+        let hba = unsafe { mem::transmute::<[u8; 8], u64>(buf[16..24].try_into().unwrap()) };
+        let version = unsafe { mem::transmute::<[u8; 8], u64>(buf[24..32].try_into().unwrap()) };
+        let key_index = 32;
+        let mut key = [0; SWORNDISK_KEY_LENGTH];
+        key[..layout.key_len].copy_from_slice(&buf[key_index..key_index + layout.key_len]);
+        let nonce_index = key_index + layout.key_len;
+        let nonce = buf[nonce_index..nonce_index + SWORNDISK_NONCE_LENGTH]
+            .try_into()
+            .unwrap();
+
+        if len == 0 || len > EXTENT_MAX_BLOCKS {
+            return Err(EINVAL);
+        }
+
+        let mut macs = Vec::new();
+        let mut index = header_size;
+        if hba != TOMBSTONE_HBA {
+            if buf.len() < index + len as usize * SWORNDISK_MAC_LENGTH {
+                return Err(EINVAL);
+            }
+
+            for _ in 0..len {
+                macs.try_push(buf[index..index + SWORNDISK_MAC_LENGTH].try_into().unwrap())?;
+                index += SWORNDISK_MAC_LENGTH;
+            }
+        }
+
+        Ok((
+            Self {
+                lba,
+                len,
+                hba,
+                key,
+                nonce,
+                version,
+                macs,
+            },
+            index,
+        ))
+    }
+}
+
+impl Serialize for Extent {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        self.serialize_with(RecordLayout::default())
+    }
+}
@@ -1,12 +1,22 @@
-use crate::{prelude::*, regions::index::record::Record};
+use crate::{
+    prelude::*,
+    regions::index::{extent::Extent, record::Record},
+};
 use core::cmp;
-use kernel::rbtree::{RBTree, RBTreeIterator, RBTreeNode};
+use kernel::rbtree::RBTree;
 
 /// MemTable: Level-0 (in memory) block index table
+///
+/// The extents are grouped by the chunk of `EXTENT_MAX_BLOCKS` LBAs they belong to, so the
+/// extent of a LBA is found by a lookup of its chunk and a binary search in the chunk.
 pub struct MemTable {
+    /// number of extents
     size: usize,
+    /// number of data blocks indexed by the extents
+    blocks: usize,
     lba_range: (u64, u64),
-    inner: RBTree<u64, Record>,
+    /// sorted extents of each chunk, which never overlap
+    inner: RBTree<u64, Vec<Extent>>,
 }
 
 impl Debug for MemTable {
@@ -20,38 +30,142 @@ impl MemTable {
     pub fn new() -> Self {
         Self {
             size: 0,
+            blocks: 0,
             lba_range: (u64::MAX, u64::MIN),
             inner: RBTree::new(),
         }
     }
 
-    /// Insert a record into the index
-    pub fn insert(&mut self, lba: u64, record: Record) -> Result<Option<RBTreeNode<u64, Record>>> {
-        self.size += 1;
-        self.lba_range.0 = cmp::min(self.lba_range.0, lba);
-        self.lba_range.1 = cmp::max(self.lba_range.1, lba);
-        self.inner.try_insert(lba, record)
+    /// Insert an extent into the index, return the parts of the extents it replaces
+    pub fn insert(&mut self, mut extent: Extent) -> Result<Vec<Extent>> {
+        let mut replaced = Vec::new();
+        if extent.len == 0 {
+            return Ok(replaced);
+        }
+
+        self.lba_range.0 = cmp::min(self.lba_range.0, extent.lba);
+        self.lba_range.1 = cmp::max(self.lba_range.1, extent.end() - 1);
+
+        // split the extent at the chunk boundaries
+        loop {
+            let chunk_end = (extent.lba / EXTENT_MAX_BLOCKS + 1) * EXTENT_MAX_BLOCKS;
+            if extent.end() <= chunk_end {
+                self.insert_into_chunk(extent, &mut replaced)?;
+                break;
+            }
+
+            let rest = extent.split_off(chunk_end - extent.lba)?;
+            self.insert_into_chunk(extent, &mut replaced)?;
+            extent = rest;
+        }
+
+        Ok(replaced)
     }
 
-    /// Find a record from the index
-    pub fn find(&self, lba: u64) -> Option<&Record> {
+    /// Insert an extent inside a chunk, the overlapped parts of the existing extents are
+    /// moved into `replaced`. A tombstone is merged into the tombstone right before it.
+    fn insert_into_chunk(&mut self, extent: Extent, replaced: &mut Vec<Extent>) -> Result {
+        let chunk = extent.lba / EXTENT_MAX_BLOCKS;
+        let (begin, end) = (extent.lba, extent.end());
+
+        let old_extents = self.inner.remove(&chunk).unwrap_or_default();
+        let mut extents: Vec<Extent> = Vec::try_with_capacity(old_extents.len() + 2)?;
+        self.size -= old_extents.len();
+        for old in old_extents.iter() {
+            self.blocks -= old.data_blocks() as usize;
+        }
+
+        let mut extent = Some(extent);
+        for mut old in old_extents.into_iter() {
+            if old.end() <= begin {
+                extents.try_push(old)?;
+                continue;
+            }
+
+            if old.lba >= end {
+                if let Some(extent) = extent.take() {
+                    Self::push_extent(&mut extents, extent)?;
+                }
+                extents.try_push(old)?;
+                continue;
+            }
+
+            // keep the parts of the old extent outside [begin, end)
+            if old.lba < begin {
+                let rest = old.split_off(begin - old.lba)?;
+                extents.try_push(old)?;
+                old = rest;
+            }
+            if old.end() > end {
+                let tail = old.split_off(end - old.lba)?;
+                replaced.try_push(old)?;
+                if let Some(extent) = extent.take() {
+                    Self::push_extent(&mut extents, extent)?;
+                }
+                extents.try_push(tail)?;
+            } else {
+                replaced.try_push(old)?;
+            }
+        }
+        if let Some(extent) = extent.take() {
+            Self::push_extent(&mut extents, extent)?;
+        }
+
+        self.size += extents.len();
+        for extent in extents.iter() {
+            self.blocks += extent.data_blocks() as usize;
+        }
+        self.inner.try_insert(chunk, extents)?;
+
+        Ok(())
+    }
+
+    /// Push an extent after the sorted `extents`, merge it if both are adjacent tombstones
+    fn push_extent(extents: &mut Vec<Extent>, extent: Extent) -> Result {
+        if let Some(last) = extents.last_mut() {
+            if last.is_tombstone() && extent.is_tombstone() && last.end() == extent.lba {
+                last.len += extent.len;
+                return Ok(());
+            }
+        }
+
+        extents.try_push(extent)?;
+        Ok(())
+    }
+
+    /// Find the record of a LBA from the index
+    pub fn find(&self, lba: u64) -> Option<Record> {
         if lba < self.lba_range.0 || lba > self.lba_range.1 {
             return None;
         }
 
-        self.inner.get(&lba)
+        let extents = self.inner.get(&(lba / EXTENT_MAX_BLOCKS))?;
+        let index = extents.partition_point(|extent| extent.end() <= lba);
+        match extents.get(index) {
+            Some(extent) if extent.contains(lba) => Some(extent.record(lba)),
+            _ => None,
+        }
     }
 
-    pub fn iter(&self) -> RBTreeIterator<'_, u64, Record> {
-        self.inner.iter()
+    /// Iterate the extents in the order of LBA
+    pub fn iter(&self) -> impl Iterator<Item = &Extent> {
+        self.inner.iter().flat_map(|(_, extents)| extents.iter())
     }
 
+    /// Get the number of extents
     pub fn size(&self) -> usize {
         self.size
     }
 
+    /// Get the number of data blocks indexed by the extents
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+
     pub fn clear(&mut self) {
         self.size = 0;
+        self.blocks = 0;
+        self.lba_range = (u64::MAX, u64::MIN);
         self.inner = RBTree::new();
     }
 }
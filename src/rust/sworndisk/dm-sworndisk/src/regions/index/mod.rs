@@ -1,9 +1,13 @@
 pub mod bit;
+pub mod bloom;
+pub mod extent;
 pub mod memtable;
 pub mod record;
 pub mod segment;
 
 pub use bit::*;
+pub use bloom::*;
+pub use extent::*;
 pub use memtable::*;
 pub use record::*;
 pub use segment::*;
@@ -1,3 +1,5 @@
+use crate::cipher::CipherSuite;
+use crate::kdf::KeyScheme;
 use crate::prelude::*;
 use crate::types::{KeyType, MacType, NonceType};
 use crate::utils::*;
@@ -13,6 +15,9 @@ pub struct Record {
     pub nonce: NonceType,
     /// Crypto authentication data (a.k.a MAC / tag)
     pub mac: MacType,
+    /// Version of the block, which is the sequence number of the checkpoint that
+    /// will cover the block at the time it is written
+    pub version: u64,
 }
 
 impl Default for Record {
@@ -22,6 +27,7 @@ impl Default for Record {
             key: [0; SWORNDISK_KEY_LENGTH],
             mac: [0; SWORNDISK_MAC_LENGTH],
             nonce: [0; SWORNDISK_NONCE_LENGTH],
+            version: 0,
         }
     }
 }
@@ -29,31 +35,119 @@ impl Default for Record {
 /// The size of BIT record
 pub const SWORNDISK_RECORD_SIZE: usize = mem::size_of::<Record>();
 
-impl Serialize for Record {
-    fn serialize(&self) -> Result<Vec<u8>> {
-        let mut vec = Vec::new();
+/// HBA of a tombstone record, which points to no block
+pub const TOMBSTONE_HBA: u64 = u64::MAX;
+
+/// The size of associated data authenticated along with a block
+pub const RECORD_AD_SIZE: usize = 24;
+
+/// On-disk layout of the records in the index blocks, which depends on the `KeyScheme` and
+/// the `CipherSuite`.
+///
+/// A record stores the key of its cipher suite, or no key if the block keys are derived.
+/// The records kept in checkpoint and journal always take the full layout
+/// (`RecordLayout::default()`), which holds the longest key.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RecordLayout {
+    /// bytes of the stored key
+    pub key_len: usize,
+}
+
+impl Default for RecordLayout {
+    fn default() -> Self {
+        Self {
+            key_len: SWORNDISK_KEY_LENGTH,
+        }
+    }
+}
+
+impl RecordLayout {
+    /// Layout of the records of `scheme` and `cipher`
+    pub fn of(scheme: KeyScheme, cipher: CipherSuite) -> Self {
+        match scheme {
+            KeyScheme::Random => Self {
+                key_len: cipher.key_len(),
+            },
+            KeyScheme::Derived => Self { key_len: 0 },
+        }
+    }
+
+    /// Layout of the stored key length `key_len`, e.g. read from an index block
+    pub fn from_key_len(key_len: usize) -> Result<Self> {
+        match key_len {
+            0 | 16 | SWORNDISK_KEY_LENGTH => Ok(Self { key_len }),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Size of a serialized record: hba, key, nonce, mac and version
+    pub fn record_size(&self) -> usize {
+        8 + self.key_len + SWORNDISK_NONCE_LENGTH + SWORNDISK_MAC_LENGTH + 8
+    }
+
+    /// Size of a serialized extent without MACs: lba, len, hba, version, key and nonce
+    pub fn extent_header_size(&self) -> usize {
+        8 * 4 + self.key_len + SWORNDISK_NONCE_LENGTH
+    }
+
+    /// Size of a serialized IndirectRecord: lba range and record
+    pub fn indirect_record_size(&self) -> usize {
+        16 + self.record_size()
+    }
+
+    /// Number of IndirectRecords an IndirectBlock holds
+    pub fn indirect_block_children(&self) -> usize {
+        (BLOCK_SIZE as usize - 8) / self.indirect_record_size() // 8: count(u32) and key_len(u32)
+    }
+}
+
+impl Record {
+    /// Create a tombstone record, the LBA is discarded and reads as zeros
+    pub fn tombstone() -> Self {
+        Self {
+            hba: TOMBSTONE_HBA,
+            ..Self::default()
+        }
+    }
+
+    /// Check the record is a tombstone
+    pub fn is_tombstone(&self) -> bool {
+        self.hba == TOMBSTONE_HBA
+    }
+
+    /// Associated data of the block managed by this record: (LBA, HBA, version).
+    ///
+    /// For a data block, `lba` is the LBA it holds. For an index block, `lba` is the first
+    /// LBA of the range it manages, which ties the block to its position in the BIT.
+    /// The AD is authenticated along with the block, so a valid ciphertext swapped or
+    /// relocated from elsewhere fails the verification.
+    pub fn associated_data(&self, lba: u64) -> Result<Vec<u8>> {
+        Self::build_associated_data(lba, self.hba, self.version)
+    }
+
+    /// Serialize the record in `layout`
+    pub fn serialize_with(&self, layout: RecordLayout) -> Result<Vec<u8>> {
+        let mut vec = Vec::try_with_capacity(layout.record_size())?;
         vec.try_extend_from_slice(&unsafe { core::mem::transmute::<u64, [u8; 8]>(self.hba) })?;
-        vec.try_extend_from_slice(&self.key)?;
+        vec.try_extend_from_slice(&self.key[..layout.key_len])?;
         vec.try_extend_from_slice(&self.nonce)?;
         vec.try_extend_from_slice(&self.mac)?;
-        vec.try_resize(SWORNDISK_RECORD_SIZE, 0u8)?;
+        vec.try_extend_from_slice(&unsafe { core::mem::transmute::<u64, [u8; 8]>(self.version) })?;
 
         Ok(vec)
     }
-}
 
-impl Deserialize for Record {
-    fn deserialize(buffer: &[u8]) -> Result<Self> {
-        if buffer.len() != SWORNDISK_RECORD_SIZE {
+    /// Deserialize a record in `layout`, the key is zeros if it is not stored
+    pub fn deserialize_with(buffer: &[u8], layout: RecordLayout) -> Result<Self> {
+        if buffer.len() < layout.record_size() {
             return Err(EINVAL);
         }
 
         let hba = unsafe { core::mem::transmute::<[u8; 8], u64>(buffer[0..8].try_into().unwrap()) };
         let key_index = 8;
-        let key = buffer[key_index..key_index + SWORNDISK_KEY_LENGTH]
-            .try_into()
-            .unwrap();
-        let nonce_index = key_index + SWORNDISK_KEY_LENGTH;
+        let mut key = [0; SWORNDISK_KEY_LENGTH];
+        key[..layout.key_len].copy_from_slice(&buffer[key_index..key_index + layout.key_len]);
+        let nonce_index = key_index + layout.key_len;
         let nonce = buffer[nonce_index..nonce_index + SWORNDISK_NONCE_LENGTH]
             .try_into()
             .unwrap();
@@ -61,12 +155,48 @@ impl Deserialize for Record {
         let mac = buffer[mac_index..mac_index + SWORNDISK_MAC_LENGTH]
             .try_into()
             .unwrap();
+        let version_index = mac_index + SWORNDISK_MAC_LENGTH;
+        let version = unsafe {
+            core::mem::transmute::<[u8; 8], u64>(
+                buffer[version_index..version_index + 8].try_into().unwrap(),
+            )
+        };
 
         Ok(Self {
             hba,
             key,
             nonce,
             mac,
+            version,
         })
     }
+
+    /// Build the associated data of a block before its record is created
+    pub fn build_associated_data(lba: u64, hba: u64, version: u64) -> Result<Vec<u8>> {
+        let mut vec = Vec::try_with_capacity(RECORD_AD_SIZE)?;
+        vec.try_extend_from_slice(&unsafe { core::mem::transmute::<u64, [u8; 8]>(lba) })?;
+        vec.try_extend_from_slice(&unsafe { core::mem::transmute::<u64, [u8; 8]>(hba) })?;
+        vec.try_extend_from_slice(&unsafe { core::mem::transmute::<u64, [u8; 8]>(version) })?;
+
+        Ok(vec)
+    }
+}
+
+impl Serialize for Record {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut vec = self.serialize_with(RecordLayout::default())?;
+        vec.try_resize(SWORNDISK_RECORD_SIZE, 0u8)?;
+
+        Ok(vec)
+    }
+}
+
+impl Deserialize for Record {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        if buffer.len() != SWORNDISK_RECORD_SIZE {
+            return Err(EINVAL);
+        }
+
+        Self::deserialize_with(buffer, RecordLayout::default())
+    }
 }
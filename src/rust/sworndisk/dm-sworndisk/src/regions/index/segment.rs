@@ -1,4 +1,5 @@
 use crate::{
+    kdf::{KeyDeriver, KeyRegion},
     prelude::*,
     regions::{Checkpoint, Record},
     utils::*,
@@ -9,51 +10,88 @@ use crypto::{get_random_bytes, Aead};
 #[derive(Debug)]
 pub struct IndexSegment {
     pub hba: u64,
+    /// bytes of index blocks written since the target is constructed
+    pub written: u64,
 }
 
 impl IndexSegment {
     pub fn new(hba: u64) -> Self {
-        Self { hba }
+        Self { hba, written: 0 }
     }
 
+    /// Write an index block which manages the LBA range beginning with `lba`
     pub fn write(
         &mut self,
         data: &[u8],
         len: usize,
+        lba: u64,
         aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
         client: &DmIoClient,
-        _checkpoint: &mut Checkpoint,
+        checkpoint: &mut Checkpoint,
         bdev: &BlockDevice,
     ) -> Result<Record> {
         let mut block = Vec::new();
         block.try_resize(BLOCK_SIZE as usize, 0u8)?;
         block[0..len].copy_from_slice(&data[0..len]);
 
-        let key = get_random_bytes(SWORNDISK_KEY_LENGTH)?;
+        self.write_extent(block, lba, aead, kdf, client, checkpoint, bdev)
+    }
+
+    /// Write `blocks` as an extent encrypted at once, e.g. the Bloom filter of a BIT.
+    /// The length of `blocks` should be a multiple of BLOCK_SIZE.
+    pub fn write_extent(
+        &mut self,
+        mut blocks: Vec<u8>,
+        lba: u64,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        client: &DmIoClient,
+        checkpoint: &mut Checkpoint,
+        bdev: &BlockDevice,
+    ) -> Result<Record> {
+        let len = blocks.len();
+        if len == 0 || len % BLOCK_SIZE as usize != 0 {
+            return Err(EINVAL);
+        }
+
+        let hba = self.hba;
+        let version = checkpoint.seq + 1;
+        let mut assoc = Record::build_associated_data(lba, hba, version)?;
+
+        let key = kdf.fresh_key()?;
+        let block_key = kdf.block_key(KeyRegion::Index, &key, hba, version)?;
         let mut nonce = get_random_bytes(SWORNDISK_NONCE_LENGTH)?;
         let mac = unsafe {
-            aead.as_ref()
-                .encrypt_in_place(&key, &mut nonce, &mut block[..], BLOCK_SIZE as usize)?
+            aead.as_ref().encrypt_in_place_with_ad(
+                &block_key,
+                &mut nonce,
+                &mut assoc[..],
+                &mut blocks[..],
+                len,
+            )?
         };
 
-        let hba = self.hba;
-        let mut region = DmIoRegion::new(&bdev, hba, BLOCK_SECTORS)?;
+        let sectors = len as u64 / SECTOR_SIZE;
+        let mut region = DmIoRegion::new(&bdev, hba, sectors)?;
         let mut io_req = DmIoRequest::with_kernel_memory(
             WRITE as i32,
             WRITE as i32,
-            block.as_mut_ptr() as *mut c_void,
+            blocks.as_mut_ptr() as *mut c_void,
             0,
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
 
-        self.hba += BLOCK_SECTORS;
+        self.hba += sectors;
+        self.written += len as u64;
 
         let record = Record {
             hba,
-            key: vec_to_slice::<{ SWORNDISK_KEY_LENGTH }>(&key)?,
+            key,
             nonce: vec_to_slice::<{ SWORNDISK_NONCE_LENGTH }>(&nonce)?,
             mac: vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)?,
+            version,
         };
 
         Ok(record)
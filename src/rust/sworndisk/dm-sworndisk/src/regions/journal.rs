@@ -0,0 +1,557 @@
+//! SwornDisk security journal
+//!
+//! The journal is an append-only log stored in the journal region of meta_dev, which is
+//! used circularly. Each on-disk journal block is laid out as:
+//!
+//! ```text
+//! | nonce | MAC | cipher (seq | prev_mac | payload length | journal records ...) |
+//! ```
+//!
+//! Every block is encrypted and authenticated with the journal key, and carries the
+//! sequence number of itself and the MAC of its predecessor. So the blocks form a chain
+//! and any reordering or removal in the middle of the journal will be detected. A removal
+//! at the tail is detected against the sequence number kept by the trusted counter, see
+//! `Journal::read_records`.
+
+use crate::{
+    cipher::CipherSuite,
+    prelude::*,
+    regions::{BITCategory, BITRootMeta, Extent, IndexSegment, RecordLayout, BIT_ROOT_META_SIZE},
+    types::{KeyType, MacType},
+    utils::*,
+};
+
+use crypto::{get_random_bytes, Aead};
+
+/// Size of the plain header of a journal block (nonce + MAC)
+const JOURNAL_BLOCK_HEADER_SIZE: usize = SWORNDISK_NONCE_LENGTH + SWORNDISK_MAC_LENGTH;
+
+/// Size of the encrypted part of a journal block
+const JOURNAL_BLOCK_CIPHER_SIZE: usize = BLOCK_SIZE as usize - JOURNAL_BLOCK_HEADER_SIZE;
+
+/// Size of the encrypted header of a journal block (seq + prev_mac + payload length)
+const JOURNAL_PAYLOAD_HEADER_SIZE: usize = 8 + SWORNDISK_MAC_LENGTH + 8;
+
+/// Max size of journal records a block can contain
+const JOURNAL_PAYLOAD_SIZE: usize = JOURNAL_BLOCK_CIPHER_SIZE - JOURNAL_PAYLOAD_HEADER_SIZE;
+
+/// Number of blocks kept for a checkpoint, which commits the pending records and then
+/// the checkpoint record
+const JOURNAL_CHECKPOINT_BLOCKS: u64 = 2;
+
+/// Size of the serialized JournalHead
+pub const JOURNAL_HEAD_SIZE: usize = SWORNDISK_KEY_LENGTH + 8 + 8 + SWORNDISK_MAC_LENGTH;
+
+/// Journal record types
+#[derive(Debug)]
+pub enum JournalRecord {
+    /// The data blocks of an extent are logged in the data segment
+    DataLog { extent: Extent },
+    /// The buffered blocks of a data segment are written to disk, all the preceding
+    /// `DataLog`s are committed. A segment may be committed several times as it fills up.
+    DataSegmentCommit { segment: u64 },
+    /// A BIT is created and added into `level`, `index_hba` is the next free block
+    /// of the index segment after the creation
+    BITCreation {
+        level: u64,
+        root_meta: BITRootMeta,
+        index_hba: u64,
+    },
+    /// BITs of `ids` in `level` are compacted, then removed from `level`
+    Compaction { level: u64, ids: Vec<u64> },
+    /// A checkpoint is written
+    Checkpoint { timestamp: u64 },
+    /// LBAs in `[begin, end)` are discarded, their records are replaced by tombstones
+    Discard { begin: u64, end: u64 },
+}
+
+const JOURNAL_DATA_LOG: u8 = 1;
+const JOURNAL_DATA_SEGMENT_COMMIT: u8 = 2;
+const JOURNAL_BIT_CREATION: u8 = 3;
+const JOURNAL_COMPACTION: u8 = 4;
+const JOURNAL_CHECKPOINT: u8 = 5;
+const JOURNAL_DISCARD: u8 = 6;
+
+impl Serialize for JournalRecord {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut vec = Vec::new();
+
+        match self {
+            JournalRecord::DataLog { extent } => {
+                vec.try_push(JOURNAL_DATA_LOG)?;
+                vec.try_extend_from_slice(&extent.serialize()?)?;
+            }
+            JournalRecord::DataSegmentCommit { segment } => {
+                vec.try_push(JOURNAL_DATA_SEGMENT_COMMIT)?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*segment) })?;
+            }
+            JournalRecord::BITCreation {
+                level,
+                root_meta,
+                index_hba,
+            } => {
+                vec.try_push(JOURNAL_BIT_CREATION)?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*level) })?;
+                vec.try_extend_from_slice(&root_meta.serialize()?)?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*index_hba) })?;
+            }
+            JournalRecord::Compaction { level, ids } => {
+                vec.try_push(JOURNAL_COMPACTION)?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*level) })?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(ids.len()) })?;
+                for id in ids.iter() {
+                    vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*id) })?;
+                }
+            }
+            JournalRecord::Checkpoint { timestamp } => {
+                vec.try_push(JOURNAL_CHECKPOINT)?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*timestamp) })?;
+            }
+            JournalRecord::Discard { begin, end } => {
+                vec.try_push(JOURNAL_DISCARD)?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*begin) })?;
+                vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(*end) })?;
+            }
+        }
+
+        Ok(vec)
+    }
+}
+
+impl JournalRecord {
+    /// Create a `BITCreation` record of the latest BIT in `level`
+    pub fn bit_creation(
+        bit_category: &BITCategory,
+        level: usize,
+        index_seg: &IndexSegment,
+    ) -> Result<Self> {
+        let root_meta = bit_category.last_bit(level).ok_or(EINVAL)?.clone();
+
+        Ok(JournalRecord::BITCreation {
+            level: level as u64,
+            root_meta,
+            index_hba: index_seg.hba,
+        })
+    }
+
+    /// Deserialize a journal record from the beginning of `buf`, returns the record and its length
+    fn deserialize_from(buf: &[u8]) -> Result<(Self, usize)> {
+        let read_u64 =
+            |index: usize| -> Result<u64> {
+                if index + 8 > buf.len() {
+                    return Err(EINVAL);
+                }
+                Ok(unsafe {
+                    mem::transmute::<[u8; 8], u64>(buf[index..index + 8].try_into().unwrap())
+                })
+            };
+
+        if buf.is_empty() {
+            return Err(EINVAL);
+        }
+
+        match buf[0] {
+            JOURNAL_DATA_LOG => {
+                // the extents are logged in the full layout, like the other records
+                let (extent, len) = Extent::deserialize_from(&buf[1..], RecordLayout::default())?;
+                Ok((JournalRecord::DataLog { extent }, 1 + len))
+            }
+            JOURNAL_DATA_SEGMENT_COMMIT => {
+                let segment = read_u64(1)?;
+                Ok((JournalRecord::DataSegmentCommit { segment }, 9))
+            }
+            JOURNAL_BIT_CREATION => {
+                let len = 1 + 8 + BIT_ROOT_META_SIZE + 8;
+                if buf.len() < len {
+                    return Err(EINVAL);
+                }
+                let level = read_u64(1)?;
+                let root_meta = BITRootMeta::deserialize(&buf[9..9 + BIT_ROOT_META_SIZE])?;
+                let index_hba = read_u64(9 + BIT_ROOT_META_SIZE)?;
+                Ok((
+                    JournalRecord::BITCreation {
+                        level,
+                        root_meta,
+                        index_hba,
+                    },
+                    len,
+                ))
+            }
+            JOURNAL_COMPACTION => {
+                let level = read_u64(1)?;
+                let count = read_u64(9)? as usize;
+                let mut ids = Vec::new();
+                for i in 0..count {
+                    ids.try_push(read_u64(17 + i * 8)?)?;
+                }
+                Ok((JournalRecord::Compaction { level, ids }, 17 + count * 8))
+            }
+            JOURNAL_CHECKPOINT => {
+                let timestamp = read_u64(1)?;
+                Ok((JournalRecord::Checkpoint { timestamp }, 9))
+            }
+            JOURNAL_DISCARD => {
+                let begin = read_u64(1)?;
+                let end = read_u64(9)?;
+                Ok((JournalRecord::Discard { begin, end }, 17))
+            }
+            _ => Err(EINVAL),
+        }
+    }
+}
+
+/// Position and chaining state of the journal, persisted in the checkpoint.
+/// Replaying the journal starts from this head.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct JournalHead {
+    /// Journal encryption key, the cipher suite takes its leading bytes
+    pub key: KeyType,
+    /// Index of the next block to write in the journal region
+    pub pos: u64,
+    /// Sequence number of the next block
+    pub seq: u64,
+    /// MAC of the last written block
+    pub prev_mac: MacType,
+}
+
+impl JournalHead {
+    /// Create the head of an empty journal with a random key
+    pub fn new() -> Result<Self> {
+        let key = get_random_bytes(SWORNDISK_KEY_LENGTH)?;
+
+        Ok(Self {
+            key: vec_to_slice::<{ SWORNDISK_KEY_LENGTH }>(&key)?,
+            pos: 0,
+            seq: 0,
+            prev_mac: [0; SWORNDISK_MAC_LENGTH],
+        })
+    }
+}
+
+impl Serialize for JournalHead {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut vec = Vec::new();
+        vec.try_extend_from_slice(&self.key)?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.pos) })?;
+        vec.try_extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.seq) })?;
+        vec.try_extend_from_slice(&self.prev_mac)?;
+        Ok(vec)
+    }
+}
+
+impl Deserialize for JournalHead {
+    fn deserialize(buf: &[u8]) -> Result<Self> {
+        if buf.len() != JOURNAL_HEAD_SIZE {
+            return Err(EINVAL);
+        }
+
+        let mut index = 0;
+        let key = buf[index..index + SWORNDISK_KEY_LENGTH].try_into().unwrap();
+        index += SWORNDISK_KEY_LENGTH;
+        let pos =
+            unsafe { mem::transmute::<[u8; 8], u64>(buf[index..index + 8].try_into().unwrap()) };
+        index += 8;
+        let seq =
+            unsafe { mem::transmute::<[u8; 8], u64>(buf[index..index + 8].try_into().unwrap()) };
+        index += 8;
+        let prev_mac = buf[index..index + SWORNDISK_MAC_LENGTH].try_into().unwrap();
+
+        Ok(Self {
+            key,
+            pos,
+            seq,
+            prev_mac,
+        })
+    }
+}
+
+/// SwornDisk security journal
+#[derive(Debug)]
+pub struct Journal {
+    /// Current head of the journal
+    head: JournalHead,
+    /// Begin sector of the journal region
+    region_hba: u64,
+    /// Number of blocks in the journal region
+    nr_blocks: u64,
+    /// Journal records pending to be written in the next block
+    buffer: Vec<u8>,
+    /// Number of blocks written since the last checkpoint
+    since_checkpoint: u64,
+    /// Cipher suite of the journal blocks
+    cipher: CipherSuite,
+}
+
+impl Journal {
+    /// Create a journal in the region of [region_hba, region_hba + region_nbytes) on meta_dev,
+    /// appending starts from `head`. The blocks are sealed with `cipher`.
+    pub fn new(
+        head: JournalHead,
+        region_hba: u64,
+        region_nbytes: u64,
+        cipher: CipherSuite,
+    ) -> Result<Self> {
+        let nr_blocks = region_nbytes / BLOCK_SIZE;
+        if nr_blocks <= JOURNAL_CHECKPOINT_BLOCKS {
+            return Err(EINVAL);
+        }
+
+        Ok(Self {
+            head,
+            region_hba,
+            nr_blocks,
+            buffer: Vec::try_with_capacity(JOURNAL_PAYLOAD_SIZE)?,
+            since_checkpoint: 0,
+            cipher,
+        })
+    }
+
+    /// Get current head of the journal
+    pub fn head(&self) -> JournalHead {
+        self.head
+    }
+
+    /// Check the journal should be truncated by a checkpoint, to avoid overwriting
+    /// the records which haven't been persisted by a checkpoint.
+    pub fn is_checkpoint_required(&self) -> bool {
+        self.since_checkpoint >= self.nr_blocks / 2
+    }
+
+    /// Append a record to the journal. The record is buffered until the block is full
+    /// or `commit` is called.
+    pub fn append(
+        &mut self,
+        record: &JournalRecord,
+        aead: &Pin<Box<Aead>>,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result {
+        let data = record.serialize()?;
+        if data.len() > JOURNAL_PAYLOAD_SIZE {
+            return Err(EINVAL);
+        }
+
+        if self.buffer.len() + data.len() > JOURNAL_PAYLOAD_SIZE {
+            self.commit(aead, bdev, client)?;
+        }
+
+        self.buffer.try_extend_from_slice(&data)?;
+        Ok(())
+    }
+
+    /// Write the buffered records to disk as a new journal block.
+    ///
+    /// Returns `ENOSPC` if the block would overwrite the records that no checkpoint covers,
+    /// the last `JOURNAL_CHECKPOINT_BLOCKS` blocks before them are kept for a checkpoint.
+    pub fn commit(
+        &mut self,
+        aead: &Pin<Box<Aead>>,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        if self.since_checkpoint + JOURNAL_CHECKPOINT_BLOCKS >= self.nr_blocks {
+            pr_err!(
+                "Journal is full: {} blocks written since the last checkpoint",
+                self.since_checkpoint
+            );
+            return Err(ENOSPC);
+        }
+
+        self.write_block(aead, bdev, client)
+    }
+
+    /// Write the buffered records to disk as the block at the head
+    fn write_block(
+        &mut self,
+        aead: &Pin<Box<Aead>>,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut block = Vec::new();
+        block.try_resize(BLOCK_SIZE as usize, 0u8)?;
+
+        let payload_len = self.buffer.len();
+        let mut index = JOURNAL_BLOCK_HEADER_SIZE;
+        block[index..index + 8]
+            .copy_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(self.head.seq) });
+        index += 8;
+        block[index..index + SWORNDISK_MAC_LENGTH].copy_from_slice(&self.head.prev_mac);
+        index += SWORNDISK_MAC_LENGTH;
+        block[index..index + 8]
+            .copy_from_slice(&unsafe { mem::transmute::<usize, [u8; 8]>(payload_len) });
+        index += 8;
+        block[index..index + payload_len].copy_from_slice(&self.buffer);
+
+        let key = self.cipher.key(&self.head.key)?;
+        let mut nonce = get_random_bytes(SWORNDISK_NONCE_LENGTH)?;
+        // SAFETY: Safe. The block is a local buffer and there is no concurrent access.
+        let mac = unsafe {
+            aead.as_ref().encrypt_in_place(
+                &key,
+                &mut nonce,
+                &mut block[JOURNAL_BLOCK_HEADER_SIZE..],
+                JOURNAL_BLOCK_CIPHER_SIZE,
+            )?
+        };
+        block[0..SWORNDISK_NONCE_LENGTH].copy_from_slice(&nonce);
+        block[SWORNDISK_NONCE_LENGTH..JOURNAL_BLOCK_HEADER_SIZE].copy_from_slice(&mac);
+
+        let hba = self.region_hba + self.head.pos * BLOCK_SECTORS;
+        let mut region = DmIoRegion::new(&bdev, hba, BLOCK_SECTORS)?;
+        let mut io_req = DmIoRequest::with_kernel_memory(
+            WRITE as i32,
+            WRITE as i32,
+            block.as_mut_ptr() as *mut c_void,
+            0,
+            client,
+        );
+        io_req.submit(&mut region)?;
+
+        self.head.prev_mac = vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)?;
+        self.head.seq += 1;
+        self.head.pos = (self.head.pos + 1) % self.nr_blocks;
+        self.since_checkpoint += 1;
+        self.buffer.clear();
+
+        Ok(())
+    }
+
+    /// Log a checkpoint event and commit the journal. Returns the head the checkpoint
+    /// should persist, records after it will be replayed on recovery.
+    pub fn checkpoint(
+        &mut self,
+        aead: &Pin<Box<Aead>>,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+    ) -> Result<JournalHead> {
+        // the blocks kept for the checkpoint are used here, so it never fails with ENOSPC
+        self.write_block(aead, bdev, client)?;
+        let record = JournalRecord::Checkpoint {
+            timestamp: current_timestamp(),
+        };
+        self.buffer.try_extend_from_slice(&record.serialize()?)?;
+        self.write_block(aead, bdev, client)?;
+        self.since_checkpoint = 0;
+
+        Ok(self.head)
+    }
+
+    /// Read the journal records after current head, following the chain until its end.
+    ///
+    /// A block that cannot be decrypted, or an older block left by the previous round
+    /// of the circular region, marks the end of the journal. A valid block that breaks
+    /// the chain means the journal is reordered, and EBADMSG is returned.
+    ///
+    /// Such an end can not be told from a truncation by the chain itself, so the journal
+    /// must reach `tail_seq`, the sequence number of the last durable block recorded in
+    /// a trusted place, otherwise EBADMSG is returned too.
+    ///
+    /// The head is moved to the end of journal, so appending continues after it.
+    pub fn read_records(
+        &mut self,
+        aead: &Pin<Box<Aead>>,
+        bdev: &BlockDevice,
+        client: &DmIoClient,
+        tail_seq: u64,
+    ) -> Result<Vec<JournalRecord>> {
+        let mut records = Vec::new();
+
+        for _ in 0..self.nr_blocks {
+            let mut block = Vec::new();
+            block.try_resize(BLOCK_SIZE as usize, 0u8)?;
+
+            let hba = self.region_hba + self.head.pos * BLOCK_SECTORS;
+            let mut region = DmIoRegion::new(&bdev, hba, BLOCK_SECTORS)?;
+            let mut io_req = DmIoRequest::with_kernel_memory(
+                READ as i32,
+                READ as i32,
+                block.as_mut_ptr() as *mut c_void,
+                0,
+                client,
+            );
+            io_req.submit(&mut region)?;
+
+            let key = self.cipher.key(&self.head.key)?;
+            let mut nonce = Vec::new();
+            nonce.try_extend_from_slice(&block[0..SWORNDISK_NONCE_LENGTH])?;
+            let mut mac = Vec::new();
+            mac.try_extend_from_slice(&block[SWORNDISK_NONCE_LENGTH..JOURNAL_BLOCK_HEADER_SIZE])?;
+
+            // SAFETY: Safe. The block is a local buffer and there is no concurrent access.
+            let decrypted = unsafe {
+                aead.as_ref().decrypt_in_place(
+                    &key,
+                    &mut mac,
+                    &mut nonce,
+                    &mut block[JOURNAL_BLOCK_HEADER_SIZE..],
+                    JOURNAL_BLOCK_CIPHER_SIZE,
+                )
+            };
+            if decrypted.is_err() {
+                break;
+            }
+
+            let mut index = JOURNAL_BLOCK_HEADER_SIZE;
+            let seq = unsafe {
+                mem::transmute::<[u8; 8], u64>(block[index..index + 8].try_into().unwrap())
+            };
+            index += 8;
+            let prev_mac = &block[index..index + SWORNDISK_MAC_LENGTH];
+            index += SWORNDISK_MAC_LENGTH;
+            let payload_len = unsafe {
+                mem::transmute::<[u8; 8], usize>(block[index..index + 8].try_into().unwrap())
+            };
+            index += 8;
+
+            // a block written in the previous round
+            if seq < self.head.seq {
+                break;
+            }
+
+            if seq > self.head.seq || prev_mac != self.head.prev_mac {
+                pr_err!(
+                    "Journal chain is broken at block {}, seq {}",
+                    self.head.pos,
+                    seq
+                );
+                return Err(EBADMSG);
+            }
+
+            if payload_len > JOURNAL_PAYLOAD_SIZE {
+                return Err(EBADMSG);
+            }
+
+            let mut offset = 0;
+            let payload = &block[index..index + payload_len];
+            while offset < payload_len {
+                let (record, len) = JournalRecord::deserialize_from(&payload[offset..])?;
+                records.try_push(record)?;
+                offset += len;
+            }
+
+            self.head.prev_mac = vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)?;
+            self.head.seq += 1;
+            self.head.pos = (self.head.pos + 1) % self.nr_blocks;
+            self.since_checkpoint += 1;
+        }
+
+        if self.head.seq < tail_seq {
+            pr_err!(
+                "Journal is truncated: it ends before block {}, but block {} is committed",
+                self.head.seq,
+                tail_seq - 1
+            );
+            return Err(EBADMSG);
+        }
+
+        Ok(records)
+    }
+}
@@ -3,9 +3,11 @@
 pub mod checkpoint;
 pub mod data;
 pub mod index;
+pub mod journal;
 pub mod superblock;
 
 pub use checkpoint::*;
 pub use data::*;
 pub use index::*;
+pub use journal::*;
 pub use superblock::*;
@@ -1,4 +1,13 @@
-use crate::prelude::*;
+use crate::{
+    cipher::{CipherSuite, DataMode},
+    kdf::KeyScheme,
+    prelude::*,
+    regions::JOURNAL_HEAD_SIZE,
+    types::MacType,
+    utils::vec_to_slice,
+};
+
+use crypto::keyring::Secret;
 
 /// SwornDisk superblock. For robustness, there are two copies of superblock,
 /// located in block index 0 and 1.
@@ -11,6 +20,9 @@ pub struct SuperBlock {
     pub checksum: u64,
     /// Magic number
     pub magic_number: u64,
+    /// version of the on-disk format, see `SWORNDISK_FORMAT_VERSION`. It follows the magic
+    /// number, so it is found in the superblock of any version.
+    pub format_version: u64,
 
     /// number of blocks in data regions
     pub nr_blocks: u64,
@@ -31,6 +43,18 @@ pub struct SuperBlock {
     pub journal_region: u64,
     /// offset of the checkpoint region (byte)
     pub checkpoint_region: u64,
+    /// size of a checkpoint slot, there are 2 slots in checkpoint region (unit: Byte)
+    pub checkpoint_size: u64,
+
+    /// how the blocks get their keys, see `KeyScheme`
+    pub key_scheme: u64,
+    /// AEAD cipher suite of the blocks, journal and checkpoint, see `CipherSuite`
+    pub cipher: u64,
+    /// how the data blocks are sealed, see `DataMode`
+    pub data_mode: u64,
+
+    /// key-check value of the root key, see `SuperBlock::key_check_value()`
+    pub key_check: MacType,
 }
 
 /// The size of SuperBlock struct
@@ -38,7 +62,16 @@ pub const SWORNDISK_SUPERBLOCK_SIZE: usize = mem::size_of::<SuperBlock>();
 
 impl SuperBlock {
     /// Create a new SuperBlock
-    pub fn new(data_nbytes: u64, index_nbytes: u64, journal_nbytes: u64) -> Self {
+    pub fn new(
+        data_nbytes: u64,
+        index_nbytes: u64,
+        journal_nbytes: u64,
+        meta_nbytes: u64,
+        key_check: MacType,
+        key_scheme: KeyScheme,
+        cipher: CipherSuite,
+        data_mode: DataMode,
+    ) -> Self {
         // floor the block number and segment number
         let nr_blocks = data_nbytes / BLOCK_SIZE;
         let nr_data_segments = nr_blocks / SEGMENT_BLOCK_NUMBER;
@@ -48,6 +81,8 @@ impl SuperBlock {
         let index_region = SEGMENT_SIZE;
         let journal_region = index_region + nr_index_segments * SEGMENT_SIZE;
         let checkpoint_region = journal_region + journal_nbytes;
+        let checkpoint_size =
+            meta_nbytes.saturating_sub(checkpoint_region) / 2 / SECTOR_SIZE * SECTOR_SIZE;
 
         let mut superblock = SuperBlock {
             nr_blocks,
@@ -56,9 +91,15 @@ impl SuperBlock {
             index_region,
             journal_region,
             checkpoint_region,
+            checkpoint_size,
+            key_check,
 
+            key_scheme: key_scheme.as_u64(),
+            cipher: cipher.as_u64(),
+            data_mode: data_mode.as_u64(),
             checksum: 0,
             magic_number: SWORNDISK_MAGIC_NUMBER,
+            format_version: SWORNDISK_FORMAT_VERSION,
             block_size: BLOCK_SIZE,
             segment_size: SEGMENT_SIZE,
             journal_size: journal_nbytes,
@@ -88,8 +129,52 @@ impl SuperBlock {
         }
     }
 
-    /// Read the superblock from disk
-    pub fn read_from_disk(block_manager: &DmBlockManager) -> Result<Self> {
+    /// Calculate the key-check value of `root_key`, which is the MAC of an all-zero block
+    /// of the key length encrypted with `root_key` and an all-zero nonce.
+    ///
+    /// The value reveals nothing about the key, but tells whether a key is the one
+    /// that the SwornDisk was formatted with.
+    pub fn key_check_value(aead: &Pin<Box<Aead>>, root_key: &[u8]) -> Result<MacType> {
+        let key = Secret::from_slice(root_key)?;
+        let mut nonce = Vec::new();
+        nonce.try_resize(SWORNDISK_NONCE_LENGTH, 0u8)?;
+        let mut block = Vec::new();
+        block.try_resize(root_key.len(), 0u8)?;
+
+        // SAFETY: Safe. `block` is a local buffer and there is no concurrent access.
+        let mac = unsafe {
+            aead.as_ref()
+                .encrypt_in_place(&key, &mut nonce, &mut block[..], root_key.len())?
+        };
+
+        vec_to_slice::<{ SWORNDISK_MAC_LENGTH }>(&mac)
+    }
+
+    /// Check whether `root_key` is the key that the SwornDisk was formatted with
+    pub fn verify_key(&self, aead: &Pin<Box<Aead>>, root_key: &[u8]) -> Result<bool> {
+        Ok(Self::key_check_value(aead, root_key)? == self.key_check)
+    }
+
+    /// Check the superblock which has the magic number is of `SWORNDISK_FORMAT_VERSION`,
+    /// the layout of its other fields is only known then
+    fn check_format(&self) -> Result {
+        if self.magic_number == SWORNDISK_MAGIC_NUMBER
+            && self.format_version != SWORNDISK_FORMAT_VERSION
+        {
+            pr_err!(
+                "SwornDisk on-disk format version {} is not supported, expected {}.",
+                self.format_version,
+                SWORNDISK_FORMAT_VERSION
+            );
+            return Err(EINVAL);
+        }
+
+        Ok(())
+    }
+
+    /// Read the superblock from disk. Returns `None` if neither copy is valid, or
+    /// `EINVAL` if the disk is of another on-disk format.
+    pub fn read_from_disk(block_manager: &DmBlockManager) -> Result<Option<Self>> {
         let block = block_manager.read_lock(SWORNDISK_FIRST_SUPERBLOCK_LOCATION, None)?;
         // SAFETY: We will verify the block is a valid block or not after.
         let data = unsafe { block.data::<SuperBlock>() };
@@ -98,11 +183,12 @@ impl SuperBlock {
         }
 
         // SAFETY: We can guarantee that `data` is non-null, but is not always valid.
-        // So we validate its integrity first. And if the data passes the validation,
-        // we can guarantee that it has a proper memory alignment.
+        // So we check its format and validate its integrity first. And if the data passes
+        // the validation, we can guarantee that it has a proper memory alignment.
         unsafe {
+            (*data).check_format()?;
             if (*data).validate() {
-                return Ok(data.read());
+                return Ok(Some(data.read()));
             }
         };
 
@@ -111,13 +197,14 @@ impl SuperBlock {
         // SAFETY: We will verify the block is a valid block or not after.
         let data = unsafe { block.data::<SuperBlock>() };
         unsafe {
+            (*data).check_format()?;
             if (*data).validate() {
                 // TODO: fix the first superblock @kirainmoe
-                return Ok(data.read());
+                return Ok(Some(data.read()));
             }
         };
 
-        Err(EINVAL)
+        Ok(None)
     }
 
     /// Write the superblock to disk
@@ -140,6 +227,21 @@ impl SuperBlock {
         Ok(())
     }
 
+    /// Get the key scheme of the blocks
+    pub fn key_scheme(&self) -> Result<KeyScheme> {
+        KeyScheme::from_u64(self.key_scheme)
+    }
+
+    /// Get the cipher suite
+    pub fn cipher(&self) -> Result<CipherSuite> {
+        CipherSuite::from_u64(self.cipher)
+    }
+
+    /// Get the mode of the data blocks
+    pub fn data_mode(&self) -> Result<DataMode> {
+        DataMode::from_u64(self.data_mode)
+    }
+
     /// Get the number of data segments
     pub fn data_segments_number(&self) -> u64 {
         self.nr_data_segments
@@ -149,6 +251,16 @@ impl SuperBlock {
     pub fn index_segments_number(&self) -> u64 {
         self.nr_index_segments
     }
+
+    /// Get the begin sector of checkpoint region
+    pub fn checkpoint_hba(&self) -> u64 {
+        self.checkpoint_region / SECTOR_SIZE
+    }
+
+    /// Get the sector number of a checkpoint slot
+    pub fn checkpoint_slot_sectors(&self) -> u64 {
+        self.checkpoint_size / SECTOR_SIZE
+    }
 }
 
 impl SuperBlock {
@@ -166,8 +278,17 @@ impl SuperBlock {
         }; // byte
 
         let dst_size = nr_data_segments * SEGMENT_BLOCK_NUMBER / 8; // byte
+        let rit_size = nr_data_segments * SEGMENT_BLOCK_NUMBER * 8; // byte
+        let version_size = nr_data_segments * SEGMENT_BLOCK_NUMBER * 8; // byte
+        let journal_head_size = JOURNAL_HEAD_SIZE as u64;
         let params_size = 8;
 
-        data_svt_size + index_svt_size + dst_size + params_size
+        data_svt_size
+            + index_svt_size
+            + dst_size
+            + rit_size
+            + version_size
+            + journal_head_size
+            + params_size
     }
 }
@@ -0,0 +1,72 @@
+//! Runtime statistics of SwornDisk, reported by `dmsetup status`
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::prelude::*;
+
+/// Counters accumulated since the target is constructed.
+///
+/// The counters are updated by the workers with or without the context lock held,
+/// so they are atomic and only give a consistent view of each counter alone.
+#[derive(Debug, Default)]
+pub struct Statistics {
+    /// bytes of data written by the write requests of users
+    foreground_bytes: AtomicU64,
+    /// bytes of live data moved by segment cleaning
+    gc_bytes: AtomicU64,
+    /// bytes of index blocks written by major compaction
+    compaction_bytes: AtomicU64,
+    /// bytes of data verified by scrubbing
+    scrubbed_bytes: AtomicU64,
+    /// number of I/O requests (and scrubbed blocks) failing the integrity check
+    integrity_failures: AtomicU64,
+}
+
+impl Statistics {
+    /// Count `bytes` written by a write request
+    pub fn add_foreground_bytes(&self, bytes: u64) {
+        self.foreground_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` moved by segment cleaning
+    pub fn add_gc_bytes(&self, bytes: u64) {
+        self.gc_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` written by major compaction
+    pub fn add_compaction_bytes(&self, bytes: u64) {
+        self.compaction_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` verified by scrubbing
+    pub fn add_scrubbed_bytes(&self, bytes: u64) {
+        self.scrubbed_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count an I/O request failing with `err`, only integrity failures are counted
+    pub fn add_failure(&self, err: Error) {
+        if err == EBADMSG {
+            self.integrity_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn foreground_bytes(&self) -> u64 {
+        self.foreground_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn gc_bytes(&self) -> u64 {
+        self.gc_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn compaction_bytes(&self) -> u64 {
+        self.compaction_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn scrubbed_bytes(&self) -> u64 {
+        self.scrubbed_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn integrity_failures(&self) -> u64 {
+        self.integrity_failures.load(Ordering::Relaxed)
+    }
+}
@@ -1,13 +1,19 @@
-use crate::{BLOCK_SIZE, SWORNDISK_KEY_LENGTH, SWORNDISK_MAC_LENGTH, SWORNDISK_NONCE_LENGTH};
+use crate::{
+    BLOCK_SIZE, SWORNDISK_HASH_LENGTH, SWORNDISK_KEY_LENGTH, SWORNDISK_MAC_LENGTH,
+    SWORNDISK_NONCE_LENGTH,
+};
 
-/// AES-128-GCM key type
+/// AEAD key type, a shorter key takes the leading bytes (see `CipherSuite::key_len`)
 pub type KeyType = [u8; SWORNDISK_KEY_LENGTH];
 
-/// AES-128-GCM iv (nonce) type
+/// AEAD iv (nonce) type
 pub type NonceType = [u8; SWORNDISK_NONCE_LENGTH];
 
-/// AES-128-GCM mac (tag) type
+/// AEAD mac (tag) type
 pub type MacType = [u8; SWORNDISK_MAC_LENGTH];
 
+/// SHA-256 digest type
+pub type HashType = [u8; SWORNDISK_HASH_LENGTH];
+
 /// Block slice type
 pub type BlockType = [u8; BLOCK_SIZE as usize];
@@ -2,7 +2,16 @@
 ///
 /// Since the kernel module workspace does not support `cargo test` command to run
 /// a unit test, we separate a indepenedent module to write and run unit tests.
-use crate::{prelude::*, utils::*};
+use core::sync::atomic::AtomicU64;
+
+use crate::{
+    cipher::{CipherSuite, DataMode},
+    counter::{CounterSlot, MemoryCounter, TrustedCounter},
+    kdf::{KeyDeriver, KeyRegion, KeyScheme},
+    prelude::*,
+    regions::{BloomFilter, Extent, MemTable, Record, RecordLayout, DST},
+    utils::*,
+};
 
 // test utils::get_lba_range()
 fn test_get_lba_range() {
@@ -33,12 +42,215 @@ fn test_bitmap() {
     assert_eq!(bitmap.get_bit(8).unwrap(), true);
 }
 
+// test counter::MemoryCounter
+fn test_memory_counter() {
+    static CELL: AtomicU64 = AtomicU64::new(0);
+    let mut counter = MemoryCounter::new(&CELL);
+
+    assert_eq!(counter.read().unwrap(), 0);
+    counter.advance(1).unwrap();
+    counter.advance(1).unwrap();
+    assert_eq!(counter.read().unwrap(), 1);
+    counter.advance(5).unwrap();
+    assert_eq!(counter.read().unwrap(), 5);
+    assert!(counter.advance(4).is_err());
+    assert_eq!(counter.read().unwrap(), 5);
+
+    // a counter sharing the same cell sees the same value
+    let mut other = MemoryCounter::new(&CELL);
+    assert_eq!(other.read().unwrap(), 5);
+
+    // only a newly formatted SwornDisk sets the counter back
+    other.init(0).unwrap();
+    assert_eq!(counter.read().unwrap(), 0);
+
+    // each device has its own counter, the slots of the module are left untouched
+    static SLOTS: [CounterSlot; 2] = [CounterSlot::new(), CounterSlot::new()];
+    let mut first = MemoryCounter::of_device_in(&SLOTS, 0x800001).unwrap();
+    let mut second = MemoryCounter::of_device_in(&SLOTS, 0x800002).unwrap();
+    first.advance(3).unwrap();
+    assert_eq!(second.read().unwrap(), 0);
+    let mut again = MemoryCounter::of_device_in(&SLOTS, 0x800001).unwrap();
+    assert_eq!(again.read().unwrap(), 3);
+    assert!(MemoryCounter::of_device_in(&SLOTS, 0x800003).is_err());
+}
+
+// test regions::DST, blocks are appended after the tail
+fn test_dst_tail() {
+    let mut dst = DST::new().unwrap();
+
+    assert_eq!(dst.alloc_block(10).unwrap(), 0);
+    assert_eq!(dst.alloc_block(11).unwrap(), 1);
+    dst.clear_block(0).unwrap();
+    // a cleared block before the tail is never reused
+    assert_eq!(dst.alloc_block(12).unwrap(), 2);
+    assert_eq!(dst.tail(), 3);
+    assert_eq!(dst.valid_blocks(), 2);
+
+    // the tail survives serialization
+    let dst = DST::deserialize(&dst.serialize().unwrap()).unwrap();
+    assert_eq!(dst.tail(), 3);
+    assert_eq!(dst.lba(2), 12);
+
+    let mut dst = dst;
+    dst.set_version(2, 7);
+    dst.set_block(SEGMENT_BLOCK_NUMBER as usize - 1, 13, 8)
+        .unwrap();
+    // the versions survive serialization
+    let mut dst = DST::deserialize(&dst.serialize().unwrap()).unwrap();
+    assert_eq!(dst.version(2), 7);
+    assert_eq!(dst.version(SEGMENT_BLOCK_NUMBER as usize - 1), 8);
+    assert_eq!(dst.is_exhausted(), true);
+    assert!(dst.alloc_block(14).is_err());
+}
+
+// test regions::MemTable, an overwritten extent is split and keeps its records
+fn test_memtable_extent() {
+    let mut memtable = MemTable::new();
+    let mut extent = Extent::new(0, 800, [0; SWORNDISK_KEY_LENGTH], 1).unwrap();
+    for i in 0..8u8 {
+        extent.push_block([i; SWORNDISK_MAC_LENGTH]).unwrap();
+    }
+    let nonce = extent.nonce_of(5);
+    assert!(memtable.insert(extent).unwrap().is_empty());
+
+    let replaced = memtable.insert(Extent::tombstone(2, 2)).unwrap();
+    assert_eq!(replaced.len(), 1);
+    assert_eq!((replaced[0].lba, replaced[0].len), (2, 2));
+    assert_eq!(memtable.size(), 3);
+    assert_eq!(memtable.blocks(), 6);
+
+    assert_eq!(memtable.find(1).unwrap().hba, 800 + BLOCK_SECTORS);
+    assert_eq!(memtable.find(3).unwrap().is_tombstone(), true);
+    let record = memtable.find(5).unwrap();
+    assert_eq!(record.hba, 800 + 5 * BLOCK_SECTORS);
+    assert_eq!(record.nonce, nonce);
+    assert_eq!(record.mac, [5; SWORNDISK_MAC_LENGTH]);
+    assert!(memtable.find(8).is_none());
+
+    // an extent crossing a chunk boundary is split at it
+    memtable
+        .insert(Extent::tombstone(EXTENT_MAX_BLOCKS - 1, 2))
+        .unwrap();
+    assert_eq!(memtable.size(), 5);
+}
+
+// test kdf::KeyDeriver and regions::RecordLayout, a derived key is not stored
+fn test_derived_keys() {
+    let cipher = CipherSuite::Aes256Gcm;
+    let kdf = KeyDeriver::new(KeyScheme::Derived, cipher, &[1; SWORNDISK_KEY_LENGTH]).unwrap();
+    let layout = kdf.layout();
+    assert_eq!(layout.key_len, 0);
+    assert!(layout.indirect_block_children() > RecordLayout::default().indirect_block_children());
+    assert_eq!(
+        RecordLayout::of(KeyScheme::Random, CipherSuite::Aes128Gcm).key_len,
+        16
+    );
+
+    let key = kdf.fresh_key().unwrap();
+    let block_key = kdf.block_key(KeyRegion::Data, &key, 8, 1).unwrap();
+    assert_eq!(block_key.len(), cipher.key_len());
+    assert_ne!(block_key[..], key[..]);
+    assert_eq!(
+        kdf.block_key(KeyRegion::Data, &key, 8, 1).unwrap(),
+        block_key
+    );
+    assert_ne!(
+        kdf.block_key(KeyRegion::Data, &key, 16, 1).unwrap(),
+        block_key
+    );
+    assert_ne!(
+        kdf.block_key(KeyRegion::Data, &key, 8, 2).unwrap(),
+        block_key
+    );
+    assert_ne!(
+        kdf.block_key(KeyRegion::Index, &key, 8, 1).unwrap(),
+        block_key
+    );
+
+    let mut extent = Extent::new(0, 8, key, 1).unwrap();
+    extent.push_block([7; SWORNDISK_MAC_LENGTH]).unwrap();
+    let buf = extent.serialize_with(layout).unwrap();
+    assert_eq!(buf.len(), extent.disk_size(layout));
+    let (extent, len) = Extent::deserialize_from(&buf, layout).unwrap();
+    assert_eq!(len, buf.len());
+    assert_eq!(kdf.data_key(&extent.record(0)).unwrap(), block_key);
+}
+
+// test cipher::DataMode, an integrity-only block is kept in plaintext but still verified
+fn test_integrity_mode() {
+    let cipher = CipherSuite::default();
+    let aead = cipher.aead().unwrap();
+    let key = cipher.key(&cipher.random_key().unwrap()).unwrap();
+    let nonce = [3; SWORNDISK_NONCE_LENGTH];
+    let mut assoc = Record::build_associated_data(5, 40, 1).unwrap();
+    let mut block = Vec::new();
+    block.try_resize(BLOCK_SIZE as usize, 0x5au8).unwrap();
+
+    let mode = DataMode::Integrity;
+    let mac = mode
+        .seal(
+            &aead,
+            &key,
+            &mut slice_to_vec(&nonce).unwrap(),
+            &mut assoc[..],
+            &mut block[..],
+        )
+        .unwrap();
+    assert!(block.iter().all(|byte| *byte == 0x5a));
+
+    let mut open = |block: &mut [u8]| {
+        let mut mac_copy = Vec::new();
+        mac_copy.try_extend_from_slice(&mac)?;
+        mode.open(
+            &aead,
+            &key,
+            &mut mac_copy,
+            &mut slice_to_vec(&nonce)?,
+            &mut assoc[..],
+            block,
+        )
+    };
+    assert!(open(&mut block[..]).is_ok());
+    block[7] ^= 1;
+    assert_eq!(open(&mut block[..]).unwrap_err(), EBADMSG);
+}
+
+// test regions::BloomFilter, an inserted LBA or chunk is never rejected
+fn test_bloom_filter() {
+    let empty = BloomFilter::new(0).unwrap();
+    assert_eq!(empty.may_contain(100), false);
+
+    let mut filter = BloomFilter::new(64).unwrap();
+    let mut extent = Extent::new(100, 0, [0; SWORNDISK_KEY_LENGTH], 1).unwrap();
+    for _ in 0..16 {
+        extent.push_block([0; SWORNDISK_MAC_LENGTH]).unwrap();
+    }
+    filter.insert_extent(&extent);
+    for lba in 100..116 {
+        assert!(filter.may_contain(lba));
+    }
+
+    // a tombstone adds its whole chunk
+    let chunk = EXTENT_MAX_BLOCKS * 10;
+    filter.insert_extent(&Extent::tombstone(chunk + 3, 2));
+    for lba in chunk..chunk + EXTENT_MAX_BLOCKS {
+        assert!(filter.may_contain(lba));
+    }
+}
+
 /// Run all unit tests
 pub fn run_all_test() {
     pr_warn!("[TEST] Running SwornDisk kernel module unit tests");
 
     test_get_lba_range();
     test_bitmap();
+    test_memory_counter();
+    test_dst_tail();
+    test_memtable_extent();
+    test_derived_keys();
+    test_integrity_mode();
+    test_bloom_filter();
 
     pr_warn!("[TEST] All unit tests are passed. Continue to load SwornDisk module.");
 }
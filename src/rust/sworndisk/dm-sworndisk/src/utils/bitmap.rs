@@ -44,6 +44,11 @@ impl BitMap {
             return Err(EINVAL);
         }
 
+        // the bit is already set, nothing to do
+        if self.get_bit(index)? {
+            return Ok(());
+        }
+
         if self.avail_len <= 0 {
             return Err(ENOSPC);
         }
@@ -74,6 +79,11 @@ impl BitMap {
             return Err(EINVAL);
         }
 
+        // the bit is already cleared, nothing to do
+        if !self.get_bit(index)? {
+            return Ok(());
+        }
+
         if self.is_seq && index != self.max_len - self.avail_len - 1 {
             self.is_seq = false;
         }
@@ -150,6 +160,11 @@ impl BitMap {
     pub fn len(&self) -> usize {
         self.max_len
     }
+
+    /// Get the number of zero (unused) bits
+    pub fn available(&self) -> usize {
+        self.avail_len
+    }
 }
 
 impl Serialize for BitMap {
@@ -51,6 +51,10 @@ where
     capacity: usize,
     head: *mut LruEntry<K, V>,
     tail: *mut LruEntry<K, V>,
+    /// number of lookups that found the key
+    hits: u64,
+    /// number of lookups that missed the key
+    misses: u64,
 }
 
 impl<K: Ord + Copy + Debug, V: Debug> Debug for LruCache<K, V> {
@@ -58,6 +62,8 @@ impl<K: Ord + Copy + Debug, V: Debug> Debug for LruCache<K, V> {
         f.debug_struct("LruCache")
             .field("len", &self.len)
             .field("capacity", &self.capacity)
+            .field("hits", &self.hits)
+            .field("misses", &self.misses)
             .finish()
     }
 }
@@ -72,6 +78,8 @@ impl<K: Ord + Copy + Debug, V: Debug> LruCache<K, V> {
             // sentinel node
             head: Box::into_raw(Box::try_new(LruEntry::new_uninit())?),
             tail: Box::into_raw(Box::try_new(LruEntry::new_uninit())?),
+            hits: 0,
+            misses: 0,
         };
 
         // SAFETY: Safe. `cache.head` and `cache.tail` is non-null.
@@ -96,6 +104,7 @@ impl<K: Ord + Copy + Debug, V: Debug> LruCache<K, V> {
                 };
                 let node_ptr: *mut LruEntry<K, V> = &mut *old_node;
                 self.detach(node_ptr);
+                self.len -= 1;
                 unsafe { Some(old_node.val.assume_init()) }
             }
         }
@@ -106,8 +115,10 @@ impl<K: Ord + Copy + Debug, V: Debug> LruCache<K, V> {
             let node_ptr: *mut LruEntry<K, V> = &mut **node;
             self.detach(node_ptr);
             self.attach(node_ptr);
+            self.hits += 1;
             Some(unsafe { &(*(*node_ptr).val.as_ptr()) as &V })
         } else {
+            self.misses += 1;
             None
         }
     }
@@ -117,12 +128,23 @@ impl<K: Ord + Copy + Debug, V: Debug> LruCache<K, V> {
             let node_ptr: *mut LruEntry<K, V> = &mut **node;
             self.detach(node_ptr);
             self.attach(node_ptr);
+            self.hits += 1;
             Some(unsafe { &mut (*(*node_ptr).val.as_mut_ptr()) as &mut V })
         } else {
+            self.misses += 1;
             None
         }
     }
 
+    /// Remove all the entries, the hit and miss counters are kept
+    pub fn clear(&mut self) {
+        // SAFETY: Safe. The entries between the sentinels are initialized.
+        while unsafe { (*self.head).next } != self.tail {
+            let key = unsafe { *(*(*self.tail).prev).key.as_ptr() };
+            self.pop(&key);
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -135,6 +157,16 @@ impl<K: Ord + Copy + Debug, V: Debug> LruCache<K, V> {
         self.capacity
     }
 
+    /// Number of `get` and `get_mut` that found the key
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of `get` and `get_mut` that missed the key
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
     fn do_put(&mut self, key: K, mut val: V) -> Result<Option<(K, V)>> {
         let node_ref = self.map.get_mut(&key);
         match node_ref {
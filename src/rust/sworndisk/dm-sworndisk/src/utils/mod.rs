@@ -75,3 +75,40 @@ pub fn slice_to_vec<const N: usize>(slice: &[u8; N]) -> Result<Vec<u8>> {
 
     Ok(vec)
 }
+
+/// Decode a hex string to a byte array of length `N`
+pub fn hex_to_slice<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != N * 2 {
+        return Err(EINVAL);
+    }
+
+    let digit = |c: u8| -> Result<u8> {
+        match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(EINVAL),
+        }
+    };
+
+    let mut slice = [0; N];
+    for i in 0..N {
+        slice[i] = digit(hex[i * 2])? << 4 | digit(hex[i * 2 + 1])?;
+    }
+
+    Ok(slice)
+}
+
+/// Display a byte slice as a hex string
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
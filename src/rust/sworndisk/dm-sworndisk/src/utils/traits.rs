@@ -11,3 +11,11 @@ pub trait Deserialize {
     where
         Self: Sized;
 }
+
+/// TryClone trait: clone a struct holding heap memory, which fails instead of panicking
+/// when the allocation fails
+pub trait TryClone {
+    fn try_clone(&self) -> Result<Self>
+    where
+        Self: Sized;
+}
@@ -1,35 +1,67 @@
-use crate::{context::CONTEXT as context, prelude::*, regions::BIT};
+use crate::{
+    context::SwornDiskContext,
+    prelude::*,
+    regions::{JournalRecord, BIT},
+};
+
+use core::ops::Range;
 
 /// SwornDisk Compaction implementation
 pub struct CompactionWorker;
 
 impl WorkFuncTrait for CompactionWorker {
-    fn work(_work_struct: *mut bindings::work_struct) -> Result {
-        let ctx = unsafe { context.as_mut().unwrap() };
+    fn work(work_struct: *mut bindings::work_struct) -> Result {
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+        let ctx = unsafe { SwornDiskContext::of_work(work_struct)? };
+
+        pr_info!("Triggered major compaction...");
+
+        Self::compact(ctx, 0..LSM_TREE_MAX_LEVEL - 1, MAX_COMPACTION_NUMBER)?;
+
+        Ok(())
+    }
+}
+
+impl CompactionWorker {
+    /// Compact each level in `levels` holding at least `threshold` BITs into the next level,
+    /// return the number of compacted levels. The caller should not hold the lock.
+    pub fn compact(
+        ctx: &mut SwornDiskContext,
+        levels: Range<usize>,
+        threshold: usize,
+    ) -> Result<usize> {
+        let lock = ctx.rw_lock();
 
         let aead = &ctx.aead;
+        let kdf = &ctx.kdf;
         let client = &ctx.dm_io_client;
         let checkpoint = &mut ctx.checkpoint;
         let index_seg = &mut ctx.index_seg;
+        let journal = &mut ctx.journal;
         let meta_dev = &mut ctx.meta_dev;
         let meta_bdev = &meta_dev.block_device()?;
         let indirect_block_cache = &mut ctx.indirect_block_cache;
+        let stats = &ctx.stats;
 
         let mut bits_pending_compaction = Vec::new();
         let mut bits_id = Vec::new();
+        let mut compacted = 0;
 
-        pr_info!("Triggered major compaction...");
-
-        for level in 0..LSM_TREE_MAX_LEVEL - 1 {
+        for level in levels {
             // TODO: refactor lock, write lock should only be acquired when write the BIT
-            let _lock = ctx.lock.write();
+            let _lock = lock.write();
 
             let size = checkpoint.bit_category.level_size(level);
-            if size >= MAX_COMPACTION_NUMBER {
+            if size > 0 && size >= threshold {
                 for i in 0..size {
                     let root_meta = checkpoint.bit_category.get_bit(level, i).unwrap();
-                    let root =
-                        root_meta.read_from_disk(aead, meta_bdev, client, indirect_block_cache)?;
+                    let root = root_meta.read_from_disk(
+                        aead,
+                        kdf,
+                        meta_bdev,
+                        client,
+                        indirect_block_cache,
+                    )?;
                     bits_pending_compaction.try_push(root)?;
                     bits_id.try_push(root_meta.unique_id)?;
                 }
@@ -39,15 +71,23 @@ impl WorkFuncTrait for CompactionWorker {
                 continue;
             }
 
+            // the tombstones hide nothing if no BIT is older than the compacted ones
+            let drop_tombstones = (level + 1..LSM_TREE_MAX_LEVEL)
+                .all(|deeper| checkpoint.bit_category.level_size(deeper) == 0);
+
+            let written = index_seg.written;
             let bit = BIT::from_compaction(
                 &bits_pending_compaction,
+                drop_tombstones,
                 aead,
+                kdf,
                 client,
                 checkpoint,
                 meta_bdev,
                 index_seg,
             )?;
 
+            stats.add_compaction_bytes(index_seg.written - written);
             pr_info!("new BIT created: {:?}", bit);
 
             // add BIT meta info to BITCategory
@@ -58,11 +98,25 @@ impl WorkFuncTrait for CompactionWorker {
                 checkpoint.bit_category.release_bit(level, *idx)?;
             }
 
+            // log the compaction
+            let record =
+                JournalRecord::bit_creation(&checkpoint.bit_category, level + 1, index_seg)?;
+            journal.append(&record, aead, meta_bdev, client)?;
+            let mut ids = Vec::new();
+            ids.try_extend_from_slice(&bits_id)?;
+            let record = JournalRecord::Compaction {
+                level: level as u64,
+                ids,
+            };
+            journal.append(&record, aead, meta_bdev, client)?;
+            journal.commit(aead, meta_bdev, client)?;
+            compacted += 1;
+
             // clear pending compaction queue
             bits_id.clear();
             bits_pending_compaction.clear();
         }
 
-        Ok(())
+        Ok(compacted)
     }
 }
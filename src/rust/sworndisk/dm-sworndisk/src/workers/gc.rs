@@ -0,0 +1,139 @@
+use crate::{context::SwornDiskContext, prelude::*, regions::DST, workers::IoWorker};
+
+/// SwornDisk Segment Cleaning implementation
+///
+/// The cleaner picks the data segments with the least valid blocks, moves their live blocks
+/// to the logging head, then releases the victims so they can be allocated again.
+pub struct SegmentCleaner;
+
+impl WorkFuncTrait for SegmentCleaner {
+    fn work(work_struct: *mut bindings::work_struct) -> Result {
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+        let ctx = unsafe { SwornDiskContext::of_work(work_struct)? };
+        let _lock = ctx.rw_lock().write();
+
+        if ctx.checkpoint.data_svt.available() >= GC_LOW_WATERMARK {
+            return Ok(());
+        }
+
+        pr_info!("Triggered segment cleaning...");
+
+        Self::clean(ctx, MAX_GC_SEGMENTS)?;
+
+        Ok(())
+    }
+}
+
+impl SegmentCleaner {
+    /// Clean at most `max_segments` data segments, return the number of reclaimed segments
+    /// and moved blocks. The caller should hold the write lock.
+    pub fn clean(ctx: &mut SwornDiskContext, max_segments: usize) -> Result<(usize, usize)> {
+        let mut victims = Vec::new();
+        while victims.len() < max_segments {
+            match ctx.checkpoint.pick_victim(&victims)? {
+                Some(victim) => victims.try_push(victim)?,
+                None => break,
+            }
+        }
+
+        if victims.is_empty() {
+            pr_warn!("No data segment can be cleaned");
+            return Ok((0, 0));
+        }
+
+        let mut moved = 0;
+        for victim in victims.iter() {
+            moved += Self::clean_segment(*victim, ctx)?;
+        }
+
+        for victim in victims.iter() {
+            ctx.checkpoint.data_svt.release(*victim as u64)?;
+            ctx.checkpoint.dst[*victim] = DST::new()?;
+        }
+
+        // persist the new location of moved blocks and the released victims in a checkpoint.
+        // The victims are never written before the checkpoint is done, since a data segment
+        // is only written back when it is full or flushed.
+        ctx.flush()?;
+
+        pr_info!(
+            "Segment cleaning done: {} segments reclaimed, {} blocks moved",
+            victims.len(),
+            moved
+        );
+
+        Ok((victims.len(), moved))
+    }
+
+    /// Move the live blocks of segment `victim` to the logging head, return the number of moved blocks
+    fn clean_segment(victim: usize, ctx: &mut SwornDiskContext) -> Result<usize> {
+        let aead = &ctx.aead;
+        let kdf = &ctx.kdf;
+        let data_mode = ctx.data_mode;
+        let checkpoint = &mut ctx.checkpoint;
+        let client = &ctx.dm_io_client;
+        let data_seg_buffer = &mut ctx.data_seg_buffer;
+        let memtable = &mut ctx.memtable;
+        let journal = &mut ctx.journal;
+        let data_dev = &mut ctx.data_dev;
+        let meta_dev = &mut ctx.meta_dev;
+        let data_bdev = &data_dev.block_device()?;
+        let meta_bdev = &meta_dev.block_device()?;
+        let indirect_block_cache = &mut ctx.indirect_block_cache;
+        let leaf_block_cache = &mut ctx.leaf_block_cache;
+        let stats = &ctx.stats;
+
+        let mut moved = 0;
+        for index in 0..SEGMENT_BLOCK_NUMBER as usize {
+            if !checkpoint.dst[victim].is_valid(index)? {
+                continue;
+            }
+
+            let lba = checkpoint.dst[victim].lba(index);
+            let hba = victim as u64 * SEGMENT_SECTORS + index as u64 * BLOCK_SECTORS;
+
+            // the LBA has been rewritten and the newer data is still buffered
+            if data_seg_buffer.contains(lba) {
+                continue;
+            }
+
+            // the block is live only if the latest record still points to it
+            let record = match IoWorker::find_record(
+                lba,
+                aead,
+                kdf,
+                checkpoint,
+                client,
+                memtable,
+                meta_bdev,
+                indirect_block_cache,
+                leaf_block_cache,
+            )? {
+                Some(record) if record.hba == hba => record,
+                _ => continue,
+            };
+
+            let mut block = IoWorker::read_block_with_record(&record, data_bdev, client)?;
+            IoWorker::decrypt_block(&mut block, lba, &record, aead, kdf, data_mode)?;
+
+            data_seg_buffer.write(
+                lba,
+                &block,
+                0,
+                BLOCK_SIZE as usize,
+                aead,
+                kdf,
+                checkpoint,
+                client,
+                memtable,
+                journal,
+                data_bdev,
+                meta_bdev,
+            )?;
+            moved += 1;
+            stats.add_gc_bytes(BLOCK_SIZE);
+        }
+
+        Ok(moved)
+    }
+}
@@ -1,17 +1,22 @@
 use crate::{
-    context::{SwornDiskContext, CONTEXT as context},
+    cipher::DataMode,
+    context::SwornDiskContext,
+    kdf::KeyDeriver,
+    options::ErrorPolicy,
     prelude::*,
-    regions::{Record, BIT},
-    utils::{get_lba_range, slice_to_vec},
+    regions::{Checkpoint, Extent, IndirectBlock, JournalRecord, LeafBlock, MemTable, Record, BIT},
+    utils::{get_lba_range, slice_to_vec, LruCache},
 };
 
+use core::sync::atomic::Ordering;
+
 pub struct IoWorker;
 
 impl WorkFuncTrait for IoWorker {
     /// functions to handle block I/O request asynchronously
-    fn work(_work_struct: *mut bindings::work_struct) -> Result {
-        // SAFETY: Safe. `context.lock` is acquired when accessing the member of `context`.
-        let ctx = unsafe { context.as_mut().unwrap() };
+    fn work(work_struct: *mut bindings::work_struct) -> Result {
+        // SAFETY: Safe. `ctx.lock` is acquired when accessing the member of `ctx`.
+        let ctx = unsafe { SwornDiskContext::of_work(work_struct)? };
 
         loop {
             let bio = {
@@ -25,7 +30,14 @@ impl WorkFuncTrait for IoWorker {
                 let operation = bio.operation();
                 let res = match operation {
                     READ => Self::handle_read_request(&mut bio, ctx),
+                    // an empty flush, which is sent by device mapper as `num_flush_bios` is set
+                    WRITE | FLUSH if bio.size() == 0 => Self::handle_flush_request(&mut bio, ctx),
+                    WRITE | DISCARD | WRITE_ZEROES if ctx.read_only.load(Ordering::Acquire) => {
+                        Err(EROFS)
+                    }
                     WRITE => Self::handle_write_request(&mut bio, ctx),
+                    DISCARD => Self::handle_discard_request(&mut bio, ctx, false),
+                    WRITE_ZEROES => Self::handle_discard_request(&mut bio, ctx, true),
                     _ => {
                         // SAFETY: Safe. we owns the bio.
                         unsafe { bio.end() };
@@ -36,7 +48,7 @@ impl WorkFuncTrait for IoWorker {
                 match res {
                     Ok(()) => {}
                     Err(e) => {
-                        pr_info!(
+                        pr_err!(
                             "error {:?} while processing bio: operation = {}, sector = {}",
                             e,
                             operation,
@@ -44,7 +56,9 @@ impl WorkFuncTrait for IoWorker {
                         );
 
                         // SAFETY: Safe. we owns the bio.
-                        unsafe { bio.end() };
+                        unsafe { bio.end_with_status(Self::error_to_status(e)) };
+                        ctx.stats.add_failure(e);
+                        Self::handle_error(e, ctx);
                     }
                 }
             } else {
@@ -57,10 +71,37 @@ impl WorkFuncTrait for IoWorker {
 }
 
 impl IoWorker {
+    /// Translate an error to the block I/O status reported to the submitter
+    fn error_to_status(err: Error) -> u8 {
+        if err == EBADMSG {
+            // the block is tampered, it fails the integrity check
+            BLK_STS_PROTECTION
+        } else if err == ENOSPC {
+            BLK_STS_NOSPC
+        } else {
+            BLK_STS_IOERR
+        }
+    }
+
+    /// Apply the error policy of SwornDisk after an I/O request fails
+    fn handle_error(err: Error, ctx: &mut SwornDiskContext) {
+        match ctx.error_policy {
+            ErrorPolicy::Fail => {}
+            ErrorPolicy::ReadOnly => {
+                if err != EROFS && !ctx.read_only.swap(true, Ordering::AcqRel) {
+                    pr_err!("SwornDisk is switched to read-only due to error {:?}", err);
+                }
+            }
+            ErrorPolicy::Panic => panic!("SwornDisk I/O error: {:?}", err),
+        }
+    }
+
     fn handle_read_request(bio: &mut Bio, ctx: &mut SwornDiskContext) -> Result {
         let _lock = ctx.lock.read();
 
         let aead = &ctx.aead;
+        let kdf = &ctx.kdf;
+        let data_mode = ctx.data_mode;
         let checkpoint = &mut ctx.checkpoint;
         let client = &ctx.dm_io_client;
         let data_seg_buffer = &mut ctx.data_seg_buffer;
@@ -83,7 +124,7 @@ impl IoWorker {
         let mut buf = Vec::new();
         buf.try_resize(len as usize, 0u8)?;
 
-        'traverse_lba: for lba in begin_lba..end_lba {
+        for lba in begin_lba..end_lba {
             let len = if lba == begin_lba {
                 core::cmp::min(block_size - begin_offset, len as usize)
             } else if lba == end_lba - 1 {
@@ -106,58 +147,66 @@ impl IoWorker {
                 continue;
             }
 
-            // find in memtable
-            if let Some(record) = memtable.find(lba as u64) {
+            // find in MemTable and LSM-tree (BIT)
+            if let Some(record) = Self::find_record(
+                lba as u64,
+                aead,
+                kdf,
+                checkpoint,
+                client,
+                memtable,
+                meta_bdev,
+                indirect_block_cache,
+                leaf_block_cache,
+            )? {
+                // a discarded LBA reads as zeros
+                if record.is_tombstone() {
+                    buf_offset += len;
+                    continue;
+                }
+
                 let mut block = Self::read_block_with_record(&record, data_bdev, client)?;
-                Self::decrypt_block(&mut block, &record, aead)?;
+                Self::decrypt_block(&mut block, lba as u64, &record, aead, kdf, data_mode)?;
 
                 buf[buf_offset..buf_offset + len].copy_from_slice(&block[offset..offset + len]);
-                buf_offset += len;
-
-                continue;
             }
 
-            // find in LSM-tree (BIT)
-            for level in 0..LSM_TREE_MAX_LEVEL {
-                for root_meta in checkpoint.bit_category.iter_level(level)? {
-                    if !root_meta.contains(lba as u64) {
-                        continue;
-                    }
-
-                    let bit =
-                        root_meta.read_from_disk(aead, meta_bdev, client, indirect_block_cache)?;
+            buf_offset += len;
+        }
 
-                    let record = bit.find_record(
-                        lba as u64,
-                        aead,
-                        meta_bdev,
-                        client,
-                        indirect_block_cache,
-                        leaf_block_cache,
-                    )?;
+        bio.set_data(buf)?;
+        unsafe { bio.end() };
 
-                    if record.is_some() {
-                        let record = record.unwrap();
-                        let mut block = Self::read_block_with_record(&record, data_bdev, client)?;
-                        Self::decrypt_block(&mut block, &record, aead)?;
+        Ok(())
+    }
 
-                        buf[buf_offset..buf_offset + len]
-                            .copy_from_slice(&block[offset..offset + len]);
-                        buf_offset += len;
+    fn handle_flush_request(bio: &mut Bio, ctx: &mut SwornDiskContext) -> Result {
+        // the writes acknowledged before the flush, which should be durable when it completes
+        let ticket = ctx.write_seq.load(Ordering::Acquire);
+        {
+            // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+            let _lock = ctx.rw_lock().write();
 
-                        continue 'traverse_lba;
-                    }
-                }
+            // group commit: the flushes waiting for the lock are covered by a single sync
+            if ctx.synced_seq < ticket {
+                ctx.sync()?;
             }
         }
 
-        bio.set_data(buf)?;
+        // SAFETY: Safe, we owns the bio in a flush request.
         unsafe { bio.end() };
 
         Ok(())
     }
 
     fn handle_write_request(bio: &mut Bio, ctx: &mut SwornDiskContext) -> Result {
+        // the writes completed before must be durable before this one
+        if bio.is_preflush() {
+            // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+            let _lock = ctx.rw_lock().write();
+            ctx.sync()?;
+        }
+
         let begin_sector = bio.sector();
         let (data, len) = bio.data(0)?;
 
@@ -185,46 +234,271 @@ impl IoWorker {
             let block = &data[buf_offset..buf_offset + len];
             buf_offset += len;
 
-            let _lock = ctx.lock.write();
+            // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+            let _lock = ctx.rw_lock().write();
 
-            let aead = &ctx.aead;
-            let checkpoint = &mut ctx.checkpoint;
-            let client = &ctx.dm_io_client;
-            let data_seg_buffer = &mut ctx.data_seg_buffer;
-            let index_seg = &mut ctx.index_seg;
-            let memtable = &mut ctx.memtable;
-            let data_dev = &mut ctx.data_dev;
-            let meta_dev = &mut ctx.meta_dev;
-            let data_bdev = &data_dev.block_device()?;
-            let meta_bdev = &meta_dev.block_device()?;
+            Self::write_block(lba as u64, block, offset, len, ctx)?;
+        }
 
-            // log the block to data segment
-            data_seg_buffer.write(
-                lba as u64, block, offset, len, aead, checkpoint, client, memtable, data_bdev,
-            )?;
+        // truncate the journal by a checkpoint before it runs out of space
+        {
+            // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+            let _lock = ctx.rw_lock().write();
+            if ctx.journal.is_checkpoint_required() {
+                ctx.flush()?;
+            }
 
-            // if the memtable reaches the threshold, trigger writeback (minor compaction)
-            if memtable.size() >= MEMTABLE_THRESHOLD {
-                pr_info!("Memtable size: {}", memtable.size());
+            ctx.write_seq.fetch_add(1, Ordering::AcqRel);
+            ctx.stats.add_foreground_bytes(buf_offset as u64);
 
-                let bit =
-                    BIT::from_memtable(memtable, aead, client, checkpoint, meta_bdev, index_seg)?;
-                checkpoint.bit_category.add_bit(bit, 0)?;
-                memtable.clear();
+            // a FUA write is acknowledged only after it is durable
+            if bio.is_fua() {
+                ctx.sync()?;
+            }
+        }
 
-                if checkpoint.bit_category.is_compaction_required() {
-                    ctx.work_queue.queue_work(&mut ctx.compaction_worker);
+        // SAFETY: Safe, we owns the bio in a write request.
+        unsafe { bio.end() };
+
+        Ok(())
+    }
+
+    /// Log a block of `lba` to data segment, the caller should hold the write lock
+    fn write_block(
+        lba: u64,
+        block: &[u8],
+        offset: usize,
+        len: usize,
+        ctx: &mut SwornDiskContext,
+    ) -> Result {
+        let aead = &ctx.aead;
+        let kdf = &ctx.kdf;
+        let checkpoint = &mut ctx.checkpoint;
+        let client = &ctx.dm_io_client;
+        let data_seg_buffer = &mut ctx.data_seg_buffer;
+        let memtable = &mut ctx.memtable;
+        let journal = &mut ctx.journal;
+        let data_dev = &mut ctx.data_dev;
+        let meta_dev = &mut ctx.meta_dev;
+        let data_bdev = &data_dev.block_device()?;
+        let meta_bdev = &meta_dev.block_device()?;
+
+        // log the block to data segment
+        data_seg_buffer.write(
+            lba, block, offset, len, aead, kdf, checkpoint, client, memtable, journal, data_bdev,
+            meta_bdev,
+        )?;
+
+        // if free data segments are running out, trigger segment cleaning
+        if checkpoint.data_svt.available() < GC_LOW_WATERMARK {
+            ctx.work_queue.queue_work(&mut ctx.gc_worker);
+        }
+
+        Self::check_memtable(ctx)
+    }
+
+    /// If the memtable reaches the threshold, trigger writeback (minor compaction).
+    /// The caller should hold the write lock.
+    fn check_memtable(ctx: &mut SwornDiskContext) -> Result {
+        if ctx.memtable.size() < ctx.memtable_threshold {
+            return Ok(());
+        }
+
+        let aead = &ctx.aead;
+        let kdf = &ctx.kdf;
+        let checkpoint = &mut ctx.checkpoint;
+        let client = &ctx.dm_io_client;
+        let index_seg = &mut ctx.index_seg;
+        let memtable = &mut ctx.memtable;
+        let journal = &mut ctx.journal;
+        let meta_bdev = &ctx.meta_dev.block_device()?;
+
+        pr_info!("Memtable size: {}", memtable.size());
+
+        let bit = BIT::from_memtable(
+            memtable, aead, kdf, client, checkpoint, meta_bdev, index_seg,
+        )?;
+        checkpoint.bit_category.add_bit(bit, 0)?;
+        memtable.clear();
+
+        let record = JournalRecord::bit_creation(&checkpoint.bit_category, 0, index_seg)?;
+        journal.append(&record, aead, meta_bdev, client)?;
+
+        if checkpoint.bit_category.is_compaction_required() {
+            ctx.work_queue.queue_work(&mut ctx.compaction_worker);
+        }
+
+        Ok(())
+    }
+
+    /// Handle a DISCARD (or WRITE_ZEROES if `zeroes` is set) request.
+    ///
+    /// The whole blocks in the range are replaced by tombstones and their old blocks are
+    /// invalid since then. The partial blocks at the edges are ignored by DISCARD, which is
+    /// only a hint, and are written with zeros by WRITE_ZEROES.
+    fn handle_discard_request(bio: &mut Bio, ctx: &mut SwornDiskContext, zeroes: bool) -> Result {
+        let begin_sector = bio.sector();
+        let end_sector = begin_sector + bio.sectors() as u64;
+
+        // whole blocks in [begin_lba, end_lba)
+        let begin_lba = (begin_sector + BLOCK_SECTORS - 1) / BLOCK_SECTORS;
+        let end_lba = end_sector / BLOCK_SECTORS;
+
+        // The lock is held for the whole request, so no data log of the discarded LBAs
+        // can be journaled before the discard.
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+        let _lock = ctx.rw_lock().write();
+
+        if zeroes {
+            let mut zeros = Vec::new();
+            zeros.try_resize(BLOCK_SIZE as usize, 0u8)?;
+
+            // partial blocks at the head and the tail, as (begin, end) sectors
+            let head = (
+                begin_sector,
+                cmp::min(end_sector, begin_lba * BLOCK_SECTORS),
+            );
+            let tail = (cmp::max(begin_sector, end_lba * BLOCK_SECTORS), end_sector);
+            for (begin, end) in [head, tail] {
+                if begin >= end {
+                    continue;
+                }
+
+                let offset = ((begin % BLOCK_SECTORS) * SECTOR_SIZE) as usize;
+                let len = ((end - begin) * SECTOR_SIZE) as usize;
+                Self::write_block(begin / BLOCK_SECTORS, &zeros[..len], offset, len, ctx)?;
+
+                // the head is also the tail if the range is inside a block
+                if head == tail {
+                    break;
                 }
             }
         }
 
-        // SAFETY: Safe, we owns the bio in a write request.
+        // tombstones are logged by runs of discarded LBAs
+        let mut run: Option<(u64, u64)> = None;
+        for lba in begin_lba..end_lba {
+            if Self::discard_block(lba, ctx)? {
+                run = match run {
+                    Some((begin, end)) if end == lba => Some((begin, lba + 1)),
+                    Some((begin, end)) => {
+                        Self::log_discard(begin, end, ctx)?;
+                        Some((lba, lba + 1))
+                    }
+                    None => Some((lba, lba + 1)),
+                };
+            }
+
+            Self::check_memtable(ctx)?;
+        }
+        if let Some((begin, end)) = run {
+            Self::log_discard(begin, end, ctx)?;
+        }
+
+        ctx.write_seq.fetch_add(1, Ordering::AcqRel);
+        drop(_lock);
+
+        // SAFETY: Safe, we owns the bio in a discard request.
         unsafe { bio.end() };
 
         Ok(())
     }
 
-    fn read_block_with_record(
+    /// Replace the record of `lba` by a tombstone, returns false if the LBA has no data to
+    /// discard. The caller should hold the write lock.
+    fn discard_block(lba: u64, ctx: &mut SwornDiskContext) -> Result<bool> {
+        // the buffered block is never indexed, dropping it is enough
+        ctx.data_seg_buffer.discard(lba, &mut ctx.checkpoint)?;
+
+        let record = match Self::find_record(
+            lba,
+            &ctx.aead,
+            &ctx.kdf,
+            &mut ctx.checkpoint,
+            &ctx.dm_io_client,
+            &ctx.memtable,
+            &ctx.meta_dev.block_device()?,
+            &mut ctx.indirect_block_cache,
+            &mut ctx.leaf_block_cache,
+        )? {
+            Some(record) if !record.is_tombstone() => record,
+            _ => return Ok(false),
+        };
+
+        ctx.memtable.insert(Extent::tombstone(lba, 1))?;
+        // the block of the newest record is invalid now, even if the record is in a BIT
+        ctx.checkpoint
+            .invalidate_block(record.hba, lba, record.version)?;
+
+        Ok(true)
+    }
+
+    /// Log the discarded LBAs in `[begin, end)` to the journal. A checkpoint is committed
+    /// if the journal is filling up, since a large discard may log many runs.
+    fn log_discard(begin: u64, end: u64, ctx: &mut SwornDiskContext) -> Result {
+        ctx.journal.append(
+            &JournalRecord::Discard { begin, end },
+            &ctx.aead,
+            &ctx.meta_dev.block_device()?,
+            &ctx.dm_io_client,
+        )?;
+
+        if ctx.journal.is_checkpoint_required() {
+            ctx.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the latest record of `lba` in MemTable and LSM-tree (BIT)
+    pub(crate) fn find_record(
+        lba: u64,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        checkpoint: &mut Checkpoint,
+        client: &DmIoClient,
+        memtable: &MemTable,
+        meta_bdev: &BlockDevice,
+        indirect_block_cache: &mut LruCache<u64, IndirectBlock>,
+        leaf_block_cache: &mut LruCache<u64, LeafBlock>,
+    ) -> Result<Option<Record>> {
+        // find in memtable
+        if let Some(record) = memtable.find(lba) {
+            return Ok(Some(record));
+        }
+
+        // find in LSM-tree (BIT)
+        for level in 0..LSM_TREE_MAX_LEVEL {
+            for root_meta in checkpoint.bit_category.iter_level(level)? {
+                // skip the BIT without descent if its Bloom filter rules out the LBA
+                if !root_meta.contains(lba) || !checkpoint.bit_category.may_contain(root_meta, lba)
+                {
+                    continue;
+                }
+
+                let bit =
+                    root_meta.read_from_disk(aead, kdf, meta_bdev, client, indirect_block_cache)?;
+
+                let record = bit.find_record(
+                    lba,
+                    aead,
+                    kdf,
+                    meta_bdev,
+                    client,
+                    indirect_block_cache,
+                    leaf_block_cache,
+                )?;
+
+                if record.is_some() {
+                    return Ok(record);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub(crate) fn read_block_with_record(
         record: &Record,
         bdev: &BlockDevice,
         client: &DmIoClient,
@@ -240,20 +514,29 @@ impl IoWorker {
             0, // offset
             client,
         );
-        io_req.submit(&mut region);
+        io_req.submit(&mut region)?;
 
         Ok(block)
     }
 
-    fn decrypt_block(block: &mut Vec<u8>, record: &Record, aead: &Pin<Box<Aead>>) -> Result {
-        unsafe {
-            aead.as_ref().decrypt_in_place(
-                &slice_to_vec::<{ SWORNDISK_KEY_LENGTH }>(&record.key)?,
-                &mut slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&record.mac)?,
-                &mut slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&record.nonce)?,
-                block,
-                BLOCK_SIZE as usize,
-            )
-        }
+    /// Decrypt the data block of `lba` read with `record`, which is only verified in
+    /// `DataMode::Integrity`
+    pub(crate) fn decrypt_block(
+        block: &mut Vec<u8>,
+        lba: u64,
+        record: &Record,
+        aead: &Pin<Box<Aead>>,
+        kdf: &KeyDeriver,
+        mode: DataMode,
+    ) -> Result {
+        let mut assoc = record.associated_data(lba)?;
+        mode.open(
+            aead,
+            &kdf.data_key(record)?,
+            &mut slice_to_vec::<{ SWORNDISK_MAC_LENGTH }>(&record.mac)?,
+            &mut slice_to_vec::<{ SWORNDISK_NONCE_LENGTH }>(&record.nonce)?,
+            &mut assoc[..],
+            &mut block[..BLOCK_SIZE as usize],
+        )
     }
 }
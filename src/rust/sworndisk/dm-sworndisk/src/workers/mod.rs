@@ -1,5 +1,9 @@
 pub mod compaction;
+pub mod gc;
 pub mod io;
+pub mod scrub;
 
 pub use compaction::CompactionWorker;
+pub use gc::SegmentCleaner;
 pub use io::IoWorker;
+pub use scrub::Scrubber;
@@ -0,0 +1,131 @@
+use crate::{context::SwornDiskContext, prelude::*, workers::IoWorker};
+
+use core::sync::atomic::Ordering;
+
+/// SwornDisk Scrubbing implementation
+///
+/// The scrubber reads back every live block of the data segments and verifies it, so the
+/// tampered or corrupted blocks are found before they are read by users. It runs until all
+/// the segments are scrubbed or `SwornDiskContext::scrubbing` is cleared.
+pub struct Scrubber;
+
+impl WorkFuncTrait for Scrubber {
+    fn work(work_struct: *mut bindings::work_struct) -> Result {
+        // SAFETY: Safe. `ctx.lock` is acquired before accessing the member of `ctx`.
+        let ctx = unsafe { SwornDiskContext::of_work(work_struct)? };
+
+        pr_info!("Triggered scrubbing...");
+
+        let res = Self::scrub(ctx);
+        let finished = ctx.scrubbing.swap(false, Ordering::AcqRel);
+        match res {
+            Ok((scrubbed, failures)) => pr_info!(
+                "Scrubbing {}: {} blocks scrubbed, {} failed the integrity check",
+                if finished { "done" } else { "stopped" },
+                scrubbed,
+                failures
+            ),
+            Err(err) => pr_err!("Scrubbing failed: {:?}", err),
+        }
+
+        res.map(|_| ())
+    }
+}
+
+impl Scrubber {
+    /// Verify the live blocks of all the data segments, return the number of scrubbed blocks
+    /// and the ones failing the integrity check
+    fn scrub(ctx: &mut SwornDiskContext) -> Result<(usize, usize)> {
+        let mut scrubbed = 0;
+        let mut failures = 0;
+        for segment in 0..ctx.checkpoint.dst.len() {
+            for index in 0..SEGMENT_BLOCK_NUMBER as usize {
+                if !ctx.scrubbing.load(Ordering::Acquire) {
+                    return Ok((scrubbed, failures));
+                }
+
+                // the lock is held for each block, so the scrubbing never stalls the users
+                let _lock = ctx.rw_lock().read();
+                match Self::scrub_block(segment, index, ctx)? {
+                    Some(true) => scrubbed += 1,
+                    Some(false) => {
+                        scrubbed += 1;
+                        failures += 1;
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        Ok((scrubbed, failures))
+    }
+
+    /// Verify block `index` of data segment `segment`, return whether it passes the integrity
+    /// check, or `None` if it is not live. The caller should hold the read lock.
+    ///
+    /// A block whose record or data can not be read counts as a failure as well, the
+    /// scrubbing goes on with the next block.
+    fn scrub_block(
+        segment: usize,
+        index: usize,
+        ctx: &mut SwornDiskContext,
+    ) -> Result<Option<bool>> {
+        let checkpoint = &ctx.checkpoint;
+        if !checkpoint.data_svt.is_allocated(segment)?
+            || !checkpoint.dst[segment].is_valid(index)?
+        {
+            return Ok(None);
+        }
+
+        let lba = checkpoint.dst[segment].lba(index);
+        let hba = segment as u64 * SEGMENT_SECTORS + index as u64 * BLOCK_SECTORS;
+
+        match Self::verify_block(lba, hba, ctx) {
+            Ok(live) => Ok(live.then(|| true)),
+            Err(err) => {
+                if err == EBADMSG {
+                    pr_err!(
+                        "Block of LBA {} at HBA {} fails the integrity check",
+                        lba,
+                        hba
+                    );
+                } else {
+                    pr_err!(
+                        "Failed to scrub the block of LBA {} at HBA {}: {:?}",
+                        lba,
+                        hba,
+                        err
+                    );
+                }
+                ctx.stats.add_failure(err);
+                Ok(Some(false))
+            }
+        }
+    }
+
+    /// Read and verify the block of `lba` at `hba`, return false if it is not live
+    fn verify_block(lba: u64, hba: u64, ctx: &mut SwornDiskContext) -> Result<bool> {
+        // the block is live only if the latest record still points to it
+        let record = match IoWorker::find_record(
+            lba,
+            &ctx.aead,
+            &ctx.kdf,
+            &mut ctx.checkpoint,
+            &ctx.dm_io_client,
+            &ctx.memtable,
+            &ctx.meta_dev.block_device()?,
+            &mut ctx.indirect_block_cache,
+            &mut ctx.leaf_block_cache,
+        )? {
+            Some(record) if record.hba == hba => record,
+            _ => return Ok(false),
+        };
+
+        let data_bdev = ctx.data_dev.block_device()?;
+        let mut block = IoWorker::read_block_with_record(&record, &data_bdev, &ctx.dm_io_client)?;
+        ctx.stats.add_scrubbed_bytes(BLOCK_SIZE);
+        IoWorker::decrypt_block(&mut block, lba, &record, &ctx.aead, &ctx.kdf, ctx.data_mode)?;
+
+        Ok(true)
+    }
+}
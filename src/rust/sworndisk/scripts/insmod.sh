@@ -1,3 +1,6 @@
+# the root key of the test device, override it with a 16 or 32-byte hex string
+ROOT_KEY=${ROOT_KEY:-000102030405060708090a0b0c0d0e0f}
+
 echo 'Setting up loop device /dev/loop0'
 sudo losetup /dev/loop0 /home/bellaris/tmp/disk.img
 
@@ -8,4 +11,4 @@ echo 'Setting up kernel module...'
 sudo insmod dm-sworndisk.ko
 
 echo 'Setting up dm_sworndisk device mapper...'
-echo -e "0 58593750 sworndisk /dev/loop0 /dev/loop1 0 $FORMAT" | sudo dmsetup create test-sworndisk
\ No newline at end of file
+echo -e "0 58593744 sworndisk /dev/loop0 /dev/loop1 0 $FORMAT $ROOT_KEY" | sudo dmsetup create test-sworndisk
\ No newline at end of file